@@ -52,7 +52,7 @@ fn generate_interactive_visualization(results: &[ScanResult], output_file: &str)
     }
 
     // Prepare data for D3.js
-    let nodes: Vec<HashMap<String, serde_json::Value>> = results.iter()
+    let nodes: Vec<serde_json::Value> = results.iter()
         .map(|r| {
             json!({
                 "id": r.ip,
@@ -64,7 +64,7 @@ fn generate_interactive_visualization(results: &[ScanResult], output_file: &str)
         })
         .collect();
 
-    let subnet_nodes: Vec<HashMap<String, serde_json::Value>> = subnets.keys()
+    let subnet_nodes: Vec<serde_json::Value> = subnets.keys()
         .map(|subnet| {
             json!({
                 "id": subnet,
@@ -82,14 +82,24 @@ fn generate_interactive_visualization(results: &[ScanResult], output_file: &str)
         })
         .collect();
 
+    // json! would read a [..] value as a JSON array literal, so join the two lists first
+    let nodes = [nodes, subnet_nodes].concat();
     let data = json!({
-        "nodes": [nodes, subnet_nodes].concat(),
+        "nodes": nodes,
         "links": links
     });
 
+    // With nothing found there is no graph to lay out; say so instead of showing an empty canvas
+    let empty = if results.is_empty() {
+        r#"<p id="empty">No live hosts were found in the scanned range, so there is nothing to draw.</p>"#
+    } else {
+        ""
+    };
+
     // HTML template with embedded D3.js visualization
+    // Raw string with ## delimiters: the template is full of "#id" selectors and colors
     let html_content = format!(
-        r#"
+        r##"
         <!DOCTYPE html>
         <html lang="en">
         <head>
@@ -166,17 +176,30 @@ fn generate_interactive_visualization(results: &[ScanResult], output_file: &str)
                     margin-right: 5px;
                     border-radius: 50%;
                 }}
+                #empty {{
+                    margin: 2rem;
+                    text-align: center;
+                    font-size: 1.2rem;
+                    color: #666;
+                }}
             </style>
         </head>
         <body>
             <div id="header">
                 <h1>Network Topology Visualization</h1>
             </div>
+            {empty}
             <div id="network-graph"></div>
             <div id="tooltip"></div>
             <div id="legend"></div>
             <script>
-                const data = {};
+                const data = {data};
+
+                // An empty scan keeps the message alone on the page
+                if (data.nodes.length === 0) {{
+                    document.getElementById("network-graph").hidden = true;
+                    document.getElementById("legend").hidden = true;
+                }}
 
                 const width = window.innerWidth - 40;
                 const height = window.innerHeight - 100;
@@ -226,7 +249,7 @@ fn generate_interactive_visualization(results: &[ScanResult], output_file: &str)
                 node.on("mouseover", (event, d) => {{
                     if (d.type === "device") {{
                         tooltip.style("opacity", 1)
-                            .html(`IP: ${d.id}<br>OS: ${d.os}<br>Subnet: ${d.subnet}<br>Ports: ${d.ports.join(", ")}`)
+                            .html(`IP: ${{d.id}}<br>OS: ${{d.os}}<br>Subnet: ${{d.subnet}}<br>Ports: ${{d.ports.join(", ") || "none"}}`)
                             .style("left", (event.pageX + 10) + "px")
                             .style("top", (event.pageY - 10) + "px");
                     }}
@@ -289,14 +312,16 @@ fn generate_interactive_visualization(results: &[ScanResult], output_file: &str)
                     .join("div")
                     .attr("class", "legend-item")
                     .html(d => `
-                        <div class="legend-color" style="background-color: ${d.color}; ${d.name === 'Subnet' ? 'border: 2px dashed #666;' : ''}"></div>
-                        <span>${d.name}</span>
+                        <div class="legend-color" style="background-color: ${{d.color}}; ${{d.name === 'Subnet' ? 'border: 2px dashed #666;' : ''}}"></div>
+                        <span>${{d.name}}</span>
                     `);
             </script>
         </body>
         </html>
-        "#,
-        serde_json::to_string(&data)?
+        "##,
+        empty = empty,
+        // A "</script>" in any field would otherwise end the script early
+        data = serde_json::to_string(&data)?.replace('<', "\\u003c")
     );
 
     file.write_all(html_content.as_bytes())?;
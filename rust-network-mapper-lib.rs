@@ -0,0 +1,8635 @@
+//! The network-mapper scanner as a library. [`scan_range`] scans an address range
+//! with a [`ScanOptions`] and returns a [`ScanResult`] per live host; [`run`] is the
+//! whole `network-mapper` command line, which the binary merely calls.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use network_mapper::{scan_range, ScanOptions};
+//! use std::net::Ipv4Addr;
+//!
+//! let mut options = ScanOptions::default();
+//! options.ports = vec![22, 80, 443];
+//! for host in scan_range(Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(192, 168, 1, 254), options).await {
+//!     println!("{} {:?} {}", host.ip, host.open_ports, host.os_guess);
+//! }
+//! # }
+//! ```
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::Instrument;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use clap::{ArgEnum, Parser, Subcommand};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Every "warning: ..." line on stderr, so --color reaches them all
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        eprintln!("{} {}", paint("warning:", Style::Warn), format_args!($($arg)*))
+    };
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(short, long)]
+    start_ip: Option<String>,
+    #[clap(short, long)]
+    end_ip: Option<String>,
+    #[clap(long, conflicts_with_all = &["start-ip", "end-ip"])]
+    cidr: Option<CidrTarget>,
+    #[clap(long)]
+    max_hosts: Option<u64>,
+    #[clap(long)]
+    max_results: Option<usize>,
+    #[clap(long)]
+    subnet_prefix: Option<u8>,
+    // -v says why hosts were skipped, -vv adds every connect, -vvv retries and probes
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u64,
+    #[clap(short, long, default_value = "network_topology.html")]
+    output_file: String,
+    #[clap(long, default_value = "100")]
+    concurrency: usize,
+    #[clap(long)]
+    connect_limit: Option<usize>,
+    #[clap(long)]
+    sink: Option<String>,
+    #[clap(long, requires = "sink")]
+    sink_deltas_only: bool,
+    #[clap(long, requires = "sink-deltas-only")]
+    sink_state: Option<String>,
+    #[clap(long)]
+    verify_services: bool,
+    #[clap(long)]
+    control_socket: Option<String>,
+    #[clap(long)]
+    serve: Option<std::net::SocketAddr>,
+    #[clap(long)]
+    on_complete: Option<String>,
+    #[clap(long)]
+    allowlist: Option<String>,
+    #[clap(long, default_value = "0")]
+    retries: u32,
+    #[clap(long, default_value = "100")]
+    retry_delay_ms: u64,
+    #[clap(long, default_value = "1000")]
+    timeout_ms: std::num::NonZeroU64,
+    #[clap(long)]
+    icmp_errors: bool,
+    #[clap(long, arg_enum, default_value = "html", multiple_occurrences = true, use_value_delimiter = true)]
+    format: Vec<OutputFormat>,
+    #[clap(long, arg_enum, default_value = "ip,os,rtt,ports", use_value_delimiter = true)]
+    csv_columns: Vec<CsvColumn>,
+    #[clap(long, multiple_occurrences = true, use_value_delimiter = true)]
+    probe_timeout_multiplier: Vec<PortMultiplier>,
+    #[clap(long)]
+    json_output: Option<String>,
+    #[clap(long, arg_enum, default_value = "pretty")]
+    json_output_style: JsonStyle,
+    #[clap(long, arg_enum, default_value = "pretty")]
+    stdout_json: JsonStyle,
+    #[clap(long)]
+    stdout_metadata: bool,
+    #[clap(long)]
+    deduplicate_banners: bool,
+    #[clap(long, multiple_occurrences = true, use_value_delimiter = true)]
+    only_subnets: Vec<Ipv4Cidr>,
+    #[clap(long, conflicts_with = "only-public")]
+    only_private: bool,
+    #[clap(long)]
+    only_public: bool,
+    #[clap(long)]
+    huge_scan: bool,
+    #[clap(long, conflicts_with_all = &["huge-scan", "schedule-window", "from-file"])]
+    stdin: bool,
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["huge-scan", "stdin", "from-file", "liveness-only"])]
+    import_masscan: Option<String>,
+    #[clap(long)]
+    merge_dual_stack: bool,
+    #[clap(long)]
+    save: Option<String>,
+    #[clap(long)]
+    from_file: Option<String>,
+    #[clap(long)]
+    baseline: Option<String>,
+    #[clap(long, requires = "baseline")]
+    probe_changed_only: bool,
+    #[clap(long, requires = "baseline")]
+    rtt_timeouts: bool,
+    #[clap(long, arg_enum, default_value = "auto")]
+    progress: ProgressMode,
+    #[clap(long, arg_enum, default_value = "auto")]
+    color: ColorMode,
+    #[clap(long, conflicts_with = "huge-scan")]
+    schedule_window: Option<u64>,
+    #[clap(long, requires = "schedule-window")]
+    schedule_interval: Option<u64>,
+    #[clap(long, requires = "schedule-window")]
+    checkpoint: Option<String>,
+    #[clap(long)]
+    scan_id: Option<String>,
+    #[clap(long)]
+    rst_as_open: bool,
+    #[clap(long, requires = "alive-ports-only")]
+    alive_ports: Option<PortList>,
+    #[clap(long)]
+    alive_ports_only: bool,
+    // A bare --liveness-only means port 80
+    #[clap(
+        long,
+        value_name = "PORT",
+        min_values = 0,
+        default_missing_value = "80",
+        conflicts_with_all = &["ports", "top-ports", "udp-ports", "alive-ports-only"]
+    )]
+    liveness_only: Option<u16>,
+    #[clap(long)]
+    ping_first: bool,
+    // A bare --latency-tiers uses the default thresholds
+    #[clap(long, value_name = "MS,MS,MS", min_values = 0, default_missing_value = DEFAULT_LATENCY_TIERS)]
+    latency_tiers: Option<LatencyTiers>,
+    #[clap(long)]
+    os_override: Option<String>,
+    #[clap(long)]
+    role_rules: Option<String>,
+    #[clap(long, multiple_occurrences = true, use_value_delimiter = true)]
+    ips: Vec<IpAddr>,
+    #[clap(long, multiple_occurrences = true, use_value_delimiter = true)]
+    target: Vec<String>,
+    #[clap(long, arg_enum, default_value = "any")]
+    target_family: AddressFamily,
+    #[clap(long)]
+    ports: Option<PortList>,
+    #[clap(long, conflicts_with = "ports")]
+    top_ports: Option<usize>,
+    #[clap(long)]
+    udp_ports: Option<PortList>,
+    #[clap(long, default_value = DEFAULT_BANNER_PORTS)]
+    banner_ports: PortList,
+    #[clap(long)]
+    banner: bool,
+    #[clap(long)]
+    response_stats: bool,
+    #[clap(long)]
+    cve_hints: bool,
+    #[clap(long, requires = "cve-hints")]
+    cve_map: Option<String>,
+    #[clap(long)]
+    detect_websocket: bool,
+    #[clap(long)]
+    http_probe: bool,
+    #[clap(long)]
+    collapse_subnets: Option<u8>,
+    #[clap(long)]
+    ja3s: bool,
+    #[clap(long)]
+    tls_certs: bool,
+    #[clap(long, arg_enum, default_value = "modern")]
+    client_hello: ClientHelloProfile,
+    #[clap(long)]
+    sni: Option<String>,
+    #[clap(long, default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+    #[clap(long)]
+    ramp_up: Option<u64>,
+    #[clap(long)]
+    rate: Option<f64>,
+    #[clap(long)]
+    throttle_on_errors: bool,
+    #[clap(long, requires = "throttle-on-errors", default_value = "20")]
+    throttle_threshold: usize,
+    #[clap(long, requires = "throttle-on-errors", default_value = "5")]
+    throttle_cooldown: u64,
+    #[clap(long)]
+    rotate: bool,
+    #[clap(long, default_value = "10")]
+    keep: usize,
+    #[clap(long, arg_enum, default_value = "snake")]
+    json_case: JsonCase,
+    #[clap(long, arg_enum, default_value = "numeric")]
+    probe_order: ProbeOrder,
+    #[clap(long)]
+    ssh_host_keys: bool,
+    #[clap(long)]
+    rdp_nla: bool,
+    #[clap(long)]
+    mdns: bool,
+    #[clap(long)]
+    resolve: bool,
+    #[clap(long)]
+    business_hours: Option<TimeWindow>,
+    #[clap(long, default_value = "local")]
+    business_hours_tz: WindowZone,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check whether this environment supports the requested scan
+    Doctor,
+    /// List built-in port sets and profiles
+    Presets,
+    /// Show which hosts appeared, disappeared or changed ports between two saved scans
+    Compare {
+        /// Earlier results, as written by --save or --json-output
+        old: String,
+        /// Later results, in either form
+        new: String,
+        /// Print the diff as JSON instead of one line per host
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+// Comma-separated ports and inclusive ranges, e.g. 22,80,8000-8100
+#[derive(Clone, Debug)]
+struct PortList(Vec<u16>);
+
+impl FromStr for PortList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |p: &str| match p.trim().parse::<u16>() {
+            Ok(port) if port > 0 => Ok(port),
+            _ => Err(format!("invalid port {:?}, expected 1-65535", p.trim())),
+        };
+
+        let mut ports = Vec::new();
+        for item in s.split(',').filter(|item| !item.trim().is_empty()) {
+            match item.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse_port(first)?, parse_port(last)?);
+                    if first > last {
+                        return Err(format!("port range {:?} is reversed", item.trim()));
+                    }
+                    ports.extend(first..=last);
+                }
+                None => ports.push(parse_port(item)?),
+            }
+        }
+        if ports.is_empty() {
+            return Err("port list is empty".to_string());
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        ports.retain(|port| seen.insert(*port));
+        Ok(PortList(ports))
+    }
+}
+
+// HH:MM-HH:MM; an end earlier than the start spans midnight, e.g. 22:00-06:00
+#[derive(Clone, Copy, Debug)]
+struct TimeWindow {
+    // Minutes since midnight, end exclusive
+    start: u16,
+    end: u16,
+}
+
+impl TimeWindow {
+    fn contains(&self, minute: u16) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_time = |t: &str| {
+            let (hours, minutes) = t
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| format!("expected HH:MM, got {:?}", t.trim()))?;
+            match (hours.parse::<u16>(), minutes.parse::<u16>()) {
+                (Ok(h), Ok(m)) if h < 24 && m < 60 && minutes.len() == 2 => Ok(h * 60 + m),
+                _ => Err(format!("invalid time {:?}, expected 00:00-23:59", t.trim())),
+            }
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got {:?}", s))?;
+        let window = TimeWindow { start: parse_time(start)?, end: parse_time(end)? };
+        if window.start == window.end {
+            return Err(format!("window {:?} is empty", s));
+        }
+        Ok(window)
+    }
+}
+
+// Time zone the --business-hours window is read in: local, utc or a fixed +HH:MM/-HH:MM
+#[derive(Clone, Copy, Debug)]
+enum WindowZone {
+    Local,
+    // Offset east of UTC, in seconds
+    Fixed(i32),
+}
+
+impl WindowZone {
+    fn minute_of_day(self, unix_secs: i64) -> u16 {
+        let offset = match self {
+            WindowZone::Local => chrono::Local::now().offset().local_minus_utc(),
+            WindowZone::Fixed(offset) => offset,
+        };
+        ((unix_secs + offset as i64).rem_euclid(86_400) / 60) as u16
+    }
+}
+
+impl FromStr for WindowZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => return Ok(WindowZone::Local),
+            "utc" | "z" => return Ok(WindowZone::Fixed(0)),
+            _ => {}
+        }
+        let invalid = || format!("invalid time zone {:?}, expected local, utc or +HH:MM", s);
+        let (sign, offset) = match (s.strip_prefix('+'), s.strip_prefix('-')) {
+            (Some(offset), _) => (1, offset),
+            (_, Some(offset)) => (-1, offset),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = offset.split_once(':').ok_or_else(invalid)?;
+        match (hours.parse::<i32>(), minutes.parse::<i32>()) {
+            (Ok(h), Ok(m)) if h <= 14 && m < 60 => Ok(WindowZone::Fixed(sign * (h * 3600 + m * 60))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Per-sink JSON rendering; `off` disables that sink
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum JsonStyle {
+    Pretty,
+    Compact,
+    Off,
+}
+
+// When the once-a-second progress line goes to stderr; auto means only on a terminal
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum ProgressMode {
+    Auto,
+    Always,
+    Never,
+}
+
+// Which of a host name's addresses are scanned, under --target-family
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum AddressFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn includes(self, ip: IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => ip.is_ipv4(),
+            AddressFamily::Ipv6 => ip.is_ipv6(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            AddressFamily::Any => "any",
+            AddressFamily::Ipv4 => "IPv4",
+            AddressFamily::Ipv6 => "IPv6",
+        }
+    }
+}
+
+// Whether stderr gets ANSI colors; auto means a terminal that NO_COLOR does not turn off
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    fn enabled(self, stderr_is_terminal: bool, no_color: Option<&str>, term: Option<&str>) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            // https://no-color.org: any non-empty value opts out
+            ColorMode::Auto => stderr_is_terminal && no_color.is_none_or(str::is_empty) && term != Some("dumb"),
+        }
+    }
+}
+
+// Set once by `run`; off for library callers, whose stderr is not ours to decorate
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+enum Style {
+    Bold,
+    Dim,
+    Good,
+    Warn,
+    Bad,
+}
+
+fn paint(text: impl std::fmt::Display, style: Style) -> String {
+    if !COLOR.load(Ordering::Relaxed) {
+        return text.to_string();
+    }
+    let code = match style {
+        Style::Bold => "1",
+        Style::Dim => "2",
+        Style::Good => "32",
+        Style::Warn => "33",
+        Style::Bad => "31",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum ProbeOrder {
+    Numeric,
+    // Most commonly open first, per TOP_PORTS; unranked ports last
+    Likely,
+}
+
+impl ProbeOrder {
+    fn arrange(self, ports: &[u16]) -> Vec<u16> {
+        let mut ports = ports.to_vec();
+        match self {
+            ProbeOrder::Numeric => ports.sort_unstable(),
+            ProbeOrder::Likely => ports.sort_by_key(|port| (port_rank(*port).unwrap_or(usize::MAX), *port)),
+        }
+        ports
+    }
+}
+
+// Field naming for every JSON output; the structs themselves are snake_case
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum JsonCase {
+    Snake,
+    Camel,
+}
+
+// PORT=FACTOR, e.g. 3306=3
+#[derive(Clone, Copy, Debug)]
+struct PortMultiplier {
+    port: u16,
+    factor: f64,
+}
+
+impl FromStr for PortMultiplier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, factor) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PORT=FACTOR, got {:?}", s))?;
+        let port = port.parse().map_err(|_| format!("invalid port {:?} in {:?}", port, s))?;
+        let factor: f64 = factor
+            .parse()
+            .map_err(|_| format!("invalid factor {:?} in {:?}", factor, s))?;
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(format!("factor in {:?} must be a positive number", s));
+        }
+        Ok(PortMultiplier { port, factor })
+    }
+}
+
+// Upper bounds of the local, LAN and WAN tiers in milliseconds
+const DEFAULT_LATENCY_TIERS: &str = "1,10,100";
+
+/// Where a host sits by its fastest measured round trip, under the bounds of [`LatencyTiers`]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LatencyTier {
+    Local,
+    Lan,
+    Wan,
+    Slow,
+}
+
+/// Exclusive upper bounds in milliseconds of the local, LAN and WAN tiers;
+/// anything at or above `wan` is slow
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencyTiers {
+    pub local: f64,
+    pub lan: f64,
+    pub wan: f64,
+}
+
+impl Default for LatencyTiers {
+    fn default() -> Self {
+        LatencyTiers { local: 1.0, lan: 10.0, wan: 100.0 }
+    }
+}
+
+impl LatencyTier {
+    fn name(self) -> &'static str {
+        match self {
+            LatencyTier::Local => "local",
+            LatencyTier::Lan => "lan",
+            LatencyTier::Wan => "wan",
+            LatencyTier::Slow => "slow",
+        }
+    }
+}
+
+impl LatencyTiers {
+    pub fn tier(&self, rtt_ms: f64) -> LatencyTier {
+        if rtt_ms < self.local {
+            LatencyTier::Local
+        } else if rtt_ms < self.lan {
+            LatencyTier::Lan
+        } else if rtt_ms < self.wan {
+            LatencyTier::Wan
+        } else {
+            LatencyTier::Slow
+        }
+    }
+}
+
+// LOCAL,LAN,WAN, e.g. 1,10,100
+impl FromStr for LatencyTiers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bounds = s
+            .split(',')
+            .map(|bound| match bound.trim().parse::<f64>() {
+                Ok(ms) if ms.is_finite() && ms > 0.0 => Ok(ms),
+                _ => Err(format!("invalid bound {:?} in {:?}, expected a positive number of milliseconds", bound, s)),
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+        match bounds[..] {
+            [local, lan, wan] if local < lan && lan < wan => Ok(LatencyTiers { local, lan, wan }),
+            [_, _, _] => Err(format!("bounds in {:?} must increase: LOCAL,LAN,WAN", s)),
+            _ => Err(format!("expected three bounds LOCAL,LAN,WAN, got {:?}", s)),
+        }
+    }
+}
+
+// The ClientHello sent under --ja3s; its JA3 goes into the report metadata so
+// JA3S fingerprints can be compared across runs that used the same profile
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum ClientHelloProfile {
+    // TLS 1.2, AEAD suites only
+    Modern,
+    // Modern plus CBC and 3DES suites, for older stacks that reject the modern set
+    Compat,
+}
+
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+const TLS_EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const TLS_EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const TLS_EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const TLS_EXT_EXTENDED_MASTER_SECRET: u16 = 0x0017;
+const TLS_EXT_RENEGOTIATION_INFO: u16 = 0xff01;
+
+// x25519, secp256r1, secp384r1
+const TLS_GROUPS: &[u16] = &[0x001d, 0x0017, 0x0018];
+const TLS_SIGNATURE_ALGORITHMS: &[u16] = &[0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601];
+
+impl ClientHelloProfile {
+    const VERSION: u16 = 0x0303;
+
+    fn ciphers(self) -> &'static [u16] {
+        match self {
+            ClientHelloProfile::Modern => &[0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0x009c, 0x009d],
+            ClientHelloProfile::Compat => &[
+                0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0x009c, 0x009d, 0xc013, 0xc014, 0xc009,
+                0xc00a, 0x002f, 0x0035, 0x000a,
+            ],
+        }
+    }
+
+    // In the order they are sent, which is part of the fingerprint; server_name
+    // (--sni) goes first when there is one
+    fn extensions(self, server_name: Option<&str>) -> Vec<(u16, Vec<u8>)> {
+        let u16_list = |items: &[u16]| {
+            let mut data = ((items.len() * 2) as u16).to_be_bytes().to_vec();
+            data.extend(items.iter().flat_map(|item| item.to_be_bytes()));
+            data
+        };
+        // A one-entry server_name_list holding a host_name (RFC 6066 section 3)
+        let sni = server_name.map(|name| {
+            let mut entry = vec![0x00];
+            entry.extend((name.len() as u16).to_be_bytes());
+            entry.extend(name.as_bytes());
+            let mut data = (entry.len() as u16).to_be_bytes().to_vec();
+            data.extend(entry);
+            (TLS_EXT_SERVER_NAME, data)
+        });
+        sni.into_iter().chain([
+            (TLS_EXT_RENEGOTIATION_INFO, vec![0x00]),
+            (TLS_EXT_SUPPORTED_GROUPS, u16_list(TLS_GROUPS)),
+            // uncompressed only
+            (TLS_EXT_EC_POINT_FORMATS, vec![0x01, 0x00]),
+            (TLS_EXT_SIGNATURE_ALGORITHMS, u16_list(TLS_SIGNATURE_ALGORITHMS)),
+            (TLS_EXT_EXTENDED_MASTER_SECRET, Vec::new()),
+        ])
+        .collect()
+    }
+
+    // SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats
+    fn ja3(self, server_name: Option<&str>) -> String {
+        let join = |items: &mut dyn Iterator<Item = u16>| items.map(|i| i.to_string()).collect::<Vec<_>>().join("-");
+        format!(
+            "{},{},{},{},0",
+            Self::VERSION,
+            join(&mut self.ciphers().iter().copied()),
+            join(&mut self.extensions(server_name).into_iter().map(|(ext, _)| ext)),
+            join(&mut TLS_GROUPS.iter().copied()),
+        )
+    }
+
+    fn client_hello(self, server_name: Option<&str>) -> Vec<u8> {
+        let mut body = Self::VERSION.to_be_bytes().to_vec();
+        // A fixed random keeps the bytes identical run to run; nothing is ever encrypted
+        body.extend([0u8; 32]);
+        body.push(0);
+        body.extend(((self.ciphers().len() * 2) as u16).to_be_bytes());
+        body.extend(self.ciphers().iter().flat_map(|c| c.to_be_bytes()));
+        body.extend([0x01, 0x00]);
+
+        let mut extensions = Vec::new();
+        for (ext, data) in self.extensions(server_name) {
+            extensions.extend(ext.to_be_bytes());
+            extensions.extend((data.len() as u16).to_be_bytes());
+            extensions.extend(data);
+        }
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Html,
+    Grep,
+    // nmap's -oG layout, for tools that already parse it
+    Grepable,
+    Graphml,
+    // Columns picked by --csv-columns
+    Csv,
+    // The --json-output document, next to --output-file
+    Json,
+    // Every probed TCP port of every live host, one flat record each
+    PortMatrix,
+    // The HTML graph colored by what changed since --baseline
+    DiffHtml,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum CsvColumn {
+    Ip,
+    // Empty unless --resolve found one
+    Hostname,
+    Subnet,
+    Os,
+    Role,
+    // Fastest connect, milliseconds
+    Rtt,
+    // Space-separated
+    Ports,
+}
+
+impl CsvColumn {
+    fn header(self) -> &'static str {
+        match self {
+            CsvColumn::Ip => "ip",
+            CsvColumn::Hostname => "hostname",
+            CsvColumn::Subnet => "subnet",
+            CsvColumn::Os => "os",
+            CsvColumn::Role => "role",
+            CsvColumn::Rtt => "rtt_ms",
+            CsvColumn::Ports => "ports",
+        }
+    }
+
+    fn value(self, result: &ScanResult) -> String {
+        match self {
+            CsvColumn::Ip => result.ip.clone(),
+            CsvColumn::Hostname => result.hostname.clone().unwrap_or_default(),
+            CsvColumn::Subnet => result.subnet.clone(),
+            CsvColumn::Os => result.os_guess.clone(),
+            CsvColumn::Role => result.role.clone(),
+            CsvColumn::Rtt => result.rtt().map(|rtt| format!("{:.2}", rtt)).unwrap_or_default(),
+            CsvColumn::Ports => result.open_ports.iter().map(|port| port.to_string()).collect::<Vec<_>>().join(" "),
+        }
+    }
+}
+
+impl OutputFormat {
+    // Terminal formats replace the JSON normally printed to stdout
+    fn writes_stdout(self) -> bool {
+        matches!(self, OutputFormat::Grep | OutputFormat::Grepable)
+    }
+
+    // `baseline` is the --baseline scan, which --format diff-html requires
+    fn report_job(self, args: &Args, metadata: &ReportMetadata, baseline: Option<&Arc<Vec<ScanResult>>>) -> ReportJob {
+        match self {
+            OutputFormat::Html => {
+                let collapse_prefix = args.collapse_subnets;
+                let merge = args.merge_dual_stack;
+                let notes = report_notes(metadata);
+                ReportJob {
+                    format: "html",
+                    path: args.output_file.clone(),
+                    write: Box::new(move |results, path| {
+                        let html = if merge {
+                            render_graph(&merge_dual_stack(results), collapse_prefix, None, &notes)?
+                        } else {
+                            render_graph(results, collapse_prefix, None, &notes)?
+                        };
+                        std::fs::write(path, html)
+                    }),
+                }
+            }
+            OutputFormat::Grep => ReportJob {
+                format: "grep",
+                path: "-".to_string(),
+                write: Box::new(write_grep),
+            },
+            OutputFormat::Grepable => {
+                let metadata = metadata.clone();
+                ReportJob {
+                    format: "grepable",
+                    path: "-".to_string(),
+                    write: Box::new(move |results, path| write_grepable(results, path, &metadata)),
+                }
+            }
+            OutputFormat::Graphml => ReportJob {
+                format: "graphml",
+                path: sibling_path(&args.output_file, "graphml"),
+                write: Box::new(write_graphml),
+            },
+            OutputFormat::Csv => {
+                let columns = args.csv_columns.clone();
+                ReportJob {
+                    format: "csv",
+                    path: sibling_path(&args.output_file, "csv"),
+                    write: Box::new(move |results, path| write_csv(results, path, &columns)),
+                }
+            }
+            OutputFormat::Json => {
+                // `off` only disables --json-output; asking for the format means writing it
+                let style = match args.json_output_style {
+                    JsonStyle::Compact => JsonStyle::Compact,
+                    _ => JsonStyle::Pretty,
+                };
+                let case = args.json_case;
+                let metadata = metadata.clone();
+                let deduplicate = args.deduplicate_banners;
+                ReportJob {
+                    format: "json",
+                    path: sibling_path(&args.output_file, "json"),
+                    write: Box::new(move |results, path| {
+                        write_json(&Report::new(&metadata, results).deduplicated_if(deduplicate), path, style, case)
+                    }),
+                }
+            }
+            OutputFormat::PortMatrix => {
+                let style = match args.json_output_style {
+                    JsonStyle::Compact => JsonStyle::Compact,
+                    _ => JsonStyle::Pretty,
+                };
+                let case = args.json_case;
+                ReportJob {
+                    format: "port-matrix",
+                    path: sibling_path(&args.output_file, "matrix.json"),
+                    write: Box::new(move |results, path| write_json(&port_matrix(results), path, style, case)),
+                }
+            }
+            OutputFormat::DiffHtml => {
+                let collapse_prefix = args.collapse_subnets;
+                let baseline = Arc::clone(baseline.expect("--format diff-html is checked for --baseline"));
+                let notes = report_notes(metadata);
+                ReportJob {
+                    format: "diff-html",
+                    path: sibling_path(&args.output_file, "diff.html"),
+                    write: Box::new(move |results, path| {
+                        let html = render_diff_visualization(&baseline, results, collapse_prefix, &notes)?;
+                        std::fs::write(path, html)
+                    }),
+                }
+            }
+        }
+    }
+}
+
+// Marks rotated copies so pruning never touches files the user named themselves
+const ROTATION_MARKER: &str = ".rotated-";
+
+fn split_output_path(path: &std::path::Path) -> (String, String) {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let suffix = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (stem, suffix)
+}
+
+// network_topology.html -> network_topology.rotated-<unix millis>[-N].html, with the
+// name reserved by creating it so concurrent runs cannot pick the same one
+fn rotated_path(path: &str, timestamp_ms: u128) -> Result<String, std::io::Error> {
+    let path = std::path::Path::new(path);
+    let (stem, suffix) = split_output_path(path);
+    for attempt in 0u32.. {
+        let name = match attempt {
+            0 => format!("{}{}{}{}", stem, ROTATION_MARKER, timestamp_ms, suffix),
+            n => format!("{}{}{}-{}{}", stem, ROTATION_MARKER, timestamp_ms, n, suffix),
+        };
+        let candidate = path.with_file_name(name);
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => return Ok(candidate.to_string_lossy().into_owned()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+// Deletes all but the newest `keep` rotated copies of `path`
+fn prune_rotated(path: &str, keep: usize) -> Result<(), std::io::Error> {
+    let path = std::path::Path::new(path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let (stem, suffix) = split_output_path(path);
+    let prefix = format!("{}{}", stem, ROTATION_MARKER);
+    let parse_stamp = |stamp: &str| -> Option<(u128, u32)> {
+        match stamp.split_once('-') {
+            Some((ms, n)) => Some((ms.parse().ok()?, n.parse().ok()?)),
+            None => Some((stamp.parse().ok()?, 0)),
+        }
+    };
+
+    let mut copies: Vec<((u128, u32), std::path::PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let stamp = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(&suffix))
+            .and_then(parse_stamp);
+        if let Some(stamp) = stamp {
+            copies.push((stamp, entry.path()));
+        }
+    }
+
+    copies.sort_by_key(|(stamp, _)| std::cmp::Reverse(*stamp));
+    for (_, old) in copies.into_iter().skip(keep) {
+        std::fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+// File formats other than HTML sit next to --output-file with their own extension
+fn sibling_path(output_file: &str, extension: &str) -> String {
+    std::path::Path::new(output_file)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScanResult {
+    pub ip: String,
+    /// PTR name under --resolve, without the trailing dot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    pub open_ports: Vec<u16>,
+    pub os_guess: String,
+    /// 0.0 to 1.0, see [`OsGuess`]; an --os-override is always 1.0
+    #[serde(default)]
+    pub os_confidence: f64,
+    /// "heuristic" or "override"
+    #[serde(default)]
+    pub os_source: String,
+    pub subnet: String,
+    /// The best of `roles`, or "unknown"
+    #[serde(default)]
+    pub role: String,
+    /// Every role an open port points to, highest score first; see [`infer_role`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<RoleScore>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unexpected_service: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unexpected_ports: Vec<u16>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub icmp_errors: HashMap<u16, IcmpError>,
+    /// Connect time per open port, milliseconds
+    #[serde(default)]
+    pub port_rtt: HashMap<u16, f64>,
+    /// Under --ping-first, the ICMP echo round trip in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping_rtt: Option<f64>,
+    /// Under --latency-tiers, the tier of the fastest connect or ping; absent with neither
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_tier: Option<LatencyTier>,
+    /// First line of what the service said, on --banner-ports or under --banner every open port
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub banners: HashMap<u16, String>,
+    /// Only in a --deduplicate-banners report: in place of `banners`, each banner's
+    /// index in the report's `banners` table. Loading the report expands them again
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub banner_ids: HashMap<u16, usize>,
+    /// Under --response-stats, how much each open port sent back and how soon
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub responses: HashMap<u16, ResponseStats>,
+    /// Ports whose response was far bigger or slower than the same port on other hosts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response_outliers: Vec<String>,
+    /// HTTP ports that answered the probe with a redirect, or with 511 Network
+    /// Authentication Required; see [`HttpRedirect`]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub http_redirects: HashMap<u16, HttpRedirect>,
+    /// Under --http-probe, what each HTTP or HTTPS port served for `/`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub http_pages: HashMap<u16, HttpPage>,
+    /// Under --cve-hints, CVEs known for a version some banner names. Advisory only:
+    /// banners can lie and distributions backport fixes without changing them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cve_hints: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub websocket_supported: bool,
+    /// MD5 of the JA3S string per TLS port, under --ja3s
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub ja3s: HashMap<u16, String>,
+    /// Leaf certificate per TLS port, under --tls-certs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tls_certificates: HashMap<u16, TlsCertificate>,
+    /// Some port answers far slower than the rest, as if relayed to another machine
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub possible_forward: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_host_key: Option<SshHostKey>,
+    /// Under --rdp-nla: whether the RDP server refuses every client that will not do
+    /// Network Level Authentication. false is a finding: the logon screen is exposed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdp_nla: Option<bool>,
+    /// Security protocol the server picks when offered all of them, e.g. "hybrid"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdp_security: Option<String>,
+    /// `ssh:<fingerprint>` or `tls:<sha256>`, whichever was captured, SSH preferred
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_fingerprint: Option<String>,
+    /// Other addresses with the same identity, e.g. one backend seen through CGNAT
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub same_device_as: Vec<String>,
+    /// Addresses of the other IP family that belong to the same device, matched by
+    /// identity or hostname
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dual_stack_addresses: Vec<String>,
+    /// What the host advertises over mDNS, under --mdns
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mdns_services: Vec<MdnsService>,
+    /// Under --udp-ports, every probed UDP port with what its answer (or silence) means
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub udp_ports: Vec<UdpPort>,
+    /// Local address of the first completed connect, i.e. the interface the scan left by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<String>,
+    /// What each probed TCP port did, in port order; only recorded for
+    /// `--format port-matrix` and never part of the JSON
+    #[serde(skip)]
+    pub probed_ports: Vec<ProbedPort>,
+    /// Under --probe-changed-only: the open ports matched the baseline, so banners,
+    /// TLS, SSH, RDP and WebSocket results were copied from it instead of re-probed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub carried_from_baseline: bool,
+    /// Connects made by the port scan, retries included
+    #[serde(default)]
+    pub attempts: u32,
+    /// False when a local failure (e.g. out of file descriptors) left some port
+    /// without an answer, so missing ports prove nothing
+    #[serde(default = "complete_by_default")]
+    pub complete: bool,
+}
+
+fn complete_by_default() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct ProbedPort {
+    pub port: u16,
+    pub state: PortState,
+    /// Milliseconds until the handshake completed or was refused
+    pub rtt: Option<f64>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortState {
+    Open,
+    /// Refused by a reset (or ICMP port unreachable)
+    Closed,
+    /// No answer within the timeout, after every retry
+    Filtered,
+    /// A local failure, e.g. no route or no file descriptors
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ResponseStats {
+    /// Everything read before the service went quiet or closed, up to a cap
+    pub bytes: usize,
+    /// Milliseconds from sending the probe (or connecting, for services that speak
+    /// first) to the first byte; absent when nothing came back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_byte_ms: Option<f64>,
+}
+
+/// How an HTTP port turned the probe away: a 3xx with its `Location`, or a 511
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct HttpRedirect {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+/// The page an HTTP port served under --http-probe, once redirects within the same
+/// port were followed
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct HttpPage {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// Where the last answer redirected to, when that was not followed: another host
+    /// or scheme, a loop, or too many hops
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+impl HttpPage {
+    // One line for the tooltip: `200 "Title" (Server)`, or where a redirect led
+    fn summary(&self) -> String {
+        let mut line = self.status.to_string();
+        if let Some(title) = &self.title {
+            line.push_str(&format!(" \"{}\"", title));
+        }
+        if let Some(server) = &self.server {
+            line.push_str(&format!(" ({})", server));
+        }
+        if let Some(location) = &self.location {
+            line.push_str(&format!(" -> {}", location));
+        }
+        line
+    }
+}
+
+/// A role and the summed weight of the open ports pointing to it
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RoleScore {
+    pub role: String,
+    pub score: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UdpPort {
+    pub port: u16,
+    /// Always "udp"; TCP ports are the ones in open_ports
+    pub protocol: String,
+    pub state: UdpState,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum UdpState {
+    /// Something answered the datagram
+    #[serde(rename = "open")]
+    Open,
+    /// Silence: a service that ignored our probe, or a firewall dropping it
+    #[serde(rename = "open|filtered")]
+    OpenFiltered,
+    /// ICMP port unreachable came back
+    #[serde(rename = "closed")]
+    Closed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MdnsService {
+    /// Instance label, e.g. "Office Printer"
+    pub name: String,
+    /// e.g. "_ipp._tcp"
+    pub service: String,
+    pub port: u16,
+}
+
+// Captured whether or not a client would trust it; what a client would object to
+// goes in validation_errors
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsCertificate {
+    pub subject: String,
+    pub issuer: String,
+    /// Unix timestamps, seconds
+    pub not_before: i64,
+    pub not_after: i64,
+    /// Hex SHA-256 of the DER certificate
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub validation_errors: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SshHostKey {
+    pub port: u16,
+    pub key_type: String,
+    /// OpenSSH style: `SHA256:<unpadded base64>`
+    pub fingerprint: String,
+}
+
+impl ScanResult {
+    // The fastest connect is the best stand-in for the host's network round trip
+    fn rtt(&self) -> Option<f64> {
+        self.port_rtt.values().copied().min_by(|a, b| a.total_cmp(b))
+    }
+
+    // A ping counts too, so a --ping-first host with nothing open still gets a tier
+    fn latency_tier_under(&self, tiers: &LatencyTiers) -> Option<LatencyTier> {
+        let fastest = self.rtt().into_iter().chain(self.ping_rtt).min_by(|a, b| a.total_cmp(b))?;
+        Some(tiers.tier(fastest))
+    }
+
+    fn slowest_port(&self) -> Option<(u16, f64)> {
+        self.port_rtt
+            .iter()
+            .map(|(&port, &rtt)| (port, rtt))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    // Everything the deep probes find, from the same host's earlier scan
+    fn carry_deep_probes(&mut self, previous: &ScanResult) {
+        self.unexpected_service = previous.unexpected_service;
+        self.unexpected_ports = previous.unexpected_ports.clone();
+        self.banners = previous.banners.clone();
+        self.responses = previous.responses.clone();
+        self.http_redirects = previous.http_redirects.clone();
+        self.http_pages = previous.http_pages.clone();
+        self.websocket_supported = previous.websocket_supported;
+        self.ja3s = previous.ja3s.clone();
+        self.tls_certificates = previous.tls_certificates.clone();
+        self.ssh_host_key = previous.ssh_host_key.clone();
+        self.rdp_nla = previous.rdp_nla;
+        self.rdp_security = previous.rdp_security.clone();
+        self.carried_from_baseline = true;
+    }
+
+    fn identity(&self) -> Option<String> {
+        if let Some(key) = &self.ssh_host_key {
+            return Some(format!("ssh:{}", key.fingerprint));
+        }
+        let mut certificates: Vec<_> = self.tls_certificates.iter().collect();
+        certificates.sort_by_key(|(port, _)| **port);
+        certificates.first().map(|(_, cert)| format!("tls:{}", cert.sha256))
+    }
+
+    // Connect() scanning never sees the SYN-ACK's TTL, so only latency is compared
+    fn looks_forwarded(&self) -> bool {
+        match (self.rtt(), self.slowest_port()) {
+            (Some(fastest), Some((_, slowest))) => {
+                slowest - fastest >= FORWARD_RTT_GAP_MS && slowest >= fastest * FORWARD_RTT_RATIO
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum MapperError {
+    #[error("invalid input: {0}")]
+    Parse(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("scan failed: {0}")]
+    Scan(String),
+    #[error("output failed: {0}")]
+    Output(String),
+}
+
+// `main` reports its error through Debug, so print the message rather than the variant
+impl std::fmt::Debug for MapperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<serde_json::Error> for MapperError {
+    fn from(e: serde_json::Error) -> Self {
+        MapperError::Output(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for MapperError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        MapperError::Scan(e.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReportMetadata {
+    scan_id: String,
+    // Unix timestamp, seconds
+    started_at: u64,
+    targets: usize,
+    // JA3 string of the ClientHello used for --ja3s
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_ja3: Option<String>,
+    // Each --target host name with the addresses it resolved to
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    resolved_targets: std::collections::BTreeMap<String, Vec<String>>,
+    // --target names that did not resolve, and why
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    unresolved_targets: std::collections::BTreeMap<String, String>,
+    // Filled in once the scan is done: how many live hosts have each port open
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    open_port_histogram: Vec<PortCount>,
+    // Set when --max-results kept only a random sample: the live hosts it was drawn from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampled_from: Option<usize>,
+    // Ctrl-C or a control socket "stop" ended the scan before every target was probed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    stopped: bool,
+    // Filled in once the scan is done, when HTTP answers look like a captive portal's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    captive_portal: Option<CaptivePortal>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct CaptivePortal {
+    // Where the hosts redirect to, or "511 Network Authentication Required"
+    answer: String,
+    hosts: usize,
+}
+
+// Redirects to one and the same place from this many hosts are a portal intercepting
+// port 80, not a coincidence
+const CAPTIVE_PORTAL_MIN_HOSTS: usize = 5;
+
+// On a guest network a captive portal answers HTTP for every address, so the
+// "hosts" behind it may not exist at all. Any 511 gives it away; otherwise the
+// most common redirect target counts once it reaches CAPTIVE_PORTAL_MIN_HOSTS
+fn detect_captive_portal(results: &[ScanResult]) -> Option<CaptivePortal> {
+    let mut by_answer: HashMap<String, usize> = HashMap::new();
+    for result in results {
+        let answers: std::collections::BTreeSet<String> = result
+            .http_redirects
+            .values()
+            .filter_map(|redirect| match (redirect.status, &redirect.location) {
+                (511, _) => Some("511 Network Authentication Required".to_string()),
+                (_, Some(location)) => Some(location.clone()),
+                (_, None) => None,
+            })
+            .collect();
+        for answer in answers {
+            *by_answer.entry(answer).or_default() += 1;
+        }
+    }
+    by_answer
+        .into_iter()
+        .filter(|(answer, hosts)| answer.starts_with("511") || *hosts >= CAPTIVE_PORTAL_MIN_HOSTS)
+        // A 511 beats any redirect; then the most hosts, then the first answer
+        .max_by_key(|(answer, hosts)| (answer.starts_with("511"), *hosts, std::cmp::Reverse(answer.clone())))
+        .map(|(answer, hosts)| CaptivePortal { answer, hosts })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct PortCount {
+    port: u16,
+    hosts: usize,
+}
+
+// Most common first, ties in port order
+fn open_port_histogram(results: &[ScanResult]) -> Vec<PortCount> {
+    let mut counts: HashMap<u16, usize> = HashMap::new();
+    for port in results.iter().flat_map(|result| &result.open_ports) {
+        *counts.entry(*port).or_default() += 1;
+    }
+    let mut histogram: Vec<PortCount> = counts.into_iter().map(|(port, hosts)| PortCount { port, hosts }).collect();
+    histogram.sort_by_key(|count| (std::cmp::Reverse(count.hosts), count.port));
+    histogram
+}
+
+// A uniform random sample of at most `capacity` hosts, however many are offered,
+// without holding more than that (Vitter's algorithm R). No capacity keeps them all
+struct Reservoir {
+    capacity: Option<usize>,
+    offered: usize,
+    hosts: Vec<ScanResult>,
+    // xorshift64* state, never zero
+    state: u64,
+}
+
+impl Reservoir {
+    fn new(capacity: Option<usize>) -> Self {
+        Reservoir::seeded(capacity, Uuid::new_v4().as_u128() as u64)
+    }
+
+    fn seeded(capacity: Option<usize>, seed: u64) -> Self {
+        Reservoir { capacity, offered: 0, hosts: Vec::new(), state: seed | 1 }
+    }
+
+    fn push(&mut self, host: ScanResult) {
+        self.offered += 1;
+        match self.capacity {
+            Some(capacity) if self.hosts.len() >= capacity => {
+                // The n-th host replaces a kept one with probability capacity / n
+                let slot = (self.next_random() % self.offered as u64) as usize;
+                if slot < capacity {
+                    self.hosts[slot] = host;
+                }
+            }
+            _ => self.hosts.push(host),
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // The kept hosts in address order, and how many were offered if some were dropped
+    fn into_hosts(self) -> (Vec<ScanResult>, Option<usize>) {
+        let mut hosts = self.hosts;
+        hosts.sort_by_key(|result| result.ip.parse::<IpAddr>().ok());
+        let sampled_from = (self.offered > hosts.len()).then_some(self.offered);
+        (hosts, sampled_from)
+    }
+}
+
+impl Extend<ScanResult> for Reservoir {
+    fn extend<I: IntoIterator<Item = ScanResult>>(&mut self, hosts: I) {
+        for host in hosts {
+            self.push(host);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    metadata: &'a ReportMetadata,
+    hosts: std::borrow::Cow<'a, [ScanResult]>,
+    // Under --deduplicate-banners, every distinct banner once; hosts refer to them by index
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    banners: Vec<String>,
+    graph: TopologyGraph,
+}
+
+impl<'a> Report<'a> {
+    fn new(metadata: &'a ReportMetadata, hosts: &'a [ScanResult]) -> Self {
+        Report { metadata, hosts: hosts.into(), banners: Vec::new(), graph: TopologyGraph::build(hosts) }
+    }
+
+    // A fleet of identical devices repeats the same few banners on every host, so each
+    // is written once and the hosts' banner_ids point into the table
+    fn deduplicate_banners(mut self) -> Self {
+        let mut ids: HashMap<String, usize> = HashMap::new();
+        for host in self.hosts.to_mut() {
+            for (port, banner) in std::mem::take(&mut host.banners) {
+                let next = ids.len();
+                let id = *ids.entry(banner.clone()).or_insert_with(|| {
+                    self.banners.push(banner);
+                    next
+                });
+                host.banner_ids.insert(port, id);
+            }
+        }
+        self
+    }
+
+    fn deduplicated_if(self, deduplicate: bool) -> Self {
+        if deduplicate {
+            self.deduplicate_banners()
+        } else {
+            self
+        }
+    }
+}
+
+// The inverse of Report::deduplicate_banners; a report without a table has no ids to expand
+fn expand_banners(hosts: &mut [ScanResult], table: &[String]) -> Result<(), String> {
+    for host in hosts {
+        for (port, id) in std::mem::take(&mut host.banner_ids) {
+            let banner = table
+                .get(id)
+                .ok_or_else(|| format!("host {} port {}: banner id {} is not in the banners table", host.ip, port, id))?;
+            host.banners.insert(port, banner.clone());
+        }
+    }
+    Ok(())
+}
+
+// The same subnet-host structure the HTML and GraphML reports draw, as plain data
+#[derive(Debug, Serialize)]
+struct TopologyGraph {
+    nodes: Vec<GraphNode>,
+    edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct GraphNode {
+    id: String,
+    // "subnet" or "host"
+    kind: &'static str,
+}
+
+// Undirected
+#[derive(Debug, Serialize, PartialEq)]
+struct GraphEdge {
+    source: String,
+    target: String,
+    // "subnet" from a subnet to each of its hosts, "same_device" between hosts
+    // sharing an identity
+    kind: &'static str,
+}
+
+impl TopologyGraph {
+    fn build(results: &[ScanResult]) -> Self {
+        let subnets: std::collections::BTreeSet<&str> = results.iter().map(|r| r.subnet.as_str()).collect();
+        let nodes = subnets
+            .iter()
+            .map(|subnet| GraphNode { id: subnet.to_string(), kind: "subnet" })
+            .chain(results.iter().map(|r| GraphNode { id: r.ip.clone(), kind: "host" }))
+            .collect();
+
+        let mut edges: Vec<GraphEdge> = results
+            .iter()
+            .map(|r| GraphEdge { source: r.subnet.clone(), target: r.ip.clone(), kind: "subnet" })
+            .collect();
+        for result in results {
+            // Each link is listed on both hosts; keep one
+            for other in result.same_device_as.iter().filter(|other| result.ip < **other) {
+                edges.push(GraphEdge { source: result.ip.clone(), target: other.clone(), kind: "same_device" });
+            }
+        }
+        TopologyGraph { nodes, edges }
+    }
+}
+
+// One NDJSON line on the sink, tagged so consumers can tell concurrent runs apart
+#[derive(Serialize)]
+struct SinkRecord<'a> {
+    scan_id: &'a str,
+    #[serde(flatten)]
+    host: &'a ScanResult,
+}
+
+// Open ports per live host, kept between runs so --sink-deltas-only can diff against them
+type SinkState = std::collections::BTreeMap<String, Vec<u16>>;
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DeltaChange {
+    Added,
+    Changed,
+    Removed,
+}
+
+impl DeltaChange {
+    fn name(self) -> &'static str {
+        match self {
+            DeltaChange::Added => "added",
+            DeltaChange::Changed => "changed",
+            DeltaChange::Removed => "removed",
+        }
+    }
+}
+
+// A --sink-deltas-only line: how one host differs from the previous run
+#[derive(Debug, Serialize)]
+struct SinkDelta<'a> {
+    scan_id: &'a str,
+    ip: &'a str,
+    change: DeltaChange,
+    opened: Vec<u16>,
+    closed: Vec<u16>,
+}
+
+impl<'a> SinkDelta<'a> {
+    // None when the host is unchanged; `current` is None for a probed host that is now down
+    fn between(scan_id: &'a str, ip: &'a str, previous: Option<&[u16]>, current: Option<&[u16]>) -> Option<Self> {
+        let change = match (previous, current) {
+            (None, None) => return None,
+            (None, Some(_)) => DeltaChange::Added,
+            (Some(_), None) => DeltaChange::Removed,
+            (Some(previous), Some(current)) => {
+                let sorted = |ports: &[u16]| {
+                    let mut ports = ports.to_vec();
+                    ports.sort_unstable();
+                    ports
+                };
+                if sorted(previous) == sorted(current) {
+                    return None;
+                }
+                DeltaChange::Changed
+            }
+        };
+        let (previous, current) = (previous.unwrap_or(&[]), current.unwrap_or(&[]));
+        let mut opened: Vec<u16> = current.iter().filter(|port| !previous.contains(port)).copied().collect();
+        let mut closed: Vec<u16> = previous.iter().filter(|port| !current.contains(port)).copied().collect();
+        opened.sort_unstable();
+        closed.sort_unstable();
+        Some(SinkDelta { scan_id, ip, change, opened, closed })
+    }
+}
+
+// `compare`: live hosts of two scans matched by IP, their open ports as sets
+#[derive(Debug, Default, Serialize, PartialEq)]
+struct ScanDiff {
+    // opened lists every open port of a new host and closed every port of a gone one
+    appeared: Vec<HostDiff>,
+    disappeared: Vec<HostDiff>,
+    changed: Vec<HostDiff>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct HostDiff {
+    ip: String,
+    opened: Vec<u16>,
+    closed: Vec<u16>,
+}
+
+impl HostDiff {
+    // e.g. "opened 443, closed 80"; a new host only lists what is open
+    fn summary(&self, change: DeltaChange) -> String {
+        let ports = |ports: &[u16]| ports.iter().map(u16::to_string).collect::<Vec<_>>().join(",");
+        match change {
+            DeltaChange::Added => format!("open {}", ports(&self.opened)),
+            DeltaChange::Removed => format!("was open {}", ports(&self.closed)),
+            DeltaChange::Changed => {
+                let mut changes = Vec::new();
+                if !self.opened.is_empty() {
+                    changes.push(format!("opened {}", ports(&self.opened)));
+                }
+                if !self.closed.is_empty() {
+                    changes.push(format!("closed {}", ports(&self.closed)));
+                }
+                changes.join(", ")
+            }
+        }
+    }
+}
+
+impl ScanDiff {
+    // Each list with its line marker in `compare` output
+    fn by_change(&self) -> [(char, DeltaChange, &[HostDiff]); 3] {
+        [
+            ('+', DeltaChange::Added, &self.appeared),
+            ('-', DeltaChange::Removed, &self.disappeared),
+            ('~', DeltaChange::Changed, &self.changed),
+        ]
+    }
+
+    fn between(old: &[ScanResult], new: &[ScanResult]) -> Self {
+        let ports = |results: &[ScanResult]| -> HashMap<String, Vec<u16>> {
+            results.iter().map(|result| (result.ip.clone(), result.open_ports.clone())).collect()
+        };
+        let (old, new) = (ports(old), ports(new));
+        let mut ips: Vec<&String> = old.keys().chain(new.keys()).collect();
+        // Address order, with anything unparsable after the rest
+        ips.sort_by_key(|ip| (ip.parse::<IpAddr>().map_err(|_| ()), ip.to_string()));
+        ips.dedup();
+
+        let mut diff = ScanDiff::default();
+        for ip in ips {
+            let previous = old.get(ip).map(Vec::as_slice);
+            let current = new.get(ip).map(Vec::as_slice);
+            let Some(delta) = SinkDelta::between("", ip, previous, current) else {
+                continue;
+            };
+            let host = HostDiff { ip: ip.clone(), opened: delta.opened, closed: delta.closed };
+            match delta.change {
+                DeltaChange::Added => diff.appeared.push(host),
+                DeltaChange::Removed => diff.disappeared.push(host),
+                DeltaChange::Changed => diff.changed.push(host),
+            }
+        }
+        diff
+    }
+}
+
+fn print_scan_diff(old: &str, new: &str, diff: &ScanDiff) {
+    println!(
+        "{} -> {}: {} appeared, {} disappeared, {} changed",
+        old,
+        new,
+        diff.appeared.len(),
+        diff.disappeared.len(),
+        diff.changed.len()
+    );
+    for (mark, change, hosts) in diff.by_change() {
+        for host in hosts {
+            println!("{} {:<15}  {}", mark, host.ip, host.summary(change));
+        }
+    }
+}
+
+fn load_sink_state(path: &str) -> Result<SinkState, MapperError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| MapperError::Parse(format!("--sink-state {}: {}", path, e))),
+        // First run: every live host is new
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SinkState::new()),
+        Err(e) => Err(MapperError::Parse(format!("--sink-state {}: {}", path, e))),
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IcmpError {
+    pub icmp_type: u8,
+    pub code: u8,
+    pub meaning: String,
+    /// Router or host that sent the error
+    pub from: Ipv4Addr,
+}
+
+impl IcmpError {
+    // Only an error sent by the target itself shows it is up; a router's "host
+    // unreachable" (e.g. a failed ARP lookup) means the opposite
+    fn proves_host_up(&self, target: Ipv4Addr) -> bool {
+        self.from == target && self.icmp_type == 3 && matches!(self.code, 2 | 3 | 9 | 10 | 13)
+    }
+}
+
+// ICMP errors seen by the raw listener, keyed by the quoted destination of our SYN
+type IcmpErrorTable = std::sync::Mutex<HashMap<(Ipv4Addr, u16), IcmpError>>;
+
+// Hosts drain their own entries; this bounds what stray or late errors can add
+const MAX_ICMP_ERRORS: usize = 4096;
+
+/// How each host is scanned. Start from [`ScanOptions::default`], which matches the
+/// command line run without options, and set the fields you need.
+pub struct ScanOptions {
+    /// Connect timeout per attempt
+    pub timeout: Duration,
+    /// How long to wait for a banner or probe answer; some ports get a multiple of it
+    pub read_timeout: Duration,
+    connect_limit: Option<Arc<Semaphore>>,
+    /// TCP ports, in probe order
+    pub ports: Vec<u16>,
+    /// UDP ports probed after the TCP ones; see [`UdpState`]
+    pub udp_ports: Vec<u16>,
+    /// Prefix length of each IPv4 result's `subnet`
+    pub subnet_prefix: u8,
+    /// Prefix length of each IPv6 result's `subnet`
+    pub ipv6_subnet_prefix: u8,
+    /// Check that well-known ports speak their protocol (`--verify-services`)
+    pub verify_services: bool,
+    /// Open ports whose banner is recorded
+    pub banner_ports: Vec<u16>,
+    /// Record a banner on every open port, not just `banner_ports`
+    pub banner_all: bool,
+    /// Read every open port's whole response, recording its size and first-byte time
+    pub response_stats: bool,
+    // Empty unless --cve-hints
+    cve_hints: Vec<CveHint>,
+    /// Nameserver asked for each live host's PTR record, filling `hostname`
+    pub resolver: Option<std::net::SocketAddr>,
+    /// Try a WebSocket upgrade on open plain-HTTP ports
+    pub detect_websocket: bool,
+    /// GET `/` from open HTTP and HTTPS ports, filling `http_pages`
+    pub http_probe: bool,
+    /// Sent as the User-Agent of every HTTP probe
+    pub user_agent: String,
+    /// Capture the host key of open SSH ports
+    pub ssh_host_keys: bool,
+    /// Check whether open RDP ports require Network Level Authentication
+    pub rdp_nla: bool,
+    // Sent to TLS ports under --ja3s or --tls-certs
+    client_hello: Option<Vec<u8>>,
+    ja3s: bool,
+    tls_certs: bool,
+    /// Extra connect attempts after a timeout
+    pub retries: u32,
+    /// Pause before the first retry; each later one waits twice as long as the last
+    pub retry_delay: Duration,
+    /// Count a refused connect (RST) as open
+    pub rst_as_open: bool,
+    /// Hosts that answer on none of these are skipped without a full scan
+    pub alive_ports: Option<Vec<u16>>,
+    /// Skip IPv4 hosts that do not answer an ICMP echo; see [`ping_host`]
+    pub ping_first: bool,
+    /// Fill each result's `latency_tier` under these bounds
+    pub latency_tiers: Option<LatencyTiers>,
+    /// Connect only to this port and close at once: a host that accepts or refuses
+    /// is up, and nothing else about it is learned. `ports` is ignored
+    pub liveness_port: Option<u16>,
+    // Most specific prefix first
+    os_overrides: Vec<(Ipv4Cidr, String)>,
+    // The built-in rules, or as changed by --role-rules
+    role_rules: Vec<RoleRule>,
+    // Under --import-masscan, the ports masscan found open on each of its hosts; those
+    // hosts are probed on these instead of `ports`
+    imported_ports: Option<Arc<HashMap<IpAddr, Vec<u16>>>>,
+    icmp_errors: Option<Arc<IcmpErrorTable>>,
+    read_timeout_multipliers: HashMap<u16, f64>,
+    throttle: Option<Throttle>,
+    rate_limit: Option<RateLimit>,
+    // Fill ScanResult::probed_ports, for --format port-matrix
+    record_probed_ports: bool,
+    // Under --probe-changed-only, the baseline's hosts; see ScanResult::carried_from_baseline
+    baseline: Option<Arc<HashMap<IpAddr, ScanResult>>>,
+    // Under --rtt-timeouts, the connect timeout of each host the baseline timed; see rtt_timeout
+    host_timeouts: Option<Arc<HashMap<IpAddr, Duration>>>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            connect_limit: None,
+            ports: DEFAULT_SCAN_PORTS.to_vec(),
+            udp_ports: Vec::new(),
+            subnet_prefix: DEFAULT_SUBNET_PREFIX,
+            ipv6_subnet_prefix: IPV6_SUBNET_PREFIX,
+            verify_services: false,
+            banner_ports: DEFAULT_BANNER_PORTS.parse::<PortList>().map(|ports| ports.0).unwrap_or_default(),
+            banner_all: false,
+            response_stats: false,
+            cve_hints: Vec::new(),
+            resolver: None,
+            detect_websocket: false,
+            http_probe: false,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            ssh_host_keys: false,
+            rdp_nla: false,
+            client_hello: None,
+            ja3s: false,
+            tls_certs: false,
+            retries: 0,
+            retry_delay: Duration::from_millis(100),
+            rst_as_open: false,
+            alive_ports: None,
+            liveness_port: None,
+            ping_first: false,
+            latency_tiers: None,
+            os_overrides: Vec::new(),
+            role_rules: builtin_role_rules(),
+            imported_ports: None,
+            icmp_errors: None,
+            read_timeout_multipliers: SLOW_PORT_MULTIPLIERS.iter().copied().collect(),
+            throttle: None,
+            rate_limit: None,
+            record_probed_ports: false,
+            baseline: None,
+            host_timeouts: None,
+        }
+    }
+}
+
+/// Scans every address from `start` to `end` inclusive, up to 100 hosts at a time,
+/// and returns the live ones in address order. Hosts sharing an SSH host key or TLS
+/// certificate are linked through `same_device_as`.
+pub async fn scan_range(start: Ipv4Addr, end: Ipv4Addr, opts: ScanOptions) -> Vec<ScanResult> {
+    let options = Arc::new(opts);
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_SCANS));
+    let tasks: Vec<_> = (u32::from(start)..=u32::from(end))
+        .map(|ip| {
+            let (options, semaphore) = (Arc::clone(&options), Arc::clone(&semaphore));
+            tokio::spawn(async move {
+                let _permit = semaphore.acquire().await.unwrap();
+                scan_host(IpAddr::V4(Ipv4Addr::from(ip)), &options).await
+            })
+        })
+        .collect();
+
+    let mut results = Vec::new();
+    for task in tasks {
+        // A panicking host task loses that host, not the scan
+        if let Ok(Some(result)) = task.await {
+            results.push(result);
+        }
+    }
+    correlate_identities(&mut results);
+    results
+}
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Under --rtt-timeouts a host gets this many times its slowest round trip last time
+const RTT_TIMEOUT_FACTOR: f64 = 4.0;
+// Never tighter than this, however near the host: a busy host answers late now and then
+const RTT_TIMEOUT_FLOOR: Duration = Duration::from_millis(50);
+
+// The connect timeout for a host a previous scan timed, from its slowest connect or
+// ping then. A fast host gets a tight one, so its filtered ports cost little; a slow
+// one keeps up to `ceiling` (--timeout-ms). None when nothing was timed
+fn rtt_timeout(previous: &ScanResult, ceiling: Duration) -> Option<Duration> {
+    let slowest = previous.port_rtt.values().copied().chain(previous.ping_rtt).max_by(|a, b| a.total_cmp(b))?;
+    let scaled = Duration::try_from_secs_f64(slowest * RTT_TIMEOUT_FACTOR / 1000.0).ok()?;
+    Some(scaled.max(RTT_TIMEOUT_FLOOR).min(ceiling))
+}
+
+// Errors counted toward --throttle-threshold must fall within this long
+const THROTTLE_ERROR_WINDOW: Duration = Duration::from_secs(1);
+// Gap between connects right after the first cooldown; doubles on every further trip
+const THROTTLE_MIN_SPACING: Duration = Duration::from_millis(10);
+const THROTTLE_MAX_SPACING: Duration = Duration::from_secs(1);
+
+// --throttle-on-errors: a burst of local connect errors pauses every new connect for
+// the cooldown, after which connects are spaced out and sped up again as they succeed
+struct Throttle {
+    threshold: usize,
+    cooldown: Duration,
+    state: std::sync::Mutex<ThrottleState>,
+}
+
+#[derive(Default)]
+struct ThrottleState {
+    errors: std::collections::VecDeque<Instant>,
+    // When the next connect may start; reserved by each caller in turn
+    next_slot: Option<Instant>,
+    spacing: Duration,
+}
+
+impl Throttle {
+    fn new(threshold: usize, cooldown: Duration) -> Self {
+        Throttle { threshold, cooldown, state: std::sync::Mutex::new(ThrottleState::default()) }
+    }
+
+    // When a connect asked for at `now` may start
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let start = state.next_slot.map_or(now, |slot| slot.max(now));
+        state.next_slot = Some(start + state.spacing);
+        start
+    }
+
+    // Returns true when this error tripped the cooldown
+    fn record(&self, failed: bool, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !failed {
+            // Each success trims the gap by a tenth, down to none once it is negligible
+            state.spacing = state.spacing.mul_f64(0.9);
+            if state.spacing < Duration::from_millis(1) {
+                state.spacing = Duration::ZERO;
+            }
+            return false;
+        }
+
+        state.errors.push_back(now);
+        while state.errors.front().is_some_and(|at| now.duration_since(*at) > THROTTLE_ERROR_WINDOW) {
+            state.errors.pop_front();
+        }
+        if state.errors.len() < self.threshold {
+            return false;
+        }
+        state.errors.clear();
+        state.spacing = (state.spacing * 2).clamp(THROTTLE_MIN_SPACING, THROTTLE_MAX_SPACING);
+        state.next_slot = Some(now + self.cooldown);
+        true
+    }
+}
+
+// --rate lets this much of a second's budget go out back to back
+const RATE_BURST: Duration = Duration::from_millis(100);
+
+// --rate: a token bucket of connects, refilled at `rate` per second and holding at
+// most RATE_BURST worth (at least one). A caller finding it empty takes a token on
+// credit and waits until it would have arrived, so waiters queue up in order
+struct RateLimit {
+    rate: f64,
+    capacity: f64,
+    state: std::sync::Mutex<(f64, Option<Instant>)>,
+}
+
+impl RateLimit {
+    fn new(rate: f64) -> Self {
+        let capacity = (rate * RATE_BURST.as_secs_f64()).max(1.0);
+        RateLimit { rate, capacity, state: std::sync::Mutex::new((capacity, None)) }
+    }
+
+    // When a connect asked for at `now` may start
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, refilled) = &mut *state;
+        if let Some(refilled) = refilled.filter(|refilled| now > *refilled) {
+            *tokens = (*tokens + now.duration_since(refilled).as_secs_f64() * self.rate).min(self.capacity);
+        }
+        *refilled = Some(refilled.map_or(now, |refilled| refilled.max(now)));
+        *tokens -= 1.0;
+        if *tokens >= 0.0 {
+            now
+        } else {
+            now + Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+impl ScanOptions {
+    // Every connect goes through here so none can bypass --connect-limit; hold the
+    // permit until the socket is dropped
+    async fn socket_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.connect_limit {
+            Some(limit) => Some(Arc::clone(limit).acquire_owned().await.unwrap()),
+            None => None,
+        }
+    }
+
+    async fn wait_for_throttle(&self) {
+        if let Some(rate_limit) = &self.rate_limit {
+            tokio::time::sleep_until(rate_limit.reserve(Instant::now())).await;
+        }
+        if let Some(throttle) = &self.throttle {
+            tokio::time::sleep_until(throttle.reserve(Instant::now())).await;
+        }
+    }
+
+    fn record_for_throttle(&self, outcome: &ConnectOutcome) {
+        if let Some(throttle) = &self.throttle {
+            // Only local failures count; timeouts and refusals are ordinary scan results
+            if throttle.record(matches!(outcome, ConnectOutcome::Error(_)), Instant::now()) {
+                warning!(
+                    "{} connect errors within {:?}, pausing connects for {:?}",
+                    throttle.threshold, THROTTLE_ERROR_WINDOW, throttle.cooldown
+                );
+            }
+        }
+    }
+
+    fn connect_timeout_for(&self, ip: IpAddr) -> Duration {
+        match &self.host_timeouts {
+            Some(timeouts) => timeouts.get(&ip).copied().unwrap_or(self.timeout),
+            None => self.timeout,
+        }
+    }
+
+    fn read_timeout_for(&self, port: u16) -> Duration {
+        match self.read_timeout_multipliers.get(&port) {
+            Some(factor) => self.read_timeout.mul_f64(*factor),
+            None => self.read_timeout,
+        }
+    }
+}
+
+type ReportWriter = Box<dyn FnOnce(&[ScanResult], &str) -> Result<(), std::io::Error> + Send>;
+
+struct ReportJob {
+    format: &'static str,
+    path: String,
+    write: ReportWriter,
+}
+
+enum ConnectOutcome {
+    Open(TcpStream, Duration),
+    // The SYN drew a reset. Linux reports that as ECONNREFUSED, other stacks
+    // sometimes as ECONNRESET; either way the host answered in this long
+    Refused(Duration),
+    TimedOut,
+    Error(std::io::Error),
+}
+
+impl ConnectOutcome {
+    // Whether the port got an answer by our own standards: silence after every retry
+    // counts, and so does finding no route to a host that is not there
+    fn is_conclusive(&self) -> bool {
+        use std::io::ErrorKind;
+        match self {
+            ConnectOutcome::Error(e) => matches!(e.kind(), ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable),
+            ConnectOutcome::Open(..) | ConnectOutcome::Refused(_) | ConnectOutcome::TimedOut => true,
+        }
+    }
+
+    // A refusal is a definitive answer from the host; only silence or a transient
+    // local failure might turn out differently on another attempt
+    fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind;
+        match self {
+            ConnectOutcome::TimedOut => true,
+            ConnectOutcome::Error(e) => matches!(
+                e.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::AddrNotAvailable
+                    | ErrorKind::ConnectionAborted
+            ),
+            ConnectOutcome::Open(..) | ConnectOutcome::Refused(_) => false,
+        }
+    }
+}
+
+// RFC 1918, plus loopback, link-local and the RFC 6598 carrier-grade NAT range. For
+// IPv6: loopback, unique local fc00::/7 and link-local fe80::/10
+fn is_internal(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            return ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80;
+        }
+    };
+    let shared = Ipv4Cidr { network: Ipv4Addr::new(100, 64, 0, 0), prefix_len: 10 };
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || shared.contains(ip)
+}
+
+// Neither internal nor in a range that is never routed on the internet. For IPv6,
+// global unicast 2000::/3 less documentation 2001:db8::/32
+fn is_globally_routable(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => {
+            let [first, second, ..] = ip.segments();
+            return first & 0xe000 == 0x2000 && !(first == 0x2001 && second == 0x0db8);
+        }
+    };
+    const SPECIAL: &[(Ipv4Addr, u8)] = &[
+        // "this network"
+        (Ipv4Addr::new(0, 0, 0, 0), 8),
+        // IETF protocol assignments
+        (Ipv4Addr::new(192, 0, 0, 0), 24),
+        // benchmarking
+        (Ipv4Addr::new(198, 18, 0, 0), 15),
+        // multicast, then reserved up to and including broadcast
+        (Ipv4Addr::new(224, 0, 0, 0), 4),
+        (Ipv4Addr::new(240, 0, 0, 0), 4),
+    ];
+    !is_internal(IpAddr::V4(ip))
+        && !ip.is_documentation()
+        && !SPECIAL
+            .iter()
+            .any(|&(network, prefix_len)| Ipv4Cidr { network, prefix_len }.contains(ip))
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Ipv4Cidr {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    fn containing(ip: Ipv4Addr, prefix_len: u8) -> Self {
+        let mut cidr = Ipv4Cidr { network: ip, prefix_len };
+        cidr.network = Ipv4Addr::from(u32::from(ip) & cidr.mask());
+        cidr
+    }
+
+    fn mask(&self) -> u32 {
+        if self.prefix_len == 0 {
+            0
+        } else {
+            u32::MAX << (32 - self.prefix_len)
+        }
+    }
+
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & self.mask() == u32::from(self.network)
+    }
+
+    // First and last usable host: the network and broadcast addresses are left out,
+    // except in a /31 (RFC 3021) and /32 where every address is a host
+    fn host_range(&self) -> (Ipv4Addr, Ipv4Addr) {
+        let first = u32::from(self.network);
+        let last = first | !self.mask();
+        if self.prefix_len >= 31 {
+            (Ipv4Addr::from(first), Ipv4Addr::from(last))
+        } else {
+            (Ipv4Addr::from(first + 1), Ipv4Addr::from(last - 1))
+        }
+    }
+}
+
+// The network of `ip` under the prefix length of its family; see subnet_prefixes
+fn subnet_of(ip: IpAddr, prefix_len: u8, ipv6_prefix_len: u8) -> String {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Cidr::containing(ip, prefix_len).to_string(),
+        IpAddr::V6(ip) => {
+            let network = u128::from(ip) & !u128::MAX.checked_shr(u32::from(ipv6_prefix_len)).unwrap_or(0);
+            format!("{}/{}", Ipv6Addr::from(network), ipv6_prefix_len)
+        }
+    }
+}
+
+// The (IPv4, IPv6) prefix lengths results are grouped by: --subnet-prefix, else the
+// --cidr being scanned, else the smallest network holding a --start-ip/--end-ip range
+// that spans several /24s. Otherwise a /24, and a /64 for IPv6, the size of almost
+// every IPv6 LAN
+fn subnet_prefixes(args: &Args) -> Result<(u8, u8), MapperError> {
+    let ipv6_prefix = match args.cidr {
+        Some(CidrTarget::V6(cidr)) => cidr.prefix_len,
+        _ => IPV6_SUBNET_PREFIX,
+    };
+    let prefix = match (args.subnet_prefix, args.cidr) {
+        (Some(prefix), _) => prefix,
+        (None, Some(CidrTarget::V4(cidr))) => cidr.prefix_len,
+        _ => match target_range(args)? {
+            Some(range) => ((range.start() ^ range.end()).leading_zeros() as u8).min(DEFAULT_SUBNET_PREFIX),
+            None => DEFAULT_SUBNET_PREFIX,
+        },
+    };
+    Ok((prefix, ipv6_prefix))
+}
+
+fn in_subnets(ip: IpAddr, subnets: &[Ipv4Cidr]) -> bool {
+    match ip {
+        IpAddr::V4(ip) => subnets.iter().any(|cidr| cidr.contains(ip)),
+        IpAddr::V6(_) => false,
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = String;
+
+    // Accepts "a.b.c.d/len" or a bare address as a /32
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => {
+                let len: u8 = len
+                    .parse()
+                    .map_err(|_| format!("invalid prefix length {:?} in {:?}", len, s))?;
+                (addr, len)
+            }
+            None => (s, 32),
+        };
+        if prefix_len > 32 {
+            return Err(format!("prefix length {} in {:?} is out of range 0-32", prefix_len, s));
+        }
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| format!("invalid IPv4 address {:?} in {:?}", addr, s))?;
+
+        Ok(Ipv4Cidr::containing(addr, prefix_len))
+    }
+}
+
+// A small IPv6 network given to --cidr; anything bigger than --max-hosts is refused
+#[derive(Clone, Copy, Debug)]
+struct Ipv6Cidr {
+    network: Ipv6Addr,
+    prefix_len: u8,
+}
+
+impl Ipv6Cidr {
+    // Addresses in the network; None for a /0, which no integer holds
+    fn size(&self) -> Option<u128> {
+        1u128.checked_shl(u32::from(128 - self.prefix_len))
+    }
+
+    // Every address but the all-zeros one, the subnet-router anycast address;
+    // a /127 or /128 keeps it, as an IPv4 /31 keeps its network address
+    fn hosts(&self) -> impl Iterator<Item = Ipv6Addr> {
+        let first = u128::from(self.network);
+        let last = first | (u128::MAX.checked_shr(u32::from(self.prefix_len)).unwrap_or(0));
+        let first = if self.prefix_len >= 127 { first } else { first + 1 };
+        (first..=last).map(Ipv6Addr::from)
+    }
+}
+
+// --cidr: an IPv4 network, or an IPv6 one small enough to enumerate
+#[derive(Clone, Copy, Debug)]
+enum CidrTarget {
+    V4(Ipv4Cidr),
+    V6(Ipv6Cidr),
+}
+
+impl FromStr for CidrTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.contains(':') {
+            return s.parse().map(CidrTarget::V4);
+        }
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => {
+                let len: u8 = len
+                    .parse()
+                    .map_err(|_| format!("invalid prefix length {:?} in {:?}", len, s))?;
+                (addr, len)
+            }
+            None => (s, 128),
+        };
+        if prefix_len > 128 {
+            return Err(format!("prefix length {} in {:?} is out of range 0-128", prefix_len, s));
+        }
+        let addr: Ipv6Addr = addr
+            .parse()
+            .map_err(|_| format!("invalid IPv6 address {:?} in {:?}", addr, s))?;
+        let mask = u128::MAX.checked_shl(u32::from(128 - prefix_len)).unwrap_or(0);
+        Ok(CidrTarget::V6(Ipv6Cidr { network: Ipv6Addr::from(u128::from(addr) & mask), prefix_len }))
+    }
+}
+
+impl std::fmt::Display for CidrTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CidrTarget::V4(cidr) => cidr.fmt(f),
+            CidrTarget::V6(cidr) => write!(f, "{}/{}", cidr.network, cidr.prefix_len),
+        }
+    }
+}
+
+impl std::fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+// Shared between scan tasks and the control socket
+struct ScanProgress {
+    // Grows as targets arrive under --stdin
+    total: AtomicUsize,
+    probed: AtomicUsize,
+    live: AtomicUsize,
+    stopped: AtomicBool,
+    // Hosts wait at this gate before starting; a paused scan lets in-flight hosts finish
+    paused: watch::Sender<bool>,
+}
+
+// What a well-known port is expected to say when it runs its usual service
+struct ExpectedService {
+    port: u16,
+    // Sent first for protocols where the client speaks before the server
+    probe: Option<ServiceProbe>,
+    matches: fn(&[u8]) -> bool,
+}
+
+enum ServiceProbe {
+    Bytes(&'static [u8]),
+    // HEAD / with --user-agent
+    HttpHead,
+}
+
+const EXPECTED_SERVICES: &[ExpectedService] = &[
+    ExpectedService { port: 21, probe: None, matches: |b| b.starts_with(b"220") },
+    ExpectedService { port: 22, probe: None, matches: |b| b.starts_with(b"SSH-") },
+    ExpectedService { port: 25, probe: None, matches: |b| b.starts_with(b"220") },
+    ExpectedService {
+        port: 80,
+        probe: Some(ServiceProbe::HttpHead),
+        matches: |b| b.starts_with(b"HTTP/"),
+    },
+    ExpectedService { port: 110, probe: None, matches: |b| b.starts_with(b"+OK") },
+    ExpectedService { port: 143, probe: None, matches: |b| b.starts_with(b"* OK") },
+    // MySQL handshake (protocol 10) or an error packet, after the 4-byte packet header
+    ExpectedService {
+        port: 3306,
+        probe: None,
+        matches: |b| b.len() > 4 && (b[4] == 0x0a || b[4] == 0xff),
+    },
+    // PostgreSQL answers an SSLRequest with a single 'S' or 'N'
+    ExpectedService {
+        port: 5432,
+        probe: Some(ServiceProbe::Bytes(&[0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f])),
+        matches: |b| b == b"S" || b == b"N",
+    },
+];
+
+// Probed on every host that gets a full scan unless --ports says otherwise
+const DEFAULT_SCAN_PORTS: &[u16] = &[21, 22, 80, 443, 3306, 5432];
+
+// Nmap's top 1000 TCP ports (nmap-services frequencies), for --top-ports and
+// --probe-order likely. The first 100 are ranked, most often open first; the rest
+// follow in port order
+const TOP_PORTS: &[u16] = &[
+    80, 23, 443, 21, 22, 25, 3389, 110, 445, 139, 143, 53, 135, 3306, 8080, 1723, 111, 995, 993, 5900, 1025,
+    587, 8888, 199, 1720, 465, 548, 113, 81, 6001, 10000, 514, 5060, 179, 1026, 2000, 8443, 8000, 32768, 554,
+    26, 1433, 49152, 2001, 515, 8008, 49154, 1027, 5666, 646, 5000, 5631, 631, 49153, 8081, 2049, 88, 79,
+    5800, 106, 2121, 1110, 49155, 6000, 513, 990, 5357, 427, 49156, 543, 544, 5101, 144, 7, 389, 8009, 3128,
+    444, 9999, 5009, 7070, 5190, 3000, 5432, 1900, 3986, 13, 1029, 9, 5051, 6646, 49157, 1028, 873, 1755,
+    2717, 4899, 9100, 119, 37,
+    1, 3, 4, 6, 17, 19, 20, 24, 30, 32, 33, 42, 43, 49, 70, 82, 83, 84, 85, 89, 90, 99, 100, 109, 125, 146,
+    161, 163, 211, 212, 222, 254, 255, 256, 259, 264, 280, 301, 306, 311, 340, 366, 406, 407, 416, 417, 425,
+    458, 464, 481, 497, 500, 512, 524, 541, 545, 555, 563, 593, 616, 617, 625, 636, 648, 666, 667, 668, 683,
+    687, 691, 700, 705, 711, 714, 720, 722, 726, 749, 765, 777, 783, 787, 800, 801, 808, 843, 880, 888, 898,
+    900, 901, 902, 903, 911, 912, 981, 987, 992, 999, 1000, 1001, 1002, 1007, 1009, 1010, 1011, 1021, 1022,
+    1023, 1024, 1030, 1031, 1032, 1033, 1034, 1035, 1036, 1037, 1038, 1039, 1040, 1041, 1042, 1043, 1044,
+    1045, 1046, 1047, 1048, 1049, 1050, 1051, 1052, 1053, 1054, 1055, 1056, 1057, 1058, 1059, 1060, 1061,
+    1062, 1063, 1064, 1065, 1066, 1067, 1068, 1069, 1070, 1071, 1072, 1073, 1074, 1075, 1076, 1077, 1078,
+    1079, 1080, 1081, 1082, 1083, 1084, 1085, 1086, 1087, 1088, 1089, 1090, 1091, 1092, 1093, 1094, 1095,
+    1096, 1097, 1098, 1099, 1100, 1102, 1104, 1105, 1106, 1107, 1108, 1111, 1112, 1113, 1114, 1117, 1119,
+    1121, 1122, 1123, 1124, 1126, 1130, 1131, 1132, 1137, 1138, 1141, 1145, 1147, 1148, 1149, 1151, 1152,
+    1154, 1163, 1164, 1165, 1166, 1169, 1174, 1175, 1183, 1185, 1186, 1187, 1192, 1198, 1199, 1201, 1213,
+    1216, 1217, 1218, 1233, 1234, 1236, 1244, 1247, 1248, 1259, 1271, 1272, 1277, 1287, 1296, 1300, 1301,
+    1309, 1310, 1311, 1322, 1328, 1334, 1352, 1417, 1434, 1443, 1455, 1461, 1494, 1500, 1501, 1503, 1521,
+    1524, 1533, 1556, 1580, 1583, 1594, 1600, 1641, 1658, 1666, 1687, 1688, 1700, 1717, 1718, 1719, 1721,
+    1761, 1782, 1783, 1801, 1805, 1812, 1839, 1840, 1862, 1863, 1864, 1875, 1914, 1935, 1947, 1971, 1972,
+    1974, 1984, 1998, 1999, 2002, 2003, 2004, 2005, 2006, 2007, 2008, 2009, 2010, 2013, 2020, 2021, 2022,
+    2030, 2033, 2034, 2035, 2038, 2040, 2041, 2042, 2043, 2045, 2046, 2047, 2048, 2065, 2068, 2099, 2100,
+    2103, 2105, 2106, 2107, 2111, 2119, 2126, 2135, 2144, 2160, 2161, 2170, 2179, 2190, 2191, 2196, 2200,
+    2222, 2251, 2260, 2288, 2301, 2323, 2366, 2381, 2382, 2383, 2393, 2394, 2399, 2401, 2492, 2500, 2522,
+    2525, 2557, 2601, 2602, 2604, 2605, 2607, 2608, 2638, 2701, 2702, 2710, 2718, 2725, 2800, 2809, 2811,
+    2869, 2875, 2909, 2910, 2920, 2967, 2968, 2998, 3001, 3003, 3005, 3006, 3007, 3011, 3013, 3017, 3030,
+    3031, 3052, 3071, 3077, 3168, 3211, 3221, 3260, 3261, 3268, 3269, 3283, 3300, 3301, 3322, 3323, 3324,
+    3325, 3333, 3351, 3367, 3369, 3370, 3371, 3372, 3390, 3404, 3476, 3493, 3517, 3527, 3546, 3551, 3580,
+    3659, 3689, 3690, 3703, 3737, 3766, 3784, 3800, 3801, 3809, 3814, 3826, 3827, 3828, 3851, 3869, 3871,
+    3878, 3880, 3889, 3905, 3914, 3918, 3920, 3945, 3971, 3995, 3998, 4000, 4001, 4002, 4003, 4004, 4005,
+    4006, 4045, 4111, 4125, 4126, 4129, 4224, 4242, 4279, 4321, 4343, 4443, 4444, 4445, 4446, 4449, 4550,
+    4567, 4662, 4848, 4900, 4998, 5001, 5002, 5003, 5004, 5030, 5033, 5050, 5054, 5061, 5080, 5087, 5100,
+    5102, 5120, 5200, 5214, 5221, 5222, 5225, 5226, 5269, 5280, 5298, 5405, 5414, 5431, 5440, 5500, 5510,
+    5544, 5550, 5555, 5560, 5566, 5633, 5678, 5679, 5718, 5730, 5801, 5802, 5810, 5811, 5815, 5822, 5825,
+    5850, 5859, 5862, 5877, 5901, 5902, 5903, 5904, 5906, 5907, 5910, 5911, 5915, 5922, 5925, 5950, 5952,
+    5959, 5960, 5961, 5962, 5963, 5987, 5988, 5989, 5998, 5999, 6002, 6003, 6004, 6005, 6006, 6007, 6009,
+    6025, 6059, 6100, 6101, 6106, 6112, 6123, 6129, 6156, 6346, 6389, 6502, 6510, 6543, 6547, 6565, 6566,
+    6567, 6580, 6666, 6667, 6668, 6669, 6689, 6692, 6699, 6779, 6788, 6789, 6792, 6839, 6881, 6901, 6969,
+    7000, 7001, 7002, 7004, 7007, 7019, 7025, 7100, 7103, 7106, 7200, 7201, 7402, 7435, 7443, 7496, 7512,
+    7625, 7627, 7676, 7741, 7777, 7778, 7800, 7911, 7920, 7921, 7937, 7938, 7999, 8001, 8002, 8007, 8010,
+    8011, 8021, 8022, 8031, 8042, 8045, 8082, 8083, 8084, 8085, 8086, 8087, 8088, 8089, 8090, 8093, 8099,
+    8100, 8180, 8181, 8192, 8193, 8194, 8200, 8222, 8254, 8290, 8291, 8292, 8300, 8333, 8383, 8400, 8402,
+    8500, 8600, 8649, 8651, 8652, 8654, 8701, 8800, 8873, 8899, 8994, 9000, 9001, 9002, 9003, 9009, 9010,
+    9011, 9040, 9050, 9071, 9080, 9081, 9090, 9091, 9099, 9101, 9102, 9103, 9110, 9111, 9200, 9207, 9220,
+    9290, 9415, 9418, 9485, 9500, 9502, 9503, 9535, 9575, 9593, 9594, 9595, 9618, 9666, 9876, 9877, 9878,
+    9898, 9900, 9917, 9929, 9943, 9944, 9968, 9998, 10001, 10002, 10003, 10004, 10009, 10010, 10012, 10024,
+    10025, 10082, 10180, 10215, 10243, 10566, 10616, 10617, 10621, 10626, 10628, 10629, 10778, 11110, 11111,
+    11967, 12000, 12174, 12265, 12345, 13456, 13722, 13782, 13783, 14000, 14238, 14441, 14442, 15000, 15002,
+    15003, 15004, 15660, 15742, 16000, 16001, 16012, 16016, 16018, 16080, 16113, 16992, 16993, 17877, 17988,
+    18040, 18101, 18988, 19101, 19283, 19315, 19350, 19780, 19801, 19842, 20000, 20005, 20031, 20221, 20222,
+    20828, 21571, 22939, 23502, 24444, 24800, 25734, 25735, 26214, 27000, 27352, 27353, 27355, 27356, 27715,
+    28201, 30000, 30718, 30951, 31038, 31337, 32769, 32770, 32771, 32772, 32773, 32774, 32775, 32776, 32777,
+    32778, 32779, 32780, 32781, 32782, 32783, 32784, 32785, 33354, 33899, 34571, 34572, 34573, 35500, 38292,
+    40193, 40911, 41511, 42510, 44176, 44442, 44443, 44501, 45100, 48080, 49158, 49159, 49160, 49161, 49163,
+    49165, 49167, 49175, 49176, 49400, 49999, 50000, 50001, 50002, 50003, 50006, 50300, 50389, 50500, 50636,
+    50800, 51103, 51493, 52673, 52822, 52848, 52869, 54045, 54328, 55055, 55056, 55555, 55600, 56737, 56738,
+    57294, 57797, 58080, 60020, 60443, 61532, 61900, 62078, 63331, 64623, 64680, 65000, 65129, 65389,
+];
+
+// --ports, else the first --top-ports of TOP_PORTS (checked to be in range), else
+// the default set
+fn scan_ports(args: &Args) -> &[u16] {
+    match (&args.ports, args.top_ports) {
+        (Some(ports), _) => &ports.0,
+        (None, Some(count)) => &TOP_PORTS[..count],
+        (None, None) => DEFAULT_SCAN_PORTS,
+    }
+}
+
+// How many of TOP_PORTS come in frequency order
+const TOP_PORTS_RANKED: usize = 100;
+
+fn port_rank(port: u16) -> Option<usize> {
+    TOP_PORTS.iter().position(|&p| p == port)
+}
+
+// Scanned services that speak first or answer a trivial probe
+const DEFAULT_BANNER_PORTS: &str = "21,22,80";
+
+// Built-in role rules: per role, each telltale port with how strongly it points there.
+// A role's score is the sum over the open ports; ties go to the earlier role.
+// --role-rules replaces or adds rules
+const ROLE_RULES: &[(&str, &[(u16, f64)])] = &[
+    ("printer", &[(515, 3.0), (631, 3.0), (9100, 3.0)]),
+    ("router", &[(23, 1.5), (1900, 2.5), (7547, 3.0), (8291, 3.0)]),
+    ("iot", &[(554, 2.0), (1883, 2.5), (8883, 2.5), (8008, 2.0), (8009, 2.0)]),
+    (
+        "server",
+        &[
+            (21, 1.5),
+            (25, 2.0),
+            (53, 1.5),
+            // Every kind of device serves a web page
+            (80, 0.5),
+            (443, 0.5),
+            // A lone SMB share is a file server; beside 135/139 it is a Windows desktop
+            (445, 2.0),
+            (1433, 3.0),
+            (3306, 3.0),
+            (5432, 3.0),
+            (6379, 3.0),
+            (27017, 3.0),
+        ],
+    ),
+    ("workstation", &[(135, 1.0), (139, 1.0), (445, 1.0), (3389, 1.5), (5900, 1.5)]),
+];
+
+// Built-in --cve-hints mapping: a banner substring naming a version, and CVEs that
+// version is known for. Replaced entirely by --cve-map
+const CVE_HINTS: &[(&str, &[&str])] = &[
+    ("OpenSSH_7.2", &["CVE-2016-6210", "CVE-2016-6515"]),
+    ("OpenSSH_7.7", &["CVE-2018-15473"]),
+    ("OpenSSH_8.5", &["CVE-2024-6387"]),
+    ("OpenSSH_8.6", &["CVE-2024-6387"]),
+    ("OpenSSH_8.7", &["CVE-2024-6387"]),
+    ("OpenSSH_8.8", &["CVE-2024-6387"]),
+    ("OpenSSH_8.9", &["CVE-2024-6387"]),
+    ("OpenSSH_9.0", &["CVE-2024-6387"]),
+    ("OpenSSH_9.1", &["CVE-2024-6387"]),
+    ("OpenSSH_9.2", &["CVE-2024-6387"]),
+    ("OpenSSH_9.3", &["CVE-2024-6387"]),
+    ("OpenSSH_9.4", &["CVE-2024-6387"]),
+    ("OpenSSH_9.5", &["CVE-2024-6387"]),
+    ("OpenSSH_9.6", &["CVE-2024-6387"]),
+    ("OpenSSH_9.7", &["CVE-2024-6387"]),
+    ("vsFTPd 2.3.4", &["CVE-2011-2523"]),
+    ("ProFTPD 1.3.5", &["CVE-2015-3306"]),
+    ("Apache/2.4.49", &["CVE-2021-41773"]),
+    ("Apache/2.4.50", &["CVE-2021-42013"]),
+    ("Exim 4.87", &["CVE-2019-10149"]),
+    ("Exim 4.88", &["CVE-2019-10149"]),
+    ("Exim 4.89", &["CVE-2019-10149"]),
+    ("Exim 4.90", &["CVE-2019-10149"]),
+    ("Exim 4.91", &["CVE-2019-10149"]),
+];
+
+// Plain-HTTP ports tried for a WebSocket upgrade under --detect-websocket, and
+// fetched under --http-probe, when scanned
+const HTTP_PORTS: &[u16] = &[80, 8000, 8008, 8080];
+
+// Says who is knocking, so a site's operator can tell scans from real clients
+const DEFAULT_USER_AGENT: &str = concat!("network-mapper/", env!("CARGO_PKG_VERSION"));
+
+// Fixed key so the handshake is reproducible; we never speak WebSocket afterwards
+const WEBSOCKET_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+// A port this much slower than the host's fastest, both absolutely and relatively,
+// is flagged as a possible port forward
+const FORWARD_RTT_GAP_MS: f64 = 5.0;
+const FORWARD_RTT_RATIO: f64 = 3.0;
+
+// Ports probed for a host key under --ssh-host-keys, when scanned
+const SSH_PORTS: &[u16] = &[22, 2222];
+
+// Ports probed for RDP security negotiation under --rdp-nla, when scanned
+const RDP_PORTS: &[u16] = &[3389];
+
+// requestedProtocols / selectedProtocol flags of the RDP negotiation (MS-RDPBCGR 2.2.1.1.1)
+const RDP_PROTOCOL_RDP: u32 = 0x0;
+const RDP_PROTOCOL_SSL: u32 = 0x1;
+const RDP_PROTOCOL_HYBRID: u32 = 0x2;
+const RDP_PROTOCOL_RDSTLS: u32 = 0x4;
+const RDP_PROTOCOL_HYBRID_EX: u32 = 0x8;
+
+// Datagrams that get a reply from the usual service on a UDP port; other ports are
+// sent an empty datagram, which many services silently ignore
+const UDP_PROBES: &[(u16, &[u8])] = &[
+    // DNS: NS query for the root zone, recursion desired
+    (53, &[0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0x00, 0x00, 0x02, 0x00, 0x01]),
+    // NTP: version 3 client request, all timestamps zero
+    (123, &[
+        0x1b, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ]),
+    // SNMPv1 GetRequest for sysDescr.0 with community "public"
+    (161, &[
+        0x30, 0x26, 0x02, 0x01, 0x00, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', 0xa0, 0x19, 0x02, 0x01,
+        0x01, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x0e, 0x30, 0x0c, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x02,
+        0x01, 0x01, 0x01, 0x00, 0x05, 0x00,
+    ]),
+    // SSDP discovery
+    (1900, b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n"),
+];
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+// Answered with one PTR per service type advertised on the link
+const MDNS_SERVICE_ENUMERATION: &str = "_services._dns-sd._udp.local";
+// How long to collect answers; responders delay up to 500ms, browsing adds a round trip
+const MDNS_WINDOW: Duration = Duration::from_secs(3);
+// Caps the follow-up browse queries a noisy network can trigger
+const MAX_MDNS_SERVICE_TYPES: usize = 64;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+// Per host; a slow or dead nameserver costs each live host this much at most
+const REVERSE_DNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_TYPE_SRV: u16 = 33;
+
+// Ports that speak TLS from the first byte, probed when scanned
+const TLS_PORTS: &[u16] = &[443, 8443];
+
+// Same-port redirects --http-probe follows before settling for the redirect itself
+const MAX_HTTP_REDIRECTS: usize = 3;
+// Titles sit near the top of a page; stop reading a response here
+const MAX_HTTP_PAGE_BYTES: usize = 64 * 1024;
+const MAX_TITLE_CHARS: usize = 120;
+
+// What `subnet` assumes when neither --subnet-prefix nor --cidr says otherwise
+const DEFAULT_SUBNET_PREFIX: u8 = 24;
+const IPV6_SUBNET_PREFIX: u8 = 64;
+
+// Range scanned when no other target source is given
+const DEFAULT_START_IP: &str = "192.168.1.1";
+const DEFAULT_END_IP: &str = "192.168.1.254";
+
+// Probed before the full port list under --alive-ports-only
+const DEFAULT_ALIVE_PORTS: &[u16] = &[22, 80, 443, 445, 3389];
+
+// Services that routinely take longer than a connect to say anything; overridable
+// per port with --probe-timeout-multiplier
+const SLOW_PORT_MULTIPLIERS: &[(u16, f64)] = &[(443, 2.0), (1433, 2.0), (1521, 3.0), (3306, 2.0), (5432, 2.0)];
+
+// Rough per-item costs behind the pre-scan memory estimate: every target holds a
+// spawned task until collected, every live host a result, every in-flight host
+// its socket and read buffers
+const TASK_BYTES_ESTIMATE: u64 = 2 * 1024;
+const RESULT_BYTES_ESTIMATE: u64 = 512;
+const IN_FLIGHT_BYTES_ESTIMATE: u64 = 16 * 1024;
+const MEMORY_WARNING_BYTES: u64 = 1024 * 1024 * 1024;
+
+// Hosts scanned at once by scan_range, and by default (--concurrency) on the command line
+const MAX_CONCURRENT_SCANS: usize = 100;
+
+// How long to wait for a reader to open the sink FIFO before giving up on it
+const SINK_OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `network-mapper` command line: parses the process arguments, scans, and
+/// writes the reports they ask for.
+pub async fn run() -> Result<(), MapperError> {
+    let mut args = Args::parse();
+    let stderr_is_terminal = std::io::IsTerminal::is_terminal(&std::io::stderr());
+    let (no_color, term) = (std::env::var("NO_COLOR").ok(), std::env::var("TERM").ok());
+    COLOR.store(
+        args.color.enabled(stderr_is_terminal, no_color.as_deref(), term.as_deref()),
+        Ordering::Relaxed,
+    );
+    // Logs go to stderr with the rest of the diagnostics, so stdout stays JSON. An
+    // embedding program may have installed its own subscriber already
+    if args.verbose > 0 {
+        let level = match args.verbose {
+            1 => tracing::Level::INFO,
+            2 => tracing::Level::DEBUG,
+            _ => tracing::Level::TRACE,
+        };
+        let _ = tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_max_level(level)
+            .with_target(false)
+            .with_ansi(COLOR.load(Ordering::Relaxed))
+            .try_init();
+    }
+    match &args.command {
+        Some(Command::Doctor) => {
+            // Non-zero so scripts can gate a scan on the checks
+            if run_doctor(&args).await > 0 {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Presets) => {
+            print_presets();
+            return Ok(());
+        }
+        Some(Command::Compare { old, new, json }) => {
+            let (_, old_results) = load_saved_results("compare", old, None)?;
+            let (_, new_results) = load_saved_results("compare", new, None)?;
+            let diff = ScanDiff::between(&old_results, &new_results);
+            if *json {
+                let text = render_json(&diff, JsonStyle::Pretty, args.json_case)?.unwrap_or_default();
+                println!("{}", text);
+            } else {
+                print_scan_diff(old, new, &diff);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    // Each run is one cycle; a scheduler calling us outside the window gets a clean no-op.
+    // Rendering saved results scans nothing, so it is never out of hours
+    if let (Some(window), None) = (args.business_hours, &args.from_file) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        let minute = args.business_hours_tz.minute_of_day(now);
+        if !window.contains(minute) {
+            eprintln!(
+                "business-hours: skipping scan, {:02}:{:02} is outside {:02}:{:02}-{:02}:{:02}",
+                minute / 60,
+                minute % 60,
+                window.start / 60,
+                window.start % 60,
+                window.end / 60,
+                window.end % 60,
+            );
+            return Ok(());
+        }
+    }
+
+    let timeout_duration = Duration::from_millis(args.timeout_ms.get());
+    if matches!(args.top_ports, Some(count) if count == 0 || count > TOP_PORTS.len()) {
+        return Err(MapperError::Parse(format!("--top-ports must be between 1 and {}", TOP_PORTS.len())));
+    }
+    if args.max_results == Some(0) {
+        return Err(MapperError::Parse("--max-results must be at least 1".to_string()));
+    }
+    if args.concurrency == 0 {
+        return Err(MapperError::Parse("--concurrency must be at least 1".to_string()));
+    }
+    let max_concurrent_scans = args.concurrency;
+
+    if matches!(args.subnet_prefix, Some(prefix) if prefix > 32) {
+        return Err(MapperError::Parse("--subnet-prefix must be between 0 and 32".to_string()));
+    }
+
+    if matches!(args.collapse_subnets, Some(prefix) if prefix > 24) {
+        return Err(MapperError::Parse("--collapse-subnets must be a prefix of /24 or shorter".to_string()));
+    }
+
+    // Loaded before scanning so a bad path fails fast
+    if args.format.contains(&OutputFormat::DiffHtml) && args.baseline.is_none() {
+        return Err(MapperError::Parse("--format diff-html needs --baseline".to_string()));
+    }
+    let baseline = match &args.baseline {
+        Some(path) => Some(Arc::new(load_saved_results("--baseline", path, None)?.1)),
+        None => None,
+    };
+
+    // No scan at all: the targets are ignored and the saved hosts go straight to the reports
+    if let Some(path) = &args.from_file {
+        let (mut metadata, hosts) = load_saved_results("--from-file", path, args.scan_id.clone())?;
+        let mut sample = Reservoir::new(args.max_results);
+        sample.extend(hosts);
+        let (results, sampled_from) = sample.into_hosts();
+        metadata.sampled_from = sampled_from.or(metadata.sampled_from);
+        let live_results: Arc<LiveResults> = Arc::new(std::sync::RwLock::new(Vec::new()));
+        let serve_task = start_server(&args, &live_results).await?;
+        let serve = serve_task.map(|task| (task, live_results));
+        return finish_run(&args, metadata, results, baseline, serve).await;
+    }
+
+    if args.user_agent.contains(['\r', '\n']) {
+        return Err(MapperError::Parse("--user-agent must be a single line".to_string()));
+    }
+    if let Some(sni) = &args.sni {
+        validate_server_name(sni).map_err(|e| MapperError::Parse(format!("--sni {:?}: {}", sni, e)))?;
+    }
+
+    // Better to stop here than to have every host's ping fail the same way
+    if args.ping_first {
+        icmp_echo_socket().map_err(|e| {
+            MapperError::Parse(format!(
+                "--ping-first: cannot open an ICMP socket ({}); run as root, grant CAP_NET_RAW, \
+                 or add this user's group to net.ipv4.ping_group_range",
+                e
+            ))
+        })?;
+    }
+
+    if args.rate.is_some_and(|rate| !(rate.is_finite() && rate > 0.0)) {
+        return Err(MapperError::Parse("--rate must be a positive number of connects per second".to_string()));
+    }
+    if args.throttle_on_errors && args.throttle_threshold == 0 {
+        return Err(MapperError::Parse("--throttle-threshold must be at least 1".to_string()));
+    }
+
+    let schedule = match args.schedule_window {
+        Some(window) => {
+            let interval = args.schedule_interval.unwrap_or(DEFAULT_SCHEDULE_INTERVAL);
+            if interval == 0 || window < interval {
+                return Err(MapperError::Parse(
+                    "--schedule-interval must be at least 1 and no longer than --schedule-window".to_string(),
+                ));
+            }
+            Some(((window / interval) as usize, interval))
+        }
+        None => None,
+    };
+
+    if args.rotate && args.keep == 0 {
+        return Err(MapperError::Parse("--keep must be at least 1".to_string()));
+    }
+
+    // Addresses, CIDRs and ranges in --target need no lookup, only the --target-family
+    // filter. Every name is resolved up front, all at once, so a typo is reported
+    // before the scan starts rather than noticed in the results
+    let mut listed = Vec::new();
+    let mut names = Vec::new();
+    for entry in &args.target {
+        match parse_target_spec(entry, args.max_hosts).map_err(|e| MapperError::Parse(format!("--target {}", e)))? {
+            TargetSpec::Addresses(ips) => {
+                let count = ips.len();
+                let kept: Vec<IpAddr> = ips.into_iter().filter(|ip| args.target_family.includes(*ip)).collect();
+                if kept.len() < count {
+                    warning!(
+                        "--target {}: skipping {} of {} addresses, not {} (--target-family)",
+                        entry,
+                        count - kept.len(),
+                        count,
+                        args.target_family.name()
+                    );
+                }
+                listed.extend(kept);
+            }
+            TargetSpec::Name(name) => names.push(name),
+        }
+    }
+    let mut resolution = resolve_targets(&names, TARGET_RESOLVE_TIMEOUT).await;
+    resolution.retain_family(args.target_family);
+    if !names.is_empty() {
+        for (name, error) in &resolution.failed {
+            warning!("--target {}: {}", name, error);
+        }
+        let addresses: std::collections::BTreeSet<IpAddr> = resolution.resolved.values().flatten().copied().collect();
+        eprintln!(
+            "resolve: {} of {} targets resolved to {} addresses",
+            resolution.resolved.len(),
+            resolution.resolved.len() + resolution.failed.len(),
+            addresses.len()
+        );
+        if addresses.is_empty()
+            && listed.is_empty()
+            && args.ips.is_empty()
+            && args.cidr.is_none()
+            && args.start_ip.is_none()
+        {
+            return Err(MapperError::Parse("no --target resolved to an address".to_string()));
+        }
+        args.ips.extend(addresses);
+    } else if !args.target.is_empty()
+        && listed.is_empty()
+        && args.ips.is_empty()
+        && args.cidr.is_none()
+        && args.start_ip.is_none()
+    {
+        return Err(MapperError::Parse(format!(
+            "no --target address is {} (--target-family)",
+            args.target_family.name()
+        )));
+    }
+    args.ips.extend(listed);
+
+    // Masscan's hosts join the targets like --ips, but are probed only on the ports it found
+    let imported_ports = match &args.import_masscan {
+        Some(path) => {
+            let imported = load_masscan(path)?;
+            args.ips.extend(imported.keys().copied());
+            Some(Arc::new(imported))
+        }
+        None => None,
+    };
+
+    let allowlist = args.allowlist.as_deref().map(load_allowlist).transpose()?;
+    // Under --huge-scan the range is never held in memory: it is walked once here to
+    // count the targets and check the allowlist, and again as they are scanned
+    let (targets, target_count): (Box<dyn Iterator<Item = IpAddr> + Send>, usize) = if args.huge_scan {
+        let mut count = 0;
+        let counted = huge_scan_targets(&args)?.inspect(|_| count += 1);
+        match &allowlist {
+            Some(allowlist) => enforce_allowlist(allowlist, counted)?,
+            None => counted.for_each(drop),
+        }
+        (Box::new(huge_scan_targets(&args)?), count)
+    } else {
+        let mut targets = expand_targets(&args)?;
+        if !args.only_subnets.is_empty() {
+            targets.retain(|ip| in_subnets(*ip, &args.only_subnets));
+        }
+        if args.only_private || args.only_public {
+            let before = targets.len();
+            targets.retain(|ip| if args.only_private { is_internal(*ip) } else { is_globally_routable(*ip) });
+            if targets.len() < before {
+                let scope = if args.only_private { "--only-private" } else { "--only-public" };
+                eprintln!("{}: skipping {} of {} targets", scope, before - targets.len(), before);
+            }
+        }
+        if let Some(allowlist) = &allowlist {
+            enforce_allowlist(allowlist, targets.iter().copied())?;
+        }
+        warn_if_memory_heavy(targets.len(), max_concurrent_scans);
+        let count = targets.len();
+        (Box::new(targets.into_iter()), count)
+    };
+    if let Some(max_hosts) = args.max_hosts {
+        if target_count as u64 > max_hosts {
+            return Err(MapperError::Parse(format!(
+                "{} targets is more than --max-hosts {}",
+                target_count, max_hosts
+            )));
+        }
+    }
+    // --connect-limit already keeps the socket count under control
+    let open_files = open_files_limit().filter(|_| args.connect_limit.is_none());
+    warn_if_concurrency_pointless(max_concurrent_scans, target_count, open_files);
+
+    // Global ceiling on simultaneously open sockets, independent of host concurrency
+    let connect_limit = match args.connect_limit {
+        Some(0) => return Err(MapperError::Parse("--connect-limit must be at least 1".to_string())),
+        Some(limit) => Some(Arc::new(Semaphore::new(limit))),
+        None => None,
+    };
+
+    let mut metadata = ReportMetadata {
+        scan_id: args.scan_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string()),
+        started_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        targets: target_count,
+        client_ja3: args.ja3s.then(|| args.client_hello.ja3(args.sni.as_deref())),
+        resolved_targets: resolution
+            .resolved
+            .iter()
+            .map(|(name, addresses)| (name.clone(), addresses.iter().map(IpAddr::to_string).collect()))
+            .collect(),
+        unresolved_targets: resolution.failed.into_iter().collect(),
+        open_port_histogram: Vec::new(),
+        sampled_from: None,
+        stopped: false,
+        captive_portal: None,
+    };
+    let scan_id: Arc<str> = Arc::from(metadata.scan_id.as_str());
+
+    // LAN-only, so it runs alongside the scan and is merged into the results after
+    let mdns_task = args.mdns.then(|| tokio::spawn(discover_mdns(MDNS_WINDOW)));
+
+    let icmp_running = Arc::new(AtomicBool::new(true));
+    let icmp_errors = if args.icmp_errors {
+        let table = Arc::new(IcmpErrorTable::default());
+        match start_icmp_listener(Arc::clone(&table), Arc::clone(&icmp_running)) {
+            Ok(_) => Some(table),
+            Err(e) => {
+                warning!("cannot capture ICMP errors (raw sockets need root or CAP_NET_RAW): {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let cve_hints = match (&args.cve_map, args.cve_hints) {
+        (Some(path), _) => load_cve_map(path)?,
+        (None, true) => builtin_cve_hints(),
+        (None, false) => Vec::new(),
+    };
+
+    let resolver = if args.resolve {
+        let resolver = system_resolver();
+        if resolver.is_none() {
+            warning!("--resolve: no nameserver in {}, hostnames skipped", RESOLV_CONF);
+        }
+        resolver
+    } else {
+        None
+    };
+
+    let os_overrides = match &args.os_override {
+        Some(path) => load_os_overrides(path)?,
+        None => Vec::new(),
+    };
+    let role_rules = match &args.role_rules {
+        Some(path) => load_role_rules(path)?,
+        None => builtin_role_rules(),
+    };
+
+    let mut read_timeout_multipliers: HashMap<u16, f64> = SLOW_PORT_MULTIPLIERS.iter().copied().collect();
+    for multiplier in &args.probe_timeout_multiplier {
+        read_timeout_multipliers.insert(multiplier.port, multiplier.factor);
+    }
+
+    let host_timeouts = baseline.as_ref().filter(|_| args.rtt_timeouts).map(|hosts| {
+        let timeouts: HashMap<IpAddr, Duration> = hosts
+            .iter()
+            .filter_map(|host| Some((host.ip.parse().ok()?, rtt_timeout(host, timeout_duration)?)))
+            .collect();
+        eprintln!(
+            "rtt-timeouts: {} of {} baseline hosts get a connect timeout from their round trips",
+            timeouts.len(),
+            hosts.len()
+        );
+        Arc::new(timeouts)
+    });
+
+    let (subnet_prefix, ipv6_subnet_prefix) = subnet_prefixes(&args)?;
+    let options = Arc::new(ScanOptions {
+        timeout: timeout_duration,
+        read_timeout: timeout_duration,
+        connect_limit,
+        ports: args
+            .probe_order
+            .arrange(scan_ports(&args)),
+        udp_ports: args.udp_ports.as_ref().map(|ports| ports.0.clone()).unwrap_or_default(),
+        subnet_prefix,
+        ipv6_subnet_prefix,
+        verify_services: args.verify_services,
+        banner_ports: args.banner_ports.0.clone(),
+        banner_all: args.banner,
+        response_stats: args.response_stats,
+        cve_hints,
+        resolver,
+        detect_websocket: args.detect_websocket,
+        http_probe: args.http_probe,
+        user_agent: args.user_agent.clone(),
+        ssh_host_keys: args.ssh_host_keys,
+        rdp_nla: args.rdp_nla,
+        client_hello: (args.ja3s || args.tls_certs).then(|| args.client_hello.client_hello(args.sni.as_deref())),
+        ja3s: args.ja3s,
+        tls_certs: args.tls_certs,
+        retries: args.retries,
+        retry_delay: Duration::from_millis(args.retry_delay_ms),
+        rst_as_open: args.rst_as_open,
+        alive_ports: args.alive_ports_only.then(|| match &args.alive_ports {
+            Some(PortList(ports)) => ports.clone(),
+            None => DEFAULT_ALIVE_PORTS.to_vec(),
+        }),
+        ping_first: args.ping_first,
+        latency_tiers: args.latency_tiers,
+        liveness_port: args.liveness_only,
+        os_overrides,
+        role_rules,
+        imported_ports,
+        icmp_errors,
+        read_timeout_multipliers,
+        throttle: args
+            .throttle_on_errors
+            .then(|| Throttle::new(args.throttle_threshold, Duration::from_secs(args.throttle_cooldown))),
+        rate_limit: args.rate.map(RateLimit::new),
+        record_probed_ports: args.format.contains(&OutputFormat::PortMatrix),
+        baseline: baseline.as_ref().filter(|_| args.probe_changed_only).map(|hosts| {
+            Arc::new(hosts.iter().filter_map(|host| Some((host.ip.parse().ok()?, host.clone()))).collect())
+        }),
+        host_timeouts,
+    });
+
+    // Under --sink-deltas-only, updated as hosts are probed and saved for the next run
+    let sink_state_path = args
+        .sink_deltas_only
+        .then(|| args.sink_state.clone().or_else(|| args.sink.as_ref().map(|sink| format!("{}.state.json", sink))))
+        .flatten();
+    let sink_state = match &sink_state_path {
+        Some(path) => Some(Arc::new(std::sync::Mutex::new(load_sink_state(path)?))),
+        None => None,
+    };
+
+    // Stream NDJSON results to the sink as each host completes
+    let (sink_tx, sink_task) = match &args.sink {
+        Some(path) => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Some(tx), Some(tokio::spawn(run_sink(path.clone(), rx))))
+        }
+        None => (None, None),
+    };
+
+    // Under --ramp-up the host limit starts at 1 and grows to the full limit
+    let ramp_up = args.ramp_up.filter(|secs| *secs > 0).map(Duration::from_secs);
+    let semaphore = Arc::new(Semaphore::new(if ramp_up.is_some() { 1 } else { max_concurrent_scans }));
+    let ramp_task = ramp_up.map(|period| {
+        tokio::spawn(ramp_up_permits(Arc::clone(&semaphore), max_concurrent_scans, period))
+    });
+    let progress = Arc::new(ScanProgress {
+        total: AtomicUsize::new(target_count),
+        probed: AtomicUsize::new(0),
+        live: AtomicUsize::new(0),
+        stopped: AtomicBool::new(false),
+        paused: watch::channel(false).0,
+    });
+
+    // Hosts appear here as they complete; the final results replace them at the end
+    let live_results: Arc<LiveResults> = Arc::new(std::sync::RwLock::new(Vec::new()));
+    let serve_task = start_server(&args, &live_results).await?;
+
+    let interrupt_task = tokio::spawn(stop_on_interrupt(Arc::clone(&progress)));
+
+    let control_task = match &args.control_socket {
+        Some(path) => Some(tokio::spawn(run_control_socket(bind_control_socket(path)?, Arc::clone(&progress)))),
+        None => None,
+    };
+
+    // A terminal gets one line rewritten in place; a log gets a line per second
+    let progress_task = match args.progress {
+        ProgressMode::Always => true,
+        ProgressMode::Auto => stderr_is_terminal,
+        ProgressMode::Never => false,
+    }
+    .then(|| tokio::spawn(report_progress(Arc::clone(&progress), stderr_is_terminal)));
+
+    let scan_target = |ip: IpAddr| {
+        let semaphore = Arc::clone(&semaphore);
+        let options = Arc::clone(&options);
+        let progress = Arc::clone(&progress);
+        let sink_tx = sink_tx.clone();
+        let sink_state = sink_state.clone();
+        let live_results = args.serve.is_some().then(|| Arc::clone(&live_results));
+        let scan_id = Arc::clone(&scan_id);
+        let json_case = args.json_case;
+
+        async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            let _ = progress.paused.subscribe().wait_for(|paused| !paused).await;
+            if progress.stopped.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let result = scan_host(ip, &options).await;
+            progress.probed.fetch_add(1, Ordering::Relaxed);
+            if result.is_some() {
+                progress.live.fetch_add(1, Ordering::Relaxed);
+            }
+            let line = match (&sink_state, &result) {
+                (Some(state), _) => {
+                    let ip = ip.to_string();
+                    let current = result.as_ref().map(|result| result.open_ports.clone());
+                    let mut state = state.lock().unwrap();
+                    let delta = SinkDelta::between(&scan_id, &ip, state.get(&ip).map(Vec::as_slice), current.as_deref())
+                        .and_then(|delta| to_json_string(&delta, json_case).ok());
+                    match current {
+                        Some(ports) => state.insert(ip, ports),
+                        None => state.remove(&ip),
+                    };
+                    delta
+                }
+                (None, Some(result)) => to_json_string(&SinkRecord { scan_id: &scan_id, host: result }, json_case).ok(),
+                (None, None) => None,
+            };
+            if let (Some(sink_tx), Some(line)) = (&sink_tx, line) {
+                let _ = sink_tx.send(line);
+            }
+            if let (Some(live), Some(result)) = (&live_results, &result) {
+                live.write().unwrap().push(result.clone());
+            }
+            result
+        }
+    };
+
+    // Every live host is streamed and served, but only the sample is kept for the reports
+    let mut sample = Reservoir::new(args.max_results);
+    if let Some((buckets, interval)) = schedule {
+        let targets: Vec<IpAddr> = targets.collect();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut checkpoint = match &args.checkpoint {
+            Some(path) => ScheduleCheckpoint::load(path, targets.len(), buckets)?,
+            None => None,
+        }
+        .unwrap_or_else(|| ScheduleCheckpoint::new(now, targets.len(), buckets));
+        if checkpoint.next_bucket > 0 {
+            eprintln!("schedule: resuming at bucket {} of {}", checkpoint.next_bucket + 1, buckets);
+        }
+        sample.extend(std::mem::take(&mut checkpoint.hosts));
+
+        for bucket in checkpoint.next_bucket..buckets {
+            let due = checkpoint.started_at + bucket as u64 * interval;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            if due > now {
+                tokio::time::sleep(Duration::from_secs(due - now)).await;
+            }
+            let batch = schedule_bucket(&targets, bucket, buckets);
+            eprintln!("schedule: bucket {} of {}, {} targets", bucket + 1, buckets, batch.len());
+            let tasks: Vec<_> = batch.into_iter().map(|ip| tokio::spawn(scan_target(ip))).collect();
+            for task in tasks {
+                sample.extend(task.await?);
+            }
+            // A stopped bucket is left to be scanned again on resume
+            if progress.stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Some(path) = &args.checkpoint {
+                checkpoint.next_bucket = bucket + 1;
+                checkpoint.save(path, &sample.hosts)?;
+            }
+        }
+    } else if args.huge_scan {
+        // Only a bounded number of tasks exist at once, and they finish in any order
+        let max_queued = max_concurrent_scans * HUGE_SCAN_QUEUE_FACTOR;
+        let mut running = tokio::task::JoinSet::new();
+        for ip in targets {
+            // Queued hosts would only return at once; nothing more is spawned
+            if progress.stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            if running.len() >= max_queued {
+                if let Some(done) = running.join_next().await {
+                    sample.extend(done?);
+                }
+            }
+            running.spawn(scan_target(ip));
+        }
+        while let Some(done) = running.join_next().await {
+            sample.extend(done?);
+        }
+    } else if args.stdin {
+        // Targets from the command line go first, then every line as it arrives; each
+        // live host is printed the moment it is done, as one NDJSON line
+        let (tx, mut incoming) = mpsc::unbounded_channel();
+        for ip in targets {
+            let _ = tx.send(ip);
+        }
+        tokio::spawn(read_stdin_targets(tx, args.max_hosts, args.target_family));
+        let stream_stdout =
+            !args.format.iter().any(|format| format.writes_stdout()) && args.stdout_json != JsonStyle::Off;
+        let mut emit = |done: Option<ScanResult>| -> Result<(), MapperError> {
+            if let Some(result) = done {
+                if stream_stdout {
+                    println!("{}", to_json_string(&result, args.json_case)?);
+                }
+                sample.push(result);
+            }
+            Ok(())
+        };
+
+        let max_queued = max_concurrent_scans * HUGE_SCAN_QUEUE_FACTOR;
+        let mut seen = std::collections::HashSet::new();
+        let mut running = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                ip = incoming.recv(), if running.len() < max_queued => {
+                    // Closed once stdin hits EOF and every host name has been looked up
+                    let Some(ip) = ip else { break };
+                    if progress.stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let wanted = (args.only_subnets.is_empty() || in_subnets(ip, &args.only_subnets))
+                        && (!args.only_private || is_internal(ip))
+                        && (!args.only_public || is_globally_routable(ip));
+                    if !wanted || !seen.insert(ip) {
+                        continue;
+                    }
+                    if allowlist.as_ref().is_some_and(|allowlist| !in_subnets(ip, allowlist)) {
+                        eprintln!("allowlist violation: {} is not in any permitted range, skipped", ip);
+                        continue;
+                    }
+                    if args.max_hosts.is_some_and(|max_hosts| seen.len() as u64 > max_hosts) {
+                        warning!("--max-hosts reached, ignoring further --stdin targets");
+                        break;
+                    }
+                    progress.total.fetch_add(1, Ordering::Relaxed);
+                    running.spawn(scan_target(ip));
+                }
+                Some(done) = running.join_next(), if !running.is_empty() => emit(done?)?,
+            }
+        }
+        while let Some(done) = running.join_next().await {
+            emit(done?)?;
+        }
+        metadata.targets = progress.total.load(Ordering::Relaxed);
+    } else {
+        let tasks: Vec<_> = targets.map(|ip| tokio::spawn(scan_target(ip))).collect();
+        for task in tasks {
+            sample.extend(task.await?);
+        }
+    }
+    let (mut results, sampled_from) = sample.into_hosts();
+    metadata.sampled_from = sampled_from;
+    metadata.stopped = progress.stopped.load(Ordering::Relaxed);
+    correlate_identities(&mut results);
+    correlate_dual_stack(&mut results);
+    flag_response_outliers(&mut results);
+    if let Some(mdns_task) = mdns_task {
+        match mdns_task.await? {
+            Ok(mut advertised) => {
+                for result in &mut results {
+                    if let Some(services) = result.ip.parse().ok().and_then(|ip| advertised.remove(&ip)) {
+                        result.mdns_services = services;
+                    }
+                }
+            }
+            Err(e) => warning!("mDNS discovery failed: {}", e),
+        }
+    }
+
+    if let Some(ramp_task) = ramp_task {
+        ramp_task.abort();
+    }
+    if let Some(progress_task) = progress_task {
+        progress_task.abort();
+        // Clear the progress line so the summary starts on a clean one
+        if stderr_is_terminal {
+            eprint!("\r\x1b[K");
+        }
+    }
+    icmp_running.store(false, Ordering::Relaxed);
+    drop(sink_tx);
+    if let Some(sink_task) = sink_task {
+        sink_task.await?;
+    }
+    // Hosts never probed (e.g. after a stop) keep their previous entry
+    if let (Some(path), Some(state)) = (&sink_state_path, &sink_state) {
+        let state = serde_json::to_string_pretty(&*state.lock().unwrap())?;
+        std::fs::write(path, state).map_err(|e| MapperError::Output(format!("--sink-state {}: {}", path, e)))?;
+    }
+
+    // --serve waits for its own Ctrl-C; anything else can still be interrupted while
+    // the reports are written
+    if args.serve.is_some() {
+        interrupt_task.abort();
+    }
+    if let Some(control_task) = control_task {
+        control_task.abort();
+        #[cfg(unix)]
+        if let Some(path) = &args.control_socket {
+            let _ = remove_stale_socket(path);
+        }
+    }
+
+    finish_run(&args, metadata, results, baseline, serve_task.map(|task| (task, live_results))).await
+}
+
+// Everything after the scan, shared with --from-file: stdout, summary, --save, the
+// reports, hooks and, under --serve, serving until Ctrl-C
+async fn finish_run(
+    args: &Args,
+    mut metadata: ReportMetadata,
+    results: Vec<ScanResult>,
+    baseline: Option<Arc<Vec<ScanResult>>>,
+    serve: Option<(tokio::task::JoinHandle<()>, Arc<LiveResults>)>,
+) -> Result<(), MapperError> {
+    metadata.open_port_histogram = open_port_histogram(&results);
+    metadata.captive_portal = detect_captive_portal(&results);
+
+    // A terminal format owns stdout; otherwise stdout gets JSON unless switched off.
+    // --stdin has already streamed every host there
+    if !args.stdin && !args.format.iter().any(|format| format.writes_stdout()) {
+        // A bare host array, as always; the metadata wrapper is opt-in here
+        let json = if args.stdout_metadata {
+            let report = Report::new(&metadata, &results).deduplicated_if(args.deduplicate_banners);
+            render_json(&report, args.stdout_json, args.json_case)?
+        } else {
+            render_json(&results, args.stdout_json, args.json_case)?
+        };
+        if let Some(json) = json {
+            println!("{}", json);
+        }
+    }
+
+    print_summary(&metadata, &results);
+    if let Some((_, live_results)) = &serve {
+        *live_results.write().unwrap() = results.clone();
+    }
+
+    if let Some(path) = &args.save {
+        let json = serde_json::to_string_pretty(&results)?;
+        std::fs::write(path, json + "\n").map_err(|e| MapperError::Output(format!("--save {}: {}", path, e)))?;
+    }
+
+    let mut jobs: Vec<ReportJob> =
+        args.format.iter().map(|format| format.report_job(args, &metadata, baseline.as_ref())).collect();
+    if let Some(path) = &args.json_output {
+        if args.json_output_style != JsonStyle::Off {
+            let style = args.json_output_style;
+            let case = args.json_case;
+            let metadata = metadata.clone();
+            let deduplicate = args.deduplicate_banners;
+            jobs.push(ReportJob {
+                format: "json",
+                path: path.clone(),
+                write: Box::new(move |results, path| {
+                    write_json(&Report::new(&metadata, results).deduplicated_if(deduplicate), path, style, case)
+                }),
+            });
+        }
+    }
+    // Under --rotate each run writes timestamped files next to the configured ones
+    let rotation_stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let rotated: Vec<String> = if args.rotate {
+        let mut rotated = Vec::new();
+        for job in jobs.iter_mut().filter(|job| job.path != "-") {
+            rotated.push(job.path.clone());
+            job.path = rotated_path(&job.path, rotation_stamp)?;
+        }
+        rotated
+    } else {
+        Vec::new()
+    };
+
+    let report_paths: Vec<String> = jobs.iter().map(|job| job.path.clone()).filter(|path| path != "-").collect();
+    let results = Arc::new(results);
+    write_reports(Arc::clone(&results), jobs).await?;
+
+    for path in &rotated {
+        if let Err(e) = prune_rotated(path, args.keep) {
+            warning!("could not prune old copies of {}: {}", path, e);
+        }
+    }
+
+    // Every writer has finished by now; make sure nothing is left in stdout's buffer
+    std::io::stdout().flush()?;
+
+    if let Some(command) = &args.on_complete {
+        let hosts = to_json_string(&*results, args.json_case)?;
+        match run_on_complete(command, &report_paths, hosts).await {
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                for line in stdout.lines().chain(stderr.lines()) {
+                    eprintln!("on-complete: {}", line);
+                }
+                eprintln!("on-complete: {}", output.status);
+            }
+            Err(e) => warning!("--on-complete could not run: {}", e),
+        }
+    }
+
+    if let Some((serve_task, _)) = serve {
+        eprintln!("scan finished, still serving; press Ctrl-C to exit");
+        let _ = tokio::signal::ctrl_c().await;
+        serve_task.abort();
+    }
+    Ok(())
+}
+
+// A port is only judged against this many other hosts' responses on it, or more
+const MIN_OUTLIER_PEERS: usize = 3;
+// An outlier is this many times the port's median, and further from it than the floor
+const OUTLIER_FACTOR: f64 = 4.0;
+const MIN_OUTLIER_BYTES: f64 = 1024.0;
+const MIN_OUTLIER_FIRST_BYTE_MS: f64 = 250.0;
+
+// Compares each port's response with the same port on every other host, so a 40 kB
+// HTTP page stands out among 2 kB ones while a chatty protocol is not flagged everywhere
+fn flag_response_outliers(results: &mut [ScanResult]) {
+    let mut by_port: HashMap<u16, (Vec<f64>, Vec<f64>)> = HashMap::new();
+    for stats in results.iter().flat_map(|result| &result.responses) {
+        let (sizes, first_bytes) = by_port.entry(*stats.0).or_default();
+        sizes.push(stats.1.bytes as f64);
+        first_bytes.extend(stats.1.first_byte_ms);
+    }
+    let median = |values: &[f64]| -> Option<f64> {
+        if values.len() <= MIN_OUTLIER_PEERS {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Some(sorted[sorted.len() / 2])
+    };
+    let medians: HashMap<u16, (Option<f64>, Option<f64>)> =
+        by_port.iter().map(|(port, (sizes, first_bytes))| (*port, (median(sizes), median(first_bytes)))).collect();
+    let stands_out = |value: f64, median: f64, floor: f64| value >= median * OUTLIER_FACTOR && value - median >= floor;
+
+    for result in results.iter_mut() {
+        let mut ports: Vec<_> = result.responses.iter().collect();
+        ports.sort_by_key(|(port, _)| **port);
+        let mut outliers = Vec::new();
+        for (port, stats) in ports {
+            let (size_median, first_byte_median) = medians[port];
+            if let Some(median) = size_median.filter(|&median| stands_out(stats.bytes as f64, median, MIN_OUTLIER_BYTES)) {
+                outliers.push(format!("port {}: {} bytes, median {}", port, stats.bytes, median));
+            }
+            if let (Some(first_byte), Some(median)) = (stats.first_byte_ms, first_byte_median) {
+                if stands_out(first_byte, median, MIN_OUTLIER_FIRST_BYTE_MS) {
+                    outliers.push(format!("port {}: first byte after {:.0} ms, median {:.0} ms", port, first_byte, median));
+                }
+            }
+        }
+        result.response_outliers = outliers;
+    }
+}
+
+// Links hosts that presented the same SSH host key or TLS certificate
+fn correlate_identities(results: &mut [ScanResult]) {
+    let mut by_identity: HashMap<String, Vec<String>> = HashMap::new();
+    for result in results.iter() {
+        if let Some(identity) = &result.identity_fingerprint {
+            by_identity.entry(identity.clone()).or_default().push(result.ip.clone());
+        }
+    }
+    for result in results.iter_mut() {
+        if let Some(ips) = result.identity_fingerprint.as_ref().and_then(|identity| by_identity.get(identity)) {
+            result.same_device_as = ips.iter().filter(|ip| **ip != result.ip).cloned().collect();
+        }
+    }
+}
+
+// An IPv4 and an IPv6 address are one dual-stack device when they share an identity
+// (SSH host key or TLS certificate) or a PTR hostname. Names only count across
+// families: two IPv4 addresses with one name are as often a load balancer's pool
+fn correlate_dual_stack(results: &mut [ScanResult]) {
+    let mut by_key: HashMap<String, (Vec<String>, Vec<String>)> = HashMap::new();
+    for result in results.iter() {
+        let is_v6 = result.ip.contains(':');
+        let name = result.hostname.as_ref().map(|name| format!("name:{}", name.to_ascii_lowercase()));
+        for key in result.identity_fingerprint.iter().cloned().chain(name) {
+            let (v4, v6) = by_key.entry(key).or_default();
+            if is_v6 { v6.push(result.ip.clone()) } else { v4.push(result.ip.clone()) }
+        }
+    }
+    for result in results.iter_mut() {
+        let is_v6 = result.ip.contains(':');
+        let name = result.hostname.as_ref().map(|name| format!("name:{}", name.to_ascii_lowercase()));
+        let mut peers: Vec<String> = result
+            .identity_fingerprint
+            .iter()
+            .cloned()
+            .chain(name)
+            .filter_map(|key| by_key.get(&key))
+            .flat_map(|(v4, v6)| if is_v6 { v4 } else { v6 })
+            .cloned()
+            .collect();
+        peers.sort();
+        peers.dedup();
+        result.dual_stack_addresses = peers;
+    }
+}
+
+// --merge-dual-stack: the graph draws each dual-stack device once, as its IPv4 node
+fn merge_dual_stack(results: &[ScanResult]) -> Vec<ScanResult> {
+    results
+        .iter()
+        .filter(|result| !result.ip.contains(':') || result.dual_stack_addresses.is_empty())
+        .cloned()
+        .collect()
+}
+
+// Ports named in the summary's histogram line; the report metadata has them all
+const SUMMARY_TOP_PORTS: usize = 10;
+
+// Human-oriented recap on stderr so stdout stays machine-readable
+fn print_summary(metadata: &ReportMetadata, results: &[ScanResult]) {
+    eprintln!(
+        "{}",
+        paint(
+            format_args!(
+                "scan {}: {} of {} targets alive",
+                metadata.scan_id,
+                metadata.sampled_from.unwrap_or(results.len()),
+                metadata.targets
+            ),
+            Style::Bold
+        )
+    );
+    if metadata.stopped {
+        eprintln!("  {}", paint("stopped early: targets never probed are missing, not down", Style::Warn));
+    }
+    if let Some(portal) = &metadata.captive_portal {
+        let finding = format!(
+            "likely captive portal: {} host(s) answer HTTP with {}; open ports and hosts may be the portal's, not real",
+            portal.hosts, portal.answer
+        );
+        eprintln!("  {}", paint(finding, Style::Bad));
+    }
+    if let Some(live) = metadata.sampled_from {
+        eprintln!("  reports keep a random sample of {} of the {} live hosts (--max-results)", results.len(), live);
+    }
+    if !metadata.open_port_histogram.is_empty() {
+        let top: Vec<String> = metadata
+            .open_port_histogram
+            .iter()
+            .take(SUMMARY_TOP_PORTS)
+            .map(|count| format!("{} on {}", count.port, count.hosts))
+            .collect();
+        let more = metadata.open_port_histogram.len().saturating_sub(SUMMARY_TOP_PORTS);
+        let more = if more > 0 { format!(", {} more", more) } else { String::new() };
+        eprintln!("  open ports by host count: {}{}", top.join(", "), more);
+    }
+    let mut tiers: std::collections::BTreeMap<LatencyTier, usize> = std::collections::BTreeMap::new();
+    for tier in results.iter().filter_map(|result| result.latency_tier) {
+        *tiers.entry(tier).or_default() += 1;
+    }
+    if !tiers.is_empty() {
+        let counts: Vec<String> = tiers.iter().map(|(tier, hosts)| format!("{} {}", hosts, tier.name())).collect();
+        eprintln!("  latency tiers: {}", counts.join(", "));
+    }
+    let carried = results.iter().filter(|result| result.carried_from_baseline).count();
+    if carried > 0 {
+        eprintln!("  {} host(s) unchanged since the baseline, deep probes carried over", carried);
+    }
+    for result in results {
+        if let Some((port, rtt)) = result.slowest_port() {
+            let note = if result.possible_forward { paint(", possible port forward", Style::Warn) } else { String::new() };
+            eprintln!("  {:<15}  slowest port {} ({:.1} ms{})", result.ip, port, rtt, note);
+        }
+        if !result.same_device_as.is_empty() {
+            eprintln!("  {:<15}  same device as {}", result.ip, result.same_device_as.join(", "));
+        }
+        if !result.dual_stack_addresses.is_empty() && !result.ip.contains(':') {
+            eprintln!("  {:<15}  dual-stack, also {}", result.ip, result.dual_stack_addresses.join(", "));
+        }
+        if result.rdp_nla == Some(false) {
+            let finding = "RDP does not require NLA, the logon screen is reachable without credentials";
+            eprintln!("  {:<15}  {}", result.ip, paint(finding, Style::Bad));
+        }
+        for outlier in &result.response_outliers {
+            eprintln!("  {:<15}  {}", result.ip, paint(format_args!("unusual response, {}", outlier), Style::Warn));
+        }
+        if !result.cve_hints.is_empty() {
+            let cves = paint(result.cve_hints.join(", "), Style::Bad);
+            eprintln!("  {:<15}  banner suggests {} (unverified)", result.ip, cves);
+        }
+        if !result.complete {
+            eprintln!(
+                "  {:<15}  {} after {} connect attempts, some ports unanswered",
+                result.ip,
+                paint("INCOMPLETE", Style::Warn),
+                result.attempts
+            );
+        }
+        for service in &result.mdns_services {
+            eprintln!("  {:<15}  advertises {} ({} on port {})", result.ip, service.name, service.service, service.port);
+        }
+    }
+}
+
+// Formatters are independent, so run them side by side on the blocking pool and let
+// every one finish even if another fails
+async fn write_reports(results: Arc<Vec<ScanResult>>, jobs: Vec<ReportJob>) -> Result<(), MapperError> {
+    let handles: Vec<_> = jobs
+        .into_iter()
+        .map(|job| {
+            let results = Arc::clone(&results);
+            let path = job.path.clone();
+            let handle = tokio::task::spawn_blocking(move || (job.write)(&results, &job.path));
+            (job.format, path, handle)
+        })
+        .collect();
+
+    let mut failed = Vec::new();
+    for (format, path, handle) in handles {
+        match handle.await {
+            Ok(Ok(())) if path == "-" => {}
+            Ok(Ok(())) => eprintln!("{}: wrote {}", format, path),
+            Ok(Err(e)) => {
+                eprintln!("{}: {} {}: {}", format, paint("failed to write", Style::Bad), path, e);
+                failed.push(format);
+            }
+            Err(e) => {
+                eprintln!("{}: writer did not complete: {}", format, e);
+                failed.push(format);
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(MapperError::Output(format!(
+            "failed to write {} report(s): {}",
+            failed.len(),
+            failed.join(", ")
+        )))
+    }
+}
+
+fn print_presets() {
+    let join = |ports: &mut dyn Iterator<Item = u16>| ports.map(|p| p.to_string()).collect::<Vec<_>>().join(",");
+
+    println!("scan ports:          {}  (--ports)", join(&mut DEFAULT_SCAN_PORTS.iter().copied()));
+    println!("top ports:           {} ranked, {} in all  (--top-ports)", TOP_PORTS_RANKED, TOP_PORTS.len());
+    println!("alive ports:         {}  (--alive-ports-only)", join(&mut DEFAULT_ALIVE_PORTS.iter().copied()));
+    println!("banner ports:        {}  (--banner-ports)", DEFAULT_BANNER_PORTS);
+    println!("http ports:          {}  (--detect-websocket, --http-probe)", join(&mut HTTP_PORTS.iter().copied()));
+    println!("tls ports:           {}  (--ja3s, --tls-certs, --http-probe)", join(&mut TLS_PORTS.iter().copied()));
+    println!("ssh ports:           {}  (--ssh-host-keys)", join(&mut SSH_PORTS.iter().copied()));
+    println!("udp payloads:        {}  (--udp-ports)", join(&mut UDP_PROBES.iter().map(|(port, _)| *port)));
+    println!("  protocol ports are only probed when --ports includes them");
+    // EXPECTED_SERVICES knows more protocols than are scanned by default
+    let mut verified = EXPECTED_SERVICES.iter().map(|e| e.port).filter(|port| DEFAULT_SCAN_PORTS.contains(port));
+    println!("verified services:   {}  (--verify-services)", join(&mut verified));
+    let multipliers: Vec<String> = SLOW_PORT_MULTIPLIERS
+        .iter()
+        .map(|(port, factor)| format!("{}={}", port, factor))
+        .collect();
+    println!("read timeout scale:  {}  (--probe-timeout-multiplier)", multipliers.join(","));
+    println!("client hello profiles (--client-hello, JA3 shown):");
+    for profile in ClientHelloProfile::value_variants() {
+        if let Some(name) = profile.to_possible_value() {
+            println!("  {:<8} {}", name.get_name(), profile.ja3(None));
+        }
+    }
+}
+
+// Sockets beyond the scan itself: stdio, output files, sink, control socket
+const FD_HEADROOM: u64 = 32;
+
+// Returns the number of failed checks
+async fn run_doctor(args: &Args) -> usize {
+    let mut failures = 0;
+    let mut report = |name: &str, result: Result<String, (String, &str)>| match result {
+        Ok(detail) => eprintln!("[{}] {}: {}", paint(" ok ", Style::Good), name, detail),
+        Err((problem, hint)) => {
+            failures += 1;
+            eprintln!("[{}] {}: {}\n       hint: {}", paint("fail", Style::Bad), name, problem, hint);
+        }
+    };
+
+    report(
+        "raw sockets",
+        Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
+            .map(|_| "available for ICMP capture".to_string())
+            .map_err(|e| {
+                (
+                    e.to_string(),
+                    "run as root or grant CAP_NET_RAW (setcap cap_net_raw+ep <binary>) to use --icmp-errors",
+                )
+            }),
+    );
+
+    // Each in-flight host holds at most one socket at a time
+    let sockets = args.connect_limit.unwrap_or(args.concurrency).min(args.concurrency);
+    let needed = sockets as u64 + FD_HEADROOM;
+    report(
+        "ping",
+        icmp_echo_socket()
+            .map(|(_, raw)| if raw { "raw ICMP socket" } else { "unprivileged ping socket" }.to_string())
+            .map_err(|e| {
+                (
+                    e.to_string(),
+                    "run as root, grant CAP_NET_RAW, or widen net.ipv4.ping_group_range to use --ping-first",
+                )
+            }),
+    );
+
+    report(
+        "file descriptors",
+        match open_files_limit() {
+            Some(limit) if limit >= needed => Ok(format!("limit {} covers the {} needed", limit, needed)),
+            Some(limit) => Err((
+                format!("limit {} is below the {} needed", limit, needed),
+                "raise it with `ulimit -n` or lower --connect-limit",
+            )),
+            None => Ok("limit unknown on this platform, skipped".to_string()),
+        },
+    );
+
+    report(
+        "dns",
+        match timeout(Duration::from_secs(3), tokio::net::lookup_host("example.com:80")).await {
+            Ok(Ok(addrs)) => match addrs.count() {
+                0 => Err(("example.com resolved to nothing".to_string(), "check /etc/resolv.conf")),
+                count => Ok(format!("example.com resolved to {} address(es)", count)),
+            },
+            Ok(Err(e)) => Err((e.to_string(), "check /etc/resolv.conf and network connectivity")),
+            Err(_) => Err(("lookup timed out".to_string(), "check that the configured resolver is reachable")),
+        },
+    );
+
+    report(
+        "output path",
+        check_writable(&args.output_file)
+            .map(|_| format!("{} is writable", args.output_file))
+            .map_err(|e| (e.to_string(), "pick a writable --output-file or fix directory permissions")),
+    );
+
+    if failures > 0 {
+        eprintln!("{}", paint(format_args!("{} check(s) failed", failures), Style::Bad));
+    } else {
+        eprintln!("{}", paint("all checks passed", Style::Good));
+    }
+    failures
+}
+
+// Soft RLIMIT_NOFILE, read from procfs to avoid a libc dependency
+fn open_files_limit() -> Option<u64> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits.lines().find(|line| line.starts_with("Max open files"))?;
+    line.split_whitespace().nth(3)?.parse().ok()
+}
+
+// Probe with a scratch file beside the target so an existing report is left alone
+fn check_writable(output_file: &str) -> Result<(), std::io::Error> {
+    let path = std::path::Path::new(output_file);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let probe = dir.join(format!(".network-mapper-doctor-{}", std::process::id()));
+    File::create(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+// Union of every target source, deduplicated: IPv4 targets first, then IPv6 ones,
+// each in address order
+fn expand_targets(args: &Args) -> Result<Vec<IpAddr>, MapperError> {
+    let mut targets = std::collections::BTreeSet::new();
+    targets.extend(args.ips.iter().copied());
+    if let Some(range) = target_range(args)? {
+        targets.extend(range.map(|ip| IpAddr::V4(Ipv4Addr::from(ip))));
+    }
+    targets.extend(ipv6_cidr_hosts(args)?);
+    Ok(targets.into_iter().collect())
+}
+
+// Addresses an IPv6 --cidr, or any --target network or range, may hold before it is
+// refused, when --max-hosts is not given
+const DEFAULT_MAX_ENUMERATED_HOSTS: u64 = 65536;
+
+// The hosts of an IPv6 --cidr, refused outright when there are more than --max-hosts:
+// a /64 alone would take longer than the age of the universe to walk
+fn ipv6_cidr_hosts(args: &Args) -> Result<Vec<IpAddr>, MapperError> {
+    let Some(CidrTarget::V6(cidr)) = args.cidr else {
+        return Ok(Vec::new());
+    };
+    let max_hosts = args.max_hosts.unwrap_or(DEFAULT_MAX_ENUMERATED_HOSTS);
+    match cidr.size() {
+        Some(size) if size <= u128::from(max_hosts) => Ok(cidr.hosts().map(IpAddr::V6).collect()),
+        _ => Err(MapperError::Parse(format!(
+            "--cidr {}: a /{} holds 2^{} addresses, more than --max-hosts {}. IPv6 networks \
+             cannot be enumerated; scan a small prefix such as a /120 (256 addresses), or list \
+             known hosts with --ips",
+            CidrTarget::V6(cidr),
+            cidr.prefix_len,
+            128 - u32::from(cidr.prefix_len),
+            max_hosts
+        ))),
+    }
+}
+
+// The --cidr or --start-ip/--end-ip range, or the default one when nothing else is given
+fn target_range(args: &Args) -> Result<Option<std::ops::RangeInclusive<u32>>, MapperError> {
+    match &args.cidr {
+        Some(CidrTarget::V4(cidr)) => {
+            let (start_ip, end_ip) = cidr.host_range();
+            return Ok(Some(u32::from(start_ip)..=u32::from(end_ip)));
+        }
+        // Its hosts come from ipv6_cidr_hosts
+        Some(CidrTarget::V6(_)) => return Ok(None),
+        None => {}
+    }
+    if args.start_ip.is_none() && args.end_ip.is_none() && !(args.ips.is_empty() && args.target.is_empty() && !args.stdin)
+    {
+        return Ok(None);
+    }
+    let start_ip = parse_ipv4("--start-ip", args.start_ip.as_deref().unwrap_or(DEFAULT_START_IP))?;
+    let end_ip = parse_ipv4("--end-ip", args.end_ip.as_deref().unwrap_or(DEFAULT_END_IP))?;
+    Ok(Some(u32::from(start_ip)..=u32::from(end_ip)))
+}
+
+#[derive(Deserialize)]
+struct SavedReport {
+    metadata: ReportMetadata,
+    hosts: Vec<ScanResult>,
+    // Present when written under --deduplicate-banners
+    #[serde(default)]
+    banners: Vec<String>,
+}
+
+// What --from-file reads: a --save file or stdout's bare host array, or the wrapped
+// form of --json-output, --format json and --stdout-metadata. The shape is picked
+// up front because an untagged enum cannot read the string keys of port maps
+// `source` names where the path came from in errors, e.g. "--from-file"
+fn load_saved_results(
+    source: &str,
+    path: &str,
+    scan_id: Option<String>,
+) -> Result<(ReportMetadata, Vec<ScanResult>), MapperError> {
+    let invalid = |e: &dyn std::fmt::Display| MapperError::Parse(format!("{} {}: {}", source, path, e));
+    let contents = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+    let wrapped = contents.trim_start().starts_with('{');
+    Ok(if wrapped {
+        // The original scan's identity survives the round trip
+        let mut report: SavedReport = serde_json::from_str(&contents).map_err(|e| invalid(&e))?;
+        expand_banners(&mut report.hosts, &report.banners).map_err(|e| invalid(&e))?;
+        (report.metadata, report.hosts)
+    } else {
+        let mut hosts: Vec<ScanResult> = serde_json::from_str(&contents).map_err(|e| invalid(&e))?;
+        expand_banners(&mut hosts, &[]).map_err(|e| invalid(&e))?;
+        let metadata = ReportMetadata {
+            scan_id: scan_id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            targets: hosts.len(),
+            client_ja3: None,
+            resolved_targets: std::collections::BTreeMap::new(),
+            unresolved_targets: std::collections::BTreeMap::new(),
+            open_port_histogram: Vec::new(),
+            sampled_from: None,
+            stopped: false,
+            captive_portal: None,
+        };
+        (metadata, hosts)
+    })
+}
+
+// What one --target entry or --stdin line names
+#[derive(Debug, PartialEq)]
+enum TargetSpec {
+    Addresses(Vec<IpAddr>),
+    // Looked up like a --target
+    Name(String),
+}
+
+// An address, a CIDR, an IPv4 range (10.0.0.1-10.0.0.40 or 10.0.0.1-40) or a host
+// name; only the first word counts, so the output of most discovery tools can be
+// piped in as is. Blank lines and # comments name nothing
+fn parse_target_spec(line: &str, max_hosts: Option<u64>) -> Result<TargetSpec, String> {
+    let Some(word) = line.split_whitespace().next().filter(|word| !word.starts_with('#')) else {
+        return Ok(TargetSpec::Addresses(Vec::new()));
+    };
+    if let Ok(ip) = word.parse::<IpAddr>() {
+        return Ok(TargetSpec::Addresses(vec![ip]));
+    }
+    // Checked before collecting: 0.0.0.0/0 alone would be 4 billion addresses
+    let enumerable = |size: Option<u128>| match size {
+        Some(size) if size <= u128::from(max_hosts.unwrap_or(DEFAULT_MAX_ENUMERATED_HOSTS)) => Ok(()),
+        _ => Err(format!("{}: too many addresses to enumerate, see --max-hosts", word)),
+    };
+    if word.contains('/') {
+        return match word.parse::<CidrTarget>()? {
+            CidrTarget::V4(cidr) => {
+                let (first, last) = cidr.host_range();
+                let range = u32::from(first)..=u32::from(last);
+                enumerable(Some(u128::from(u32::from(last) - u32::from(first)) + 1))?;
+                Ok(TargetSpec::Addresses(range.map(|ip| IpAddr::V4(ip.into())).collect()))
+            }
+            CidrTarget::V6(cidr) => {
+                enumerable(cidr.size())?;
+                Ok(TargetSpec::Addresses(cidr.hosts().map(IpAddr::V6).collect()))
+            }
+        };
+    }
+    // Host names may hold dashes too; only an address before the dash makes a range
+    if let Some((first, last)) = word.split_once('-') {
+        if let Ok(first) = first.parse::<Ipv4Addr>() {
+            let last = match last.parse::<Ipv4Addr>() {
+                Ok(last) => last,
+                Err(_) => match last.parse::<u8>() {
+                    Ok(octet) => {
+                        let [a, b, c, _] = first.octets();
+                        Ipv4Addr::new(a, b, c, octet)
+                    }
+                    Err(_) => return Err(format!("invalid end {:?} in range {:?}", last, word)),
+                },
+            };
+            if last < first {
+                return Err(format!("range {:?} ends before it starts", word));
+            }
+            enumerable(Some(u128::from(u32::from(last) - u32::from(first)) + 1))?;
+            let range = u32::from(first)..=u32::from(last);
+            return Ok(TargetSpec::Addresses(range.map(|ip| IpAddr::V4(ip.into())).collect()));
+        }
+    }
+    Ok(TargetSpec::Name(word.to_string()))
+}
+
+// Feeds --stdin targets to the scan until EOF; the channel closes once the last
+// host name lookup is done too
+async fn read_stdin_targets(tx: mpsc::UnboundedSender<IpAddr>, max_hosts: Option<u64>, family: AddressFamily) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut number = 0;
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warning!("--stdin: stopped reading: {}", e);
+                break;
+            }
+        };
+        number += 1;
+        match parse_target_spec(&line, max_hosts) {
+            Ok(TargetSpec::Addresses(ips)) => {
+                let count = ips.len();
+                let kept: Vec<IpAddr> = ips.into_iter().filter(|ip| family.includes(*ip)).collect();
+                if kept.len() < count {
+                    let skipped = count - kept.len();
+                    warning!("--stdin line {}: skipping {} of {} addresses, not {}", number, skipped, count, family.name());
+                }
+                for ip in kept {
+                    let _ = tx.send(ip);
+                }
+            }
+            Ok(TargetSpec::Name(name)) => {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut resolution = resolve_targets(std::slice::from_ref(&name), TARGET_RESOLVE_TIMEOUT).await;
+                    resolution.retain_family(family);
+                    for (name, error) in resolution.failed {
+                        warning!("--stdin {}: {}", name, error);
+                    }
+                    for ip in resolution.resolved.into_values().flatten() {
+                        let _ = tx.send(ip);
+                    }
+                });
+            }
+            Err(e) => warning!("--stdin line {}: {}", number, e),
+        }
+    }
+}
+
+// Per --target name; getaddrinfo can otherwise wait on a dead nameserver for much longer
+const TARGET_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct TargetResolution {
+    // Name to its addresses, IPv4 first
+    resolved: std::collections::BTreeMap<String, Vec<IpAddr>>,
+    // Name to the reason it did not resolve
+    failed: std::collections::BTreeMap<String, String>,
+}
+
+impl TargetResolution {
+    // A name left with no address of the family counts as failed, with a reason saying so
+    fn retain_family(&mut self, family: AddressFamily) {
+        for (name, mut addresses) in std::mem::take(&mut self.resolved) {
+            addresses.retain(|ip| family.includes(*ip));
+            if addresses.is_empty() {
+                self.failed.insert(name, format!("no {} address", family.name()));
+            } else {
+                self.resolved.insert(name, addresses);
+            }
+        }
+    }
+}
+
+// Resolves every name concurrently through the system resolver. A literal address is
+// its own answer, so mixing names and IPs in --target costs no lookups
+async fn resolve_targets(names: &[String], wait: Duration) -> TargetResolution {
+    let lookups: Vec<_> = names
+        .iter()
+        .map(|name| {
+            let name = name.trim().to_string();
+            tokio::spawn(async move {
+                let answer = match name.parse::<IpAddr>() {
+                    Ok(ip) => Ok(vec![ip]),
+                    Err(_) => match timeout(wait, tokio::net::lookup_host((name.as_str(), 0))).await {
+                        Ok(Ok(addrs)) => Ok(addrs.map(|addr| addr.ip()).collect::<Vec<_>>()),
+                        Ok(Err(e)) => Err(e.to_string()),
+                        Err(_) => Err(format!("no answer within {:?}", wait)),
+                    },
+                };
+                (name, answer)
+            })
+        })
+        .collect();
+
+    let mut resolution = TargetResolution::default();
+    for lookup in lookups {
+        let Ok((name, answer)) = lookup.await else { continue };
+        match answer {
+            Ok(mut addresses) if !addresses.is_empty() => {
+                addresses.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+                addresses.dedup();
+                resolution.resolved.insert(name, addresses);
+            }
+            Ok(_) => {
+                resolution.failed.insert(name, "no addresses".to_string());
+            }
+            Err(e) => {
+                resolution.failed.insert(name, e);
+            }
+        }
+    }
+    resolution
+}
+
+// Seconds between buckets under --schedule-window unless --schedule-interval is given
+const DEFAULT_SCHEDULE_INTERVAL: u64 = 900;
+
+// Bucket `bucket` of `buckets` takes every buckets-th target, so each subnet is spread
+// over the whole window instead of being scanned in one go
+fn schedule_bucket<T: Copy>(targets: &[T], bucket: usize, buckets: usize) -> Vec<T> {
+    targets.iter().skip(bucket).step_by(buckets).copied().collect()
+}
+
+// --checkpoint: where a --schedule-window run got to, and the hosts it found so far
+#[derive(Serialize, Deserialize)]
+struct ScheduleCheckpoint {
+    // Unix seconds when the first bucket was due; each later one is due an interval on
+    started_at: u64,
+    targets: usize,
+    buckets: usize,
+    next_bucket: usize,
+    hosts: Vec<ScanResult>,
+}
+
+impl ScheduleCheckpoint {
+    fn new(started_at: u64, targets: usize, buckets: usize) -> Self {
+        ScheduleCheckpoint { started_at, targets, buckets, next_bucket: 0, hosts: Vec::new() }
+    }
+
+    // None when there is nothing to resume: no file yet, or a schedule that finished
+    fn load(path: &str, targets: usize, buckets: usize) -> Result<Option<Self>, MapperError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(MapperError::Parse(format!("--checkpoint {}: {}", path, e))),
+        };
+        let checkpoint: ScheduleCheckpoint =
+            serde_json::from_str(&contents).map_err(|e| MapperError::Parse(format!("--checkpoint {}: {}", path, e)))?;
+        if checkpoint.targets != targets || checkpoint.buckets != buckets {
+            return Err(MapperError::Parse(format!(
+                "--checkpoint {}: made for {} targets in {} buckets, not {} in {}; remove it to start over",
+                path, checkpoint.targets, checkpoint.buckets, targets, buckets
+            )));
+        }
+        Ok((checkpoint.next_bucket < checkpoint.buckets).then_some(checkpoint))
+    }
+
+    // Written aside and renamed over, so a crash mid-write leaves the previous one intact
+    fn save(&self, path: &str, hosts: &[ScanResult]) -> Result<(), MapperError> {
+        let json = serde_json::to_string(&json!({
+            "started_at": self.started_at,
+            "targets": self.targets,
+            "buckets": self.buckets,
+            "next_bucket": self.next_bucket,
+            "hosts": hosts,
+        }))?;
+        let staging = format!("{}.tmp", path);
+        std::fs::write(&staging, json)
+            .and_then(|_| std::fs::rename(&staging, path))
+            .map_err(|e| MapperError::Output(format!("--checkpoint {}: {}", path, e)))
+    }
+}
+
+// Chance that --huge-scan skips a range address because its filter wrongly claims a
+// listed target already covered it; the filter is made smaller only past its memory cap
+const HUGE_SCAN_FALSE_POSITIVE_RATE: f64 = 0.001;
+const MAX_BLOOM_FILTER_BYTES: u64 = 512 * 1024 * 1024;
+// Tasks spawned ahead of the scan, per host allowed to scan at once
+const HUGE_SCAN_QUEUE_FACTOR: usize = 4;
+
+// The same targets as expand_targets, in listed-then-range order, without collecting
+// the range. Addresses within the range cannot repeat, so only a range address that
+// was also listed needs skipping; a bloom filter of the listed ones, a fraction of
+// the size of the list itself, tells which
+fn huge_scan_targets(args: &Args) -> Result<impl Iterator<Item = IpAddr> + Send + 'static, MapperError> {
+    let range = target_range(args)?.into_iter().flatten();
+    let mut listed = args.ips.clone();
+    listed.extend(ipv6_cidr_hosts(args)?);
+    let mut seen = BloomFilter::with_capacity(listed.len() as u64);
+    if !listed.is_empty() {
+        eprintln!(
+            "--huge-scan: {} KiB filter for {} listed targets, false positive rate {:.2e}",
+            seen.bit_len() / 8 / 1024,
+            listed.len(),
+            seen.false_positive_rate(listed.len() as u64)
+        );
+    }
+
+    let only_subnets = args.only_subnets.clone();
+    let (only_private, only_public) = (args.only_private, args.only_public);
+    let in_scope = move |ip: IpAddr| {
+        (only_subnets.is_empty() || in_subnets(ip, &only_subnets))
+            && (!only_private || is_internal(ip))
+            && (!only_public || is_globally_routable(ip))
+    };
+
+    let listed = listed.into_iter().map(|ip| (ip, true));
+    let ranged = range.map(|ip| (IpAddr::V4(Ipv4Addr::from(ip)), false));
+    Ok(listed
+        .chain(ranged)
+        .filter(move |&(ip, is_listed)| {
+            let first_seen = if is_listed { seen.insert(bloom_key(ip)) } else { !seen.contains(bloom_key(ip)) };
+            first_seen && in_scope(ip)
+        })
+        .map(|(ip, _)| ip))
+}
+
+// Both families in one key space, IPv4 as v4-mapped IPv6
+fn bloom_key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+// Set membership in fixed memory: never a false "absent", occasionally a false "present"
+struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    // Sized for `expected` items at HUGE_SCAN_FALSE_POSITIVE_RATE, within MAX_BLOOM_FILTER_BYTES
+    fn with_capacity(expected: u64) -> Self {
+        let expected = expected.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let wanted = (-expected * HUGE_SCAN_FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as u64;
+        let bits = wanted.clamp(64, MAX_BLOOM_FILTER_BYTES * 8);
+        let hashes = (bits as f64 / expected * ln2).round().clamp(1.0, 16.0) as u32;
+        BloomFilter { bits: vec![0; bits.div_ceil(64) as usize], hashes }
+    }
+
+    fn bit_len(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    // Double hashing: the i-th position is h1 + i * h2
+    fn positions(&self, item: u128) -> impl Iterator<Item = (usize, u64)> {
+        let h1 = splitmix64(item as u64 ^ splitmix64((item >> 64) as u64));
+        let h2 = splitmix64(h1) | 1;
+        let len = self.bit_len();
+        (0..u64::from(self.hashes)).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % len;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+
+    // Returns false if the item was (probably) inserted before
+    fn insert(&mut self, item: u128) -> bool {
+        let mut new = false;
+        for (word, mask) in self.positions(item).collect::<Vec<_>>() {
+            new |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        new
+    }
+
+    fn contains(&self, item: u128) -> bool {
+        self.positions(item).all(|(word, mask)| self.bits[word] & mask != 0)
+    }
+
+    fn false_positive_rate(&self, items: u64) -> f64 {
+        let k = f64::from(self.hashes);
+        (1.0 - (-k * items as f64 / self.bit_len() as f64).exp()).powf(k)
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn parse_ipv4(flag: &str, value: &str) -> Result<Ipv4Addr, MapperError> {
+    value
+        .parse()
+        .map_err(|e| MapperError::Parse(format!("{} {:?}: {}", flag, value, e)))
+}
+
+// I/O errors alone don't say which file or option they came from
+fn read_input_file(flag: &str, path: &str) -> Result<String, MapperError> {
+    std::fs::read_to_string(path).map_err(|e| MapperError::Parse(format!("{} {}: {}", flag, path, e)))
+}
+
+fn load_allowlist(path: &str) -> Result<Vec<Ipv4Cidr>, MapperError> {
+    let contents = read_input_file("--allowlist", path)?;
+    let mut allowlist = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let cidr = line
+            .parse()
+            .map_err(|e| MapperError::Parse(format!("{}:{}: {}", path, number + 1, e)))?;
+        allowlist.push(cidr);
+    }
+    Ok(allowlist)
+}
+
+// Lines of "<ip-or-cidr> <os label>"; the label may contain spaces
+fn load_os_overrides(path: &str) -> Result<Vec<(Ipv4Cidr, String)>, MapperError> {
+    let contents = read_input_file("--os-override", path)?;
+    let mut overrides = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: String| MapperError::Parse(format!("{}:{}: {}", path, number + 1, reason));
+        let (target, label) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| invalid("expected <ip-or-cidr> <os label>".to_string()))?;
+        let cidr: Ipv4Cidr = target.parse().map_err(invalid)?;
+        overrides.push((cidr, label.trim().to_string()));
+    }
+    overrides.sort_by_key(|(cidr, _)| std::cmp::Reverse(cidr.prefix_len));
+    Ok(overrides)
+}
+
+fn load_masscan(path: &str) -> Result<HashMap<IpAddr, Vec<u16>>, MapperError> {
+    let contents = read_input_file("--import-masscan", path)?;
+    let (imported, skipped) = parse_masscan(path, &contents)?;
+    if imported.is_empty() {
+        return Err(MapperError::Parse(format!("--import-masscan {}: no open TCP ports in the file", path)));
+    }
+    eprintln!(
+        "import-masscan: {} open TCP ports on {} hosts{}",
+        imported.values().map(Vec::len).sum::<usize>(),
+        imported.len(),
+        if skipped > 0 { format!(", {} UDP or other records skipped", skipped) } else { String::new() }
+    );
+    Ok(imported)
+}
+
+// masscan -oJ writes one record per line inside a JSON array, e.g.
+//   {"ip": "10.0.0.5", "timestamp": "1700000000", "ports": [{"port": 22, "proto": "tcp", "status": "open", ...}]}
+// Older versions leave a comma before the closing bracket and end with a
+// {finished: 1} trailer, which no JSON parser accepts as a whole, so a file that
+// does not parse is read a line at a time; -oD's one-record-per-line form reads the
+// same way. Banner records (--banners) carry a "service" instead of a status and
+// name a port that is open. Returns the open TCP ports per host, sorted, and how
+// many port records were not TCP
+fn parse_masscan(path: &str, contents: &str) -> Result<(HashMap<IpAddr, Vec<u16>>, usize), MapperError> {
+    let records: Vec<(usize, serde_json::Value)> = match serde_json::from_str::<Vec<serde_json::Value>>(contents) {
+        Ok(records) => records.into_iter().map(|record| (0, record)).collect(),
+        Err(_) => {
+            let mut records = Vec::new();
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim().trim_matches(|c: char| c == ',' || c == '[' || c == ']').trim();
+                if line.is_empty() || line.replace(char::is_whitespace, "") == "{finished:1}" {
+                    continue;
+                }
+                let record = serde_json::from_str(line)
+                    .map_err(|e| MapperError::Parse(format!("{}:{}: {}", path, number + 1, e)))?;
+                records.push((number + 1, record));
+            }
+            records
+        }
+    };
+
+    let mut open: std::collections::BTreeMap<IpAddr, std::collections::BTreeSet<u16>> = Default::default();
+    let mut skipped = 0;
+    for (line, record) in records {
+        let invalid = |reason: String| {
+            let at = if line > 0 { format!("{}:{}", path, line) } else { path.to_string() };
+            MapperError::Parse(format!("{}: {}", at, reason))
+        };
+        // Anything without an address, such as a quoted form of the finished trailer
+        let Some(ip) = record.get("ip").and_then(|ip| ip.as_str()) else {
+            continue;
+        };
+        let ip: IpAddr = ip.trim().parse().map_err(|e| invalid(format!("ip {:?}: {}", ip, e)))?;
+        let Some(ports) = record.get("ports").and_then(|ports| ports.as_array()) else {
+            continue;
+        };
+        for entry in ports {
+            let port = entry
+                .get("port")
+                .and_then(|port| port.as_u64())
+                .and_then(|port| u16::try_from(port).ok())
+                .filter(|&port| port != 0)
+                .ok_or_else(|| invalid(format!("{}: port record without a valid port", ip)))?;
+            if entry.get("proto").and_then(|proto| proto.as_str()).is_some_and(|proto| proto != "tcp") {
+                skipped += 1;
+                continue;
+            }
+            let open_port = match entry.get("status").and_then(|status| status.as_str()) {
+                Some(status) => status == "open",
+                None => entry.get("service").is_some(),
+            };
+            if open_port {
+                open.entry(ip).or_default().insert(port);
+            }
+        }
+    }
+    Ok((open.into_iter().map(|(ip, ports)| (ip, ports.into_iter().collect())).collect(), skipped))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct RoleRule {
+    role: String,
+    weights: Vec<(u16, f64)>,
+}
+
+fn builtin_role_rules() -> Vec<RoleRule> {
+    ROLE_RULES
+        .iter()
+        .map(|(role, weights)| RoleRule { role: role.to_string(), weights: weights.to_vec() })
+        .collect()
+}
+
+// Lines of "<role> <port>:<weight>,..."; a role already known gets the new weights,
+// any other is added after the built-in roles
+fn load_role_rules(path: &str) -> Result<Vec<RoleRule>, MapperError> {
+    let contents = read_input_file("--role-rules", path)?;
+    let mut rules = builtin_role_rules();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: String| MapperError::Parse(format!("{}:{}: {}", path, number + 1, reason));
+        let (role, list) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| invalid("expected <role> <port>:<weight>,...".to_string()))?;
+        let weights = list
+            .split(',')
+            .map(|entry| {
+                let (port, weight) = entry
+                    .trim()
+                    .split_once(':')
+                    .ok_or_else(|| invalid(format!("{}: expected <port>:<weight>", entry.trim())))?;
+                let port: u16 = port.parse().map_err(|e| invalid(format!("port {}: {}", port, e)))?;
+                let weight: f64 = weight.parse().map_err(|e| invalid(format!("weight {}: {}", weight, e)))?;
+                if !weight.is_finite() || weight < 0.0 {
+                    return Err(invalid(format!("weight {}: must be zero or more", weight)));
+                }
+                Ok((port, weight))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match rules.iter_mut().find(|rule| rule.role == role) {
+            Some(rule) => rule.weights = weights,
+            None => rules.push(RoleRule { role: role.to_string(), weights }),
+        }
+    }
+    Ok(rules)
+}
+
+// Roles with a positive score, best first; equal scores keep rule order
+fn rank_roles(open_ports: &[u16], rules: &[RoleRule]) -> Vec<RoleScore> {
+    let mut roles: Vec<RoleScore> = rules
+        .iter()
+        .map(|rule| RoleScore {
+            role: rule.role.clone(),
+            score: rule.weights.iter().filter(|(port, _)| open_ports.contains(port)).map(|(_, weight)| weight).sum(),
+        })
+        .filter(|role| role.score > 0.0)
+        .collect();
+    roles.sort_by(|a, b| b.score.total_cmp(&a.score));
+    roles
+}
+
+#[derive(Debug, PartialEq)]
+struct CveHint {
+    pattern: String,
+    cves: Vec<String>,
+}
+
+fn builtin_cve_hints() -> Vec<CveHint> {
+    CVE_HINTS
+        .iter()
+        .map(|(pattern, cves)| CveHint {
+            pattern: pattern.to_string(),
+            cves: cves.iter().map(|cve| cve.to_string()).collect(),
+        })
+        .collect()
+}
+
+// Lines of "<banner substring> <CVE-ID>..."; the substring may contain spaces and
+// runs up to the first CVE ID
+fn load_cve_map(path: &str) -> Result<Vec<CveHint>, MapperError> {
+    let contents = read_input_file("--cve-map", path)?;
+    let mut hints = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let split = words.iter().position(|word| word.starts_with("CVE-")).unwrap_or(words.len());
+        let (pattern, cves) = words.split_at(split);
+        if pattern.is_empty() || cves.is_empty() {
+            return Err(MapperError::Parse(format!(
+                "{}:{}: expected <banner substring> <CVE-ID>...",
+                path,
+                number + 1
+            )));
+        }
+        hints.push(CveHint {
+            pattern: pattern.join(" "),
+            cves: cves.iter().map(|cve| cve.to_string()).collect(),
+        });
+    }
+    Ok(hints)
+}
+
+// A pattern matches anywhere in a banner, ignoring case, but not as the front of a
+// longer version: "OpenSSH_7.2" matches "OpenSSH_7.2p2" and not "OpenSSH_7.23"
+fn cve_hints_for(banners: &HashMap<u16, String>, hints: &[CveHint]) -> Vec<String> {
+    let mut cves = Vec::new();
+    for banner in banners.values() {
+        let banner = banner.to_ascii_lowercase();
+        for hint in hints {
+            let pattern = hint.pattern.to_ascii_lowercase();
+            let matched = banner.match_indices(&pattern).any(|(at, _)| {
+                !banner[at + pattern.len()..].starts_with(|c: char| c.is_ascii_digit())
+            });
+            if matched {
+                cves.extend(hint.cves.iter().cloned());
+            }
+        }
+    }
+    cves.sort();
+    cves.dedup();
+    cves
+}
+
+// Refuse the whole run if any target falls outside the permitted ranges. These are
+// IPv4 only, so every IPv6 target is a violation
+fn enforce_allowlist(allowlist: &[Ipv4Cidr], targets: impl IntoIterator<Item = IpAddr>) -> Result<(), MapperError> {
+    const MAX_LOGGED: usize = 10;
+    let mut violations = 0;
+    for ip in targets {
+        if !in_subnets(ip, allowlist) {
+            if violations < MAX_LOGGED {
+                eprintln!("allowlist violation: {} is not in any permitted range", ip);
+            }
+            violations += 1;
+        }
+    }
+
+    if violations > MAX_LOGGED {
+        eprintln!("allowlist violation: ... and {} more", violations - MAX_LOGGED);
+    }
+    if violations > 0 {
+        return Err(MapperError::Scan(format!(
+            "{} target(s) outside the allowlist, refusing to scan",
+            violations
+        )));
+    }
+    Ok(())
+}
+
+// Worst case assumes every target turns out to be alive
+fn estimate_peak_memory(targets: usize, concurrency: usize) -> u64 {
+    targets as u64 * (TASK_BYTES_ESTIMATE + RESULT_BYTES_ESTIMATE)
+        + concurrency.min(targets) as u64 * IN_FLIGHT_BYTES_ESTIMATE
+}
+
+// Raising it past the targets gains nothing, and past the open-file limit makes
+// connects fail with EMFILE. Returns the warning for tests
+fn warn_if_concurrency_pointless(concurrency: usize, targets: usize, open_files: Option<u64>) -> Option<String> {
+    let warning = match open_files {
+        Some(limit) if concurrency as u64 + FD_HEADROOM > limit => format!(
+            "--concurrency {} is above the open-file limit of {}; connects will fail with \
+             \"too many open files\" unless you raise `ulimit -n` or set --connect-limit",
+            concurrency, limit
+        ),
+        _ if concurrency > MAX_CONCURRENT_SCANS && concurrency > targets.saturating_mul(2) => format!(
+            "--concurrency {} is far above the {} targets, all of which are scanned at once anyway",
+            concurrency, targets
+        ),
+        _ => return None,
+    };
+    warning!("{}", warning);
+    Some(warning)
+}
+
+fn warn_if_memory_heavy(targets: usize, concurrency: usize) {
+    let estimate = estimate_peak_memory(targets, concurrency);
+    if estimate > MEMORY_WARNING_BYTES {
+        warning!(
+            "scanning {} targets at concurrency {} may need up to ~{} MiB; \
+             consider --sink to stream results or splitting the range",
+            targets,
+            concurrency,
+            estimate / (1024 * 1024)
+        );
+    }
+}
+
+/// Scans one address, of either family; `None` when the host shows no sign of life.
+/// Everything it logs is inside a `scan_host` span carrying the address.
+pub async fn scan_host(ip: IpAddr, options: &ScanOptions) -> Option<ScanResult> {
+    let span = tracing::debug_span!("scan_host", ip = %ip);
+    probe_host(ip, options).instrument(span).await
+}
+
+async fn probe_host(ip: IpAddr, options: &ScanOptions) -> Option<ScanResult> {
+    // IPv6 hosts are scanned without one; only ICMPv4 echo is implemented
+    let mut ping_rtt = None;
+    if let (true, IpAddr::V4(v4)) = (options.ping_first, ip) {
+        let (wait, retries) = (options.timeout, options.retries);
+        match tokio::task::spawn_blocking(move || ping_host(v4, wait, retries)).await {
+            Ok(Ok(Some(rtt))) => ping_rtt = Some(rtt.as_secs_f64() * 1000.0),
+            Ok(Ok(None)) => {
+                tracing::info!("skipped: no reply to the ICMP echo (--ping-first)");
+                return None;
+            }
+            // Could not tell, so scan it rather than drop it
+            Ok(Err(e)) => warning!("ping {} failed: {}", ip, e),
+            Err(_) => {}
+        }
+    }
+
+    if let Some(alive_ports) = &options.alive_ports {
+        if !host_answers(ip, alive_ports, options).await {
+            tracing::info!(ports = ?alive_ports, "skipped: no answer on any --alive-ports port");
+            return None;
+        }
+    }
+
+    // Under --probe-changed-only a host the baseline saw only gets the port scan unless
+    // its open ports moved; a host new since then is probed in full. A liveness sweep
+    // never probes
+    let liveness = options.liveness_port.is_some();
+    let previous = options.baseline.as_ref().filter(|_| !liveness).and_then(|baseline| baseline.get(&ip));
+    let mut deep = previous.is_none() && !liveness;
+
+    let imported = options.imported_ports.as_ref().and_then(|imported| imported.get(&ip));
+    let ports_to_scan = match (&options.liveness_port, imported) {
+        (Some(port), _) => std::slice::from_ref(port),
+        (None, Some(ports)) => ports.as_slice(),
+        (None, None) => options.ports.as_slice(),
+    };
+    // Under --liveness-only a reset proves the host is up as well as an accept does
+    let mut refused = false;
+    let mut open_ports = Vec::new();
+    let mut failed_ports = Vec::new();
+    let mut unexpected_ports = Vec::new();
+    let mut port_rtt = HashMap::new();
+    let mut probed_ports = Vec::new();
+    let mut port_probes = Vec::new();
+    let mut attempts = 0;
+    let mut complete = true;
+    let mut source_ip = None;
+
+    for &port in ports_to_scan {
+        // Held until the socket from this attempt is dropped
+        let _socket_permit = options.socket_permit().await;
+
+        let (outcome, tries) = connect_port_counted(ip, port, options).await;
+        attempts += tries;
+        complete &= outcome.is_conclusive();
+        if options.record_probed_ports {
+            let (state, rtt) = match &outcome {
+                ConnectOutcome::Open(_, rtt) => (PortState::Open, Some(rtt)),
+                ConnectOutcome::Refused(rtt) if options.rst_as_open => (PortState::Open, Some(rtt)),
+                ConnectOutcome::Refused(rtt) => (PortState::Closed, Some(rtt)),
+                ConnectOutcome::TimedOut => (PortState::Filtered, None),
+                ConnectOutcome::Error(_) => (PortState::Error, None),
+            };
+            let rtt = rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+            probed_ports.push(ProbedPort { port, state, rtt });
+        }
+        match outcome {
+            ConnectOutcome::Open(mut stream, rtt) => {
+                open_ports.push(port);
+                port_rtt.insert(port, rtt.as_secs_f64() * 1000.0);
+                if source_ip.is_none() {
+                    source_ip = stream.local_addr().ok().map(|addr| addr.ip().to_string());
+                }
+
+                if deep {
+                    tracing::trace!(port, "probing the open port");
+                    port_probes.push((port, probe_open_port(&mut stream, port, options).await));
+                }
+            }
+            ConnectOutcome::Refused(rtt) if options.rst_as_open => {
+                open_ports.push(port);
+                port_rtt.insert(port, rtt.as_secs_f64() * 1000.0);
+            }
+            ConnectOutcome::Refused(_) if liveness => refused = true,
+            _ => failed_ports.push(port),
+        }
+    }
+    // Reports list ports numerically whatever the probe order
+    open_ports.sort_unstable();
+    probed_ports.sort_unstable_by_key(|probed: &ProbedPort| probed.port);
+
+    if let Some(previous) = previous {
+        let mut before = previous.open_ports.clone();
+        before.sort_unstable();
+        if before != open_ports {
+            // The first pass only learned which ports are open; go back for the rest
+            deep = true;
+            for &port in &open_ports {
+                let _socket_permit = options.socket_permit().await;
+                if let ConnectOutcome::Open(mut stream, _) = connect_port(ip, port, options).await {
+                    port_probes.push((port, probe_open_port(&mut stream, port, options).await));
+                }
+            }
+        }
+    }
+    let (mut banners, mut responses, mut http_redirects) = (HashMap::new(), HashMap::new(), HashMap::new());
+    for (port, probe) in port_probes {
+        if let Some(redirect) = probe.redirect {
+            http_redirects.insert(port, redirect);
+        }
+        if probe.unexpected {
+            unexpected_ports.push(port);
+        }
+        if let Some(banner) = probe.banner {
+            banners.insert(port, banner);
+        }
+        if let Some(stats) = probe.stats {
+            responses.insert(port, stats);
+        }
+    }
+    unexpected_ports.sort_unstable();
+
+    let mut websocket_supported = false;
+    if deep && options.detect_websocket {
+        for &port in open_ports.iter().filter(|port| HTTP_PORTS.contains(port)) {
+            if websocket_upgrade(ip, port, options).await {
+                websocket_supported = true;
+                break;
+            }
+        }
+    }
+
+    let mut http_pages = HashMap::new();
+    if deep && options.http_probe {
+        for &port in &open_ports {
+            let tls = TLS_PORTS.contains(&port);
+            if !tls && !HTTP_PORTS.contains(&port) {
+                continue;
+            }
+            if let Some(page) = fetch_http_page(ip, port, tls, options).await {
+                http_pages.insert(port, page);
+            }
+        }
+    }
+
+    let mut ja3s = HashMap::new();
+    let mut tls_certificates = HashMap::new();
+    if let (true, Some(client_hello)) = (deep, &options.client_hello) {
+        for &port in open_ports.iter().filter(|port| TLS_PORTS.contains(port)) {
+            let Some(flight) = tls_server_flight(ip, port, client_hello, options).await else {
+                continue;
+            };
+            let message = |wanted: u8| flight.iter().find(|(kind, _)| *kind == wanted).map(|(_, body)| body);
+            if options.ja3s {
+                if let Some(fingerprint) = message(2).and_then(|hello| parse_server_hello(hello)) {
+                    ja3s.insert(port, format!("{:x}", md5::compute(fingerprint)));
+                }
+            }
+            if options.tls_certs {
+                if let Some(certificate) = message(11).and_then(|body| parse_leaf_certificate(body, ip)) {
+                    tls_certificates.insert(port, certificate);
+                }
+            }
+        }
+    }
+
+    let mut ssh_host_key = None;
+    if deep && options.ssh_host_keys {
+        for &port in open_ports.iter().filter(|port| SSH_PORTS.contains(port)) {
+            ssh_host_key = fetch_ssh_host_key(ip, port, options).await;
+            if ssh_host_key.is_some() {
+                break;
+            }
+        }
+    }
+
+    let udp_ports = scan_host_udp(ip, options).await;
+
+    let (mut rdp_nla, mut rdp_security) = (None, None);
+    if deep && options.rdp_nla {
+        for &port in open_ports.iter().filter(|port| RDP_PORTS.contains(port)) {
+            (rdp_nla, rdp_security) = probe_rdp_security(ip, port, options).await;
+            if rdp_nla.is_some() {
+                break;
+            }
+        }
+    }
+
+    // The raw listener only captures ICMPv4
+    let icmp_errors: HashMap<u16, IcmpError> = match (&options.icmp_errors, ip) {
+        (Some(table), IpAddr::V4(ip)) => {
+            let mut table = table.lock().unwrap();
+            let mut errors: HashMap<u16, IcmpError> = ports_to_scan
+                .iter()
+                .filter_map(|&port| table.remove(&(ip, port)).map(|error| (port, error)))
+                .collect();
+            errors.retain(|port, _| failed_ports.contains(port));
+            errors
+        }
+        _ => HashMap::new(),
+    };
+
+    // A rejection from the host itself (often its firewall) proves it is there even
+    // with no open ports, as does a UDP answer or port unreachable. An incomplete host
+    // is kept too, so it is never mistaken for one that is down
+    let udp_answered = udp_ports.iter().any(|udp| udp.state != UdpState::OpenFiltered);
+    if !open_ports.is_empty()
+        || refused
+        || ping_rtt.is_some()
+        || udp_answered
+        || !complete
+        || matches!(ip, IpAddr::V4(target) if icmp_errors.values().any(|error| error.proves_host_up(target)))
+    {
+        let (os_guess, os_confidence, os_source) =
+            match options.os_overrides.iter().find(|(cidr, _)| in_subnets(ip, std::slice::from_ref(cidr))) {
+                Some((_, label)) => (label.clone(), 1.0, "override"),
+                None => {
+                    let guess = guess_os(&open_ports);
+                    (guess.os.to_string(), guess.confidence, "heuristic")
+                }
+            };
+        let roles = rank_roles(&open_ports, &options.role_rules);
+        let role = roles.first().map_or("unknown", |best| best.role.as_str()).to_string();
+        let hostname = match options.resolver {
+            Some(resolver) => {
+                let _socket_permit = options.socket_permit().await;
+                reverse_dns(resolver, ip, REVERSE_DNS_TIMEOUT).await
+            }
+            None => None,
+        };
+        let mut result = ScanResult {
+            ip: ip.to_string(),
+            hostname,
+            open_ports,
+            os_guess,
+            os_confidence,
+            os_source: os_source.to_string(),
+            subnet: subnet_of(ip, options.subnet_prefix, options.ipv6_subnet_prefix),
+            role,
+            roles,
+            unexpected_service: !unexpected_ports.is_empty(),
+            unexpected_ports,
+            icmp_errors,
+            port_rtt,
+            ping_rtt,
+            latency_tier: None,
+            cve_hints: cve_hints_for(&banners, &options.cve_hints),
+            banners,
+            banner_ids: HashMap::new(),
+            responses,
+            response_outliers: Vec::new(),
+            http_redirects,
+            http_pages,
+            websocket_supported,
+            ja3s,
+            tls_certificates,
+            possible_forward: false,
+            ssh_host_key,
+            rdp_nla,
+            rdp_security,
+            identity_fingerprint: None,
+            same_device_as: Vec::new(),
+            dual_stack_addresses: Vec::new(),
+            mdns_services: Vec::new(),
+            udp_ports,
+            source_ip,
+            probed_ports,
+            carried_from_baseline: false,
+            attempts,
+            complete,
+        };
+        if let (false, Some(previous)) = (deep, previous) {
+            result.carry_deep_probes(previous);
+            result.cve_hints = cve_hints_for(&result.banners, &options.cve_hints);
+        }
+        result.possible_forward = result.looks_forwarded();
+        result.identity_fingerprint = result.identity();
+        result.latency_tier = options.latency_tiers.and_then(|tiers| result.latency_tier_under(&tiers));
+        tracing::info!(open = result.open_ports.len(), complete = result.complete, "up");
+        Some(result)
+    } else {
+        tracing::info!(probed = ports_to_scan.len(), "down: no port opened or refused, and no ICMP error from the host");
+        None
+    }
+}
+
+// What one exchange with an open port found
+#[derive(Default)]
+struct PortProbe {
+    // Failed --verify-services
+    unexpected: bool,
+    banner: Option<String>,
+    stats: Option<ResponseStats>,
+    redirect: Option<HttpRedirect>,
+}
+
+// Service verification, banner and response stats for one open port; one exchange
+// serves them all
+async fn probe_open_port(stream: &mut TcpStream, port: u16, options: &ScanOptions) -> PortProbe {
+    let expected = EXPECTED_SERVICES.iter().find(|e| e.port == port);
+    let verify = options.verify_services && expected.is_some();
+    let grab_banner = options.banner_all || options.banner_ports.contains(&port);
+    if !verify && !grab_banner && !options.response_stats {
+        return PortProbe::default();
+    }
+    let probe = match expected.and_then(|e| e.probe.as_ref()) {
+        Some(ServiceProbe::Bytes(bytes)) => Some(bytes.to_vec()),
+        Some(ServiceProbe::HttpHead) => {
+            Some(format!("HEAD / HTTP/1.0\r\nUser-Agent: {}\r\n\r\n", options.user_agent).into_bytes())
+        }
+        None => None,
+    };
+    let read_timeout = options.read_timeout_for(port);
+    let (response, stats) = if options.response_stats {
+        let (response, stats) = probe_service_measured(stream, probe.as_deref(), read_timeout).await;
+        (response, Some(stats))
+    } else {
+        (probe_service(stream, probe.as_deref(), read_timeout).await, None)
+    };
+    PortProbe {
+        unexpected: match expected.filter(|_| verify) {
+            Some(expected) => response.is_empty() || !(expected.matches)(&response),
+            None => false,
+        },
+        banner: if grab_banner { banner_line(&response) } else { None },
+        stats,
+        redirect: http_redirect(&response),
+    }
+}
+
+async fn scan_host_udp(ip: IpAddr, options: &ScanOptions) -> Vec<UdpPort> {
+    let mut ports = Vec::new();
+    for &port in &options.udp_ports {
+        let payload = UDP_PROBES.iter().find(|(p, _)| *p == port).map_or(&[][..], |(_, payload)| payload);
+        let _socket_permit = options.socket_permit().await;
+        let state = match udp_probe((ip, port).into(), payload, options.timeout, options.retries).await {
+            Ok(state) => state,
+            Err(e) => {
+                warning!("UDP probe of {}:{} failed: {}", ip, port, e);
+                continue;
+            }
+        };
+        ports.push(UdpPort { port, protocol: "udp".to_string(), state });
+    }
+    ports
+}
+
+// A connected UDP socket turns an ICMP port unreachable for its peer into
+// ECONNREFUSED on the next receive, which is how a closed port shows itself
+async fn udp_probe(
+    addr: std::net::SocketAddr,
+    payload: &[u8],
+    wait: Duration,
+    retries: u32,
+) -> std::io::Result<UdpState> {
+    let socket = tokio::net::UdpSocket::bind(unspecified_like(addr)).await?;
+    socket.connect(addr).await?;
+    let mut buf = [0u8; 1500];
+    for _ in 0..=retries {
+        match socket.send(payload).await {
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => return Ok(UdpState::Closed),
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        match timeout(wait, socket.recv(&mut buf)).await {
+            Ok(Ok(_)) => return Ok(UdpState::Open),
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => return Ok(UdpState::Closed),
+            Ok(Err(e)) => return Err(e),
+            // Datagrams get lost; try again before calling it silence
+            Err(_) => {}
+        }
+    }
+    Ok(UdpState::OpenFiltered)
+}
+
+// Adds one permit at a time so concurrency climbs linearly over the period
+async fn ramp_up_permits(semaphore: Arc<Semaphore>, target: usize, period: Duration) {
+    if target <= 1 {
+        return;
+    }
+    let step = period / (target - 1) as u32;
+    for _ in 1..target {
+        sleep(step).await;
+        semaphore.add_permits(1);
+    }
+}
+
+fn start_icmp_listener(
+    table: Arc<IcmpErrorTable>,
+    running: Arc<AtomicBool>,
+) -> std::io::Result<std::thread::JoinHandle<()>> {
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+    // Wake up periodically so the thread notices the end of the scan
+    socket.set_read_timeout(Some(Duration::from_millis(250)))?;
+
+    Ok(std::thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while running.load(Ordering::Relaxed) {
+            let n = match (&socket).read(&mut buf) {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            if let Some((ip, port, error)) = parse_icmp_error(&buf[..n]) {
+                let mut table = table.lock().unwrap();
+                if table.len() < MAX_ICMP_ERRORS {
+                    table.insert((ip, port), error);
+                }
+            }
+        }
+    }))
+}
+
+// Echo requests from a raw socket carry this id, mixed with the process id so two
+// scans side by side can tell their replies apart; a ping socket picks its own
+const PING_ID: u16 = 0x4e4d;
+
+// An unprivileged ping socket where net.ipv4.ping_group_range allows one, else a raw
+// socket. The flag says which: raw reads start with the IP header
+fn icmp_echo_socket() -> std::io::Result<(Socket, bool)> {
+    match Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4)) {
+        Ok(socket) => Ok((socket, false)),
+        Err(_) => Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).map(|socket| (socket, true)),
+    }
+}
+
+/// Sends up to `1 + retries` ICMP echo requests to `ip`, waiting `wait` for each reply,
+/// and returns the round trip of the first one answered; `None` if none was. Blocks
+/// the calling thread. Needs a ping socket (`net.ipv4.ping_group_range`) or raw socket
+/// privileges (root or `CAP_NET_RAW`).
+pub fn ping_host(ip: Ipv4Addr, wait: Duration, retries: u32) -> std::io::Result<Option<Duration>> {
+    let (socket, raw) = icmp_echo_socket()?;
+    // Connected, the kernel only hands this socket packets from `ip`
+    socket.connect(&std::net::SocketAddr::from((ip, 0)).into())?;
+    let id = PING_ID ^ std::process::id() as u16;
+    let mut buf = [0u8; 1500];
+    for seq in 0..=retries.min(u32::from(u16::MAX)) as u16 {
+        let started = Instant::now();
+        socket.send(&echo_request(id, seq))?;
+        while let Some(left) = wait.checked_sub(started.elapsed()).filter(|left| !left.is_zero()) {
+            socket.set_read_timeout(Some(left))?;
+            match (&socket).read(&mut buf) {
+                Ok(n) if echo_reply_seq(&buf[..n], raw.then_some(id)) == Some(seq) => {
+                    return Ok(Some(started.elapsed()))
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+                // An ICMP error for our echo, e.g. host unreachable from a router
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable) => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn echo_request(id: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![8, 0, 0, 0];
+    packet.extend(id.to_be_bytes());
+    packet.extend(seq.to_be_bytes());
+    packet.extend(b"network-mapper");
+    let checksum = internet_checksum(&packet);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+// RFC 1071: ones' complement of the ones' complement sum of 16-bit words
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)])))
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// The sequence number of an echo reply. `raw_id` is the id a raw socket must check
+// itself; a ping socket rewrites the id and only delivers its own replies
+fn echo_reply_seq(packet: &[u8], raw_id: Option<u16>) -> Option<u16> {
+    let icmp = match raw_id {
+        Some(_) => packet.get(usize::from(*packet.first()? & 0x0f) * 4..)?,
+        None => packet,
+    };
+    if *icmp.first()? != 0 {
+        return None;
+    }
+    if let Some(id) = raw_id {
+        if icmp.get(4..6)? != id.to_be_bytes() {
+            return None;
+        }
+    }
+    Some(u16::from_be_bytes([*icmp.get(6)?, *icmp.get(7)?]))
+}
+
+// Raw ICMP reads include the IP header; errors quote the IP header and the first
+// 8 bytes of the offending datagram, enough to recover the TCP destination port
+fn parse_icmp_error(packet: &[u8]) -> Option<(Ipv4Addr, u16, IcmpError)> {
+    let ihl = usize::from(*packet.first()? & 0x0f) * 4;
+    let src = packet.get(12..16)?;
+    let icmp = packet.get(ihl..)?;
+    let (icmp_type, code) = (*icmp.first()?, *icmp.get(1)?);
+    if icmp_type != 3 && icmp_type != 11 {
+        return None;
+    }
+
+    let quoted = icmp.get(8..)?;
+    let quoted_ihl = usize::from(*quoted.first()? & 0x0f) * 4;
+    if *quoted.get(9)? != 6 {
+        return None;
+    }
+    let dst = quoted.get(16..20)?;
+    let tcp = quoted.get(quoted_ihl..quoted_ihl + 4)?;
+
+    Some((
+        Ipv4Addr::new(dst[0], dst[1], dst[2], dst[3]),
+        u16::from_be_bytes([tcp[2], tcp[3]]),
+        IcmpError {
+            icmp_type,
+            code,
+            meaning: icmp_meaning(icmp_type, code).to_string(),
+            from: Ipv4Addr::new(src[0], src[1], src[2], src[3]),
+        },
+    ))
+}
+
+fn icmp_meaning(icmp_type: u8, code: u8) -> &'static str {
+    match (icmp_type, code) {
+        (3, 0) => "network unreachable",
+        (3, 1) => "host unreachable",
+        (3, 2) => "protocol unreachable",
+        (3, 3) => "port unreachable",
+        (3, 4) => "fragmentation needed",
+        (3, 9) | (3, 10) | (3, 13) => "administratively prohibited",
+        (3, _) => "destination unreachable",
+        (11, _) => "time exceeded",
+        _ => "unknown",
+    }
+}
+
+// Any answer proves the host is up, including a refusal
+async fn host_answers(ip: IpAddr, ports: &[u16], options: &ScanOptions) -> bool {
+    for &port in ports {
+        let _socket_permit = options.socket_permit().await;
+        if matches!(
+            connect_port(ip, port, options).await,
+            ConnectOutcome::Open(..) | ConnectOutcome::Refused(_)
+        ) {
+            return true;
+        }
+    }
+    false
+}
+
+async fn connect_port(ip: IpAddr, port: u16, options: &ScanOptions) -> ConnectOutcome {
+    connect_port_counted(ip, port, options).await.0
+}
+
+// Also returns how many connects it took
+async fn connect_port_counted(ip: IpAddr, port: u16, options: &ScanOptions) -> (ConnectOutcome, u32) {
+    let mut attempt = 0;
+    loop {
+        options.wait_for_throttle().await;
+        let started = Instant::now();
+        let outcome = match timeout(options.connect_timeout_for(ip), TcpStream::connect((ip, port))).await {
+            Ok(Ok(stream)) => ConnectOutcome::Open(stream, started.elapsed()),
+            Ok(Err(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                ConnectOutcome::Refused(started.elapsed())
+            }
+            Ok(Err(e)) => ConnectOutcome::Error(e),
+            Err(_) => ConnectOutcome::TimedOut,
+        };
+        options.record_for_throttle(&outcome);
+        match &outcome {
+            ConnectOutcome::Open(_, rtt) => tracing::debug!(port, rtt_ms = rtt.as_secs_f64() * 1000.0, "open"),
+            ConnectOutcome::Refused(rtt) => tracing::debug!(port, rtt_ms = rtt.as_secs_f64() * 1000.0, "refused"),
+            ConnectOutcome::TimedOut => {
+                tracing::debug!(port, timeout_ms = options.connect_timeout_for(ip).as_millis(), "timed out")
+            }
+            ConnectOutcome::Error(e) => tracing::debug!(port, error = %e, "connect failed"),
+        }
+
+        if attempt >= options.retries || !outcome.is_retryable() {
+            return (outcome, attempt + 1);
+        }
+        // Back off, so a burst of loss (or a rate limiter) has time to clear
+        let delay = retry_backoff(options.retry_delay, attempt);
+        tracing::trace!(port, retry = attempt + 1, delay_ms = delay.as_millis(), "retrying");
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Doubles from `base` on each retry, up to MAX_RETRY_DELAY
+fn retry_backoff(base: Duration, retry: u32) -> Duration {
+    base.saturating_mul(1 << retry.min(16)).min(MAX_RETRY_DELAY)
+}
+
+async fn probe_service(stream: &mut TcpStream, probe: Option<&[u8]>, read_timeout: Duration) -> Vec<u8> {
+    if let Some(probe) = probe {
+        if stream.write_all(probe).await.is_err() {
+            return Vec::new();
+        }
+    }
+
+    read_banner(stream, read_timeout).await
+}
+
+// A response counts as finished once the service is quiet this long after its first byte
+const RESPONSE_IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+// Stop counting here; anything this big is already an outlier
+const MAX_RESPONSE_BYTES: usize = 1 << 20;
+
+// Like probe_service, but reads the response to its end and times the first byte.
+// The first chunk is returned for banners and verification
+async fn probe_service_measured(
+    stream: &mut TcpStream,
+    probe: Option<&[u8]>,
+    read_timeout: Duration,
+) -> (Vec<u8>, ResponseStats) {
+    if let Some(probe) = probe {
+        if stream.write_all(probe).await.is_err() {
+            return (Vec::new(), ResponseStats { bytes: 0, first_byte_ms: None });
+        }
+    }
+    let sent = Instant::now();
+    let first = read_banner(stream, read_timeout).await;
+    if first.is_empty() {
+        return (first, ResponseStats { bytes: 0, first_byte_ms: None });
+    }
+    let first_byte_ms = Some(sent.elapsed().as_secs_f64() * 1000.0);
+
+    let mut bytes = first.len();
+    let mut buf = vec![0u8; 8192];
+    while bytes < MAX_RESPONSE_BYTES {
+        match timeout(RESPONSE_IDLE_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => bytes += n,
+            _ => break,
+        }
+    }
+    (first, ResponseStats { bytes: bytes.min(MAX_RESPONSE_BYTES), first_byte_ms })
+}
+
+// A fresh connection, since the banner exchange may already have used the first one
+async fn websocket_upgrade(ip: IpAddr, port: u16, options: &ScanOptions) -> bool {
+    let _socket_permit = options.socket_permit().await;
+    let mut stream = match connect_port(ip, port, options).await {
+        ConnectOutcome::Open(stream, _) => stream,
+        _ => return false,
+    };
+
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        http_host(ip),
+        options.user_agent,
+        WEBSOCKET_KEY
+    );
+    let response = probe_service(&mut stream, Some(request.as_bytes()), options.read_timeout_for(port)).await;
+    response.starts_with(b"HTTP/1.1 101")
+}
+
+// An address as the authority of an HTTP request or URL
+fn http_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
+// GETs `/`, following redirects that stay on this port. A redirect whose target
+// cannot be fetched is kept as the answer; None when nothing here speaks HTTP
+async fn fetch_http_page(ip: IpAddr, port: u16, tls: bool, options: &ScanOptions) -> Option<HttpPage> {
+    let mut visited = vec!["/".to_string()];
+    let mut page = None;
+    for _ in 0..=MAX_HTTP_REDIRECTS {
+        let path = visited.last().cloned().unwrap_or_default();
+        let Some(next) = http_get(ip, port, tls, &path, options).await.and_then(|response| parse_http_page(&response))
+        else {
+            break;
+        };
+        let target = next.location.as_deref().and_then(|location| same_port_path(location, &path, ip, port, tls));
+        page = Some(next);
+        match target {
+            Some(target) if !visited.contains(&target) => visited.push(target),
+            _ => break,
+        }
+    }
+    let mut page = page?;
+    // Followed all the way: the page itself is the answer, not how we got there
+    if !(300..400).contains(&page.status) {
+        page.location = None;
+    }
+    Some(page)
+}
+
+// One GET on a fresh connection, over TLS on TLS ports. Certificates are not
+// checked: the point is to see what is served, and self-signed is the norm
+async fn http_get(ip: IpAddr, port: u16, tls: bool, path: &str, options: &ScanOptions) -> Option<Vec<u8>> {
+    let _socket_permit = options.socket_permit().await;
+    let stream = match connect_port(ip, port, options).await {
+        ConnectOutcome::Open(stream, _) => stream,
+        _ => return None,
+    };
+    let host = http_host(ip);
+    // HTTP/1.0 with Connection: close, so the body is neither chunked nor kept open
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: text/html\r\nConnection: close\r\n\r\n",
+        path, host, options.user_agent
+    );
+    let read_timeout = options.read_timeout_for(port);
+    if !tls {
+        return read_http_response(stream, request.as_bytes(), read_timeout).await;
+    }
+    let connector = tokio_native_tls::native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        // An address is not a valid server_name; see validate_server_name
+        .use_sni(false)
+        .build()
+        .ok()?;
+    let connector = tokio_native_tls::TlsConnector::from(connector);
+    let stream = match timeout(read_timeout, connector.connect(&host, stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            tracing::debug!(port, error = %e, "TLS handshake failed");
+            return None;
+        }
+        Err(_) => return None,
+    };
+    read_http_response(stream, request.as_bytes(), read_timeout).await
+}
+
+async fn read_http_response<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+    read_timeout: Duration,
+) -> Option<Vec<u8>> {
+    stream.write_all(request).await.ok()?;
+    let mut response = Vec::new();
+    let mut buf = vec![0u8; 8192];
+    while response.len() < MAX_HTTP_PAGE_BYTES {
+        match timeout(read_timeout, stream.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => response.extend_from_slice(&buf[..n]),
+            _ => break,
+        }
+    }
+    (!response.is_empty()).then_some(response)
+}
+
+// Status, Server header, <title> and, for a 3xx, the Location. None for an answer
+// that is not HTTP at all; a page without headers or title still counts
+fn parse_http_page(response: &[u8]) -> Option<HttpPage> {
+    let text = String::from_utf8_lossy(response);
+    let (head, body) = text.split_once("\r\n\r\n").or_else(|| text.split_once("\n\n")).unwrap_or((&text, ""));
+    let mut lines = head.lines();
+    let status: u16 = lines.next()?.strip_prefix("HTTP/")?.split_whitespace().nth(1)?.parse().ok()?;
+    let mut page = HttpPage { status, title: html_title(body), server: None, location: None };
+    for header in lines {
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = printable(value.trim());
+        if value.is_empty() {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("server") {
+            page.server = Some(value);
+        } else if name.eq_ignore_ascii_case("location") && (300..400).contains(&status) {
+            page.location = Some(value);
+        }
+    }
+    Some(page)
+}
+
+fn html_title(body: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets, so positions carry over to `body`
+    let lower = body.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title").unwrap_or(lower.len() - start);
+    let title = body[start..end]
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    let title = printable(&title.split_whitespace().collect::<Vec<_>>().join(" "));
+    (!title.is_empty()).then(|| title.chars().take(MAX_TITLE_CHARS).collect())
+}
+
+fn printable(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect()
+}
+
+// The path to request next when a redirect stays on this scheme, address and
+// port; None for one that leaves it, which is recorded rather than followed
+fn same_port_path(location: &str, current: &str, ip: IpAddr, port: u16, tls: bool) -> Option<String> {
+    let (scheme, default_port) = if tls { ("https://", 443) } else { ("http://", 80) };
+    if location.starts_with("//") {
+        return same_port_path(&format!("{}{}", &scheme[..scheme.len() - 2], location), current, ip, port, tls);
+    }
+    if let Some(rest) = location.get(..scheme.len()).filter(|prefix| prefix.eq_ignore_ascii_case(scheme)) {
+        let rest = &location[rest.len()..];
+        let (authority, path) = rest.find('/').map_or((rest, "/"), |at| (&rest[..at], &rest[at..]));
+        let host = http_host(ip);
+        let same = authority == host
+            || authority == format!("{}:{}", host, port)
+            || (port == default_port && authority == format!("{}:{}", host, default_port));
+        return same.then(|| path.to_string());
+    }
+    if location.contains("://") {
+        return None;
+    }
+    if location.starts_with('/') {
+        return Some(location.to_string());
+    }
+    // Relative to the directory of the page that redirected
+    let dir = current.rfind('/').map_or("/", |at| &current[..=at]);
+    Some(format!("{}{}", dir, location))
+}
+
+// RFC 6066 allows only a DNS host name in server_name, never an address
+fn validate_server_name(name: &str) -> Result<(), &'static str> {
+    if name.parse::<IpAddr>().is_ok() {
+        return Err("must be a host name, not an IP address");
+    }
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len()) && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    };
+    if name.len() > 253 || !name.split('.').all(valid_label) {
+        return Err("is not a valid host name");
+    }
+    Ok(())
+}
+
+// Handshake messages (type, body) the server sends in reply to our ClientHello, up
+// to its Certificate. A TLS 1.2 server sends these in the clear, so the certificate
+// is readable without finishing the handshake or trusting anything
+async fn tls_server_flight(
+    ip: IpAddr,
+    port: u16,
+    client_hello: &[u8],
+    options: &ScanOptions,
+) -> Option<Vec<(u8, Vec<u8>)>> {
+    // Far more than any certificate chain worth looking at
+    const MAX_FLIGHT: usize = 64 * 1024;
+    const HANDSHAKE: u8 = 22;
+    const CERTIFICATE: u8 = 11;
+    const SERVER_HELLO_DONE: u8 = 14;
+
+    let _socket_permit = options.socket_permit().await;
+    let mut stream = match connect_port(ip, port, options).await {
+        ConnectOutcome::Open(stream, _) => stream,
+        _ => return None,
+    };
+    stream.write_all(client_hello).await.ok()?;
+
+    let mut messages = Vec::new();
+    let read = async {
+        let mut handshake = Vec::new();
+        loop {
+            let mut header = [0u8; 5];
+            if stream.read_exact(&mut header).await.is_err() {
+                return;
+            }
+            let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+            let mut fragment = vec![0u8; len];
+            if header[0] != HANDSHAKE || stream.read_exact(&mut fragment).await.is_err() {
+                return;
+            }
+            handshake.extend(fragment);
+
+            // Messages can span records, so only take the complete ones
+            while handshake.len() >= 4 {
+                let body_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+                if handshake.len() < 4 + body_len {
+                    break;
+                }
+                let kind = handshake[0];
+                let body = handshake[4..4 + body_len].to_vec();
+                handshake.drain(..4 + body_len);
+                messages.push((kind, body));
+                if kind == CERTIFICATE || kind == SERVER_HELLO_DONE {
+                    return;
+                }
+            }
+            if handshake.len() > MAX_FLIGHT {
+                return;
+            }
+        }
+    };
+    let _ = timeout(options.read_timeout_for(port), read).await;
+
+    (!messages.is_empty()).then_some(messages)
+}
+
+// The first entry in a Certificate message is the server's own certificate
+fn parse_leaf_certificate(body: &[u8], ip: IpAddr) -> Option<TlsCertificate> {
+    use sha2::Digest;
+    use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+    let first_len = u32::from_be_bytes([0, *body.get(3)?, *body.get(4)?, *body.get(5)?]) as usize;
+    let der = body.get(6..6 + first_len)?;
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let not_before = cert.validity().not_before.timestamp();
+    let not_after = cert.validity().not_after.timestamp();
+    let subject = cert.subject().to_string();
+    let issuer = cert.issuer().to_string();
+
+    // The checks a client fails on whatever its trust store; we never verify the chain
+    let mut validation_errors = Vec::new();
+    if now > not_after {
+        validation_errors.push("expired".to_string());
+    }
+    if now < not_before {
+        validation_errors.push("not yet valid".to_string());
+    }
+    if subject == issuer {
+        validation_errors.push("self-signed".to_string());
+    }
+    let names_ip = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san.value.general_names.iter().any(|name| match name {
+            GeneralName::IPAddress(addr) => match ip {
+                IpAddr::V4(ip) => *addr == ip.octets(),
+                IpAddr::V6(ip) => *addr == ip.octets(),
+            },
+            _ => false,
+        }),
+        _ => false,
+    };
+    if !names_ip {
+        validation_errors.push(format!("{} not among subject alternative names", ip));
+    }
+
+    Some(TlsCertificate {
+        subject,
+        issuer,
+        not_before,
+        not_after,
+        sha256: sha2::Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect(),
+        validation_errors,
+    })
+}
+
+// SSLVersion,Cipher,Extensions from a ServerHello body
+fn parse_server_hello(hello: &[u8]) -> Option<String> {
+    let version = u16::from_be_bytes([*hello.first()?, *hello.get(1)?]);
+    let session_id_len = usize::from(*hello.get(34)?);
+    let rest = hello.get(35 + session_id_len..)?;
+    let cipher = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+
+    let mut extensions = Vec::new();
+    if let Some(len) = rest.get(3..5) {
+        let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+        let mut data = rest.get(5..5 + len)?;
+        while data.len() >= 4 {
+            extensions.push(u16::from_be_bytes([data[0], data[1]]).to_string());
+            let ext_len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+            data = data.get(4 + ext_len..)?;
+        }
+    }
+
+    Some(format!("{},{},{}", version, cipher, extensions.join("-")))
+}
+
+// Runs the key exchange just far enough for the server to send its host key in
+// SSH_MSG_KEX_ECDH_REPLY; we never compute the shared secret or authenticate
+async fn fetch_ssh_host_key(ip: IpAddr, port: u16, options: &ScanOptions) -> Option<SshHostKey> {
+    const MSG_KEXINIT: u8 = 20;
+    const MSG_KEX_ECDH_INIT: u8 = 30;
+    const MSG_KEX_ECDH_REPLY: u8 = 31;
+
+    let _socket_permit = options.socket_permit().await;
+    let stream = match connect_port(ip, port, options).await {
+        ConnectOutcome::Open(stream, _) => stream,
+        _ => return None,
+    };
+    let mut stream = BufReader::new(stream);
+
+    let exchange = async {
+        stream.write_all(b"SSH-2.0-NetworkMapper\r\n").await.ok()?;
+
+        // Servers may send other lines before their identification string
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if stream.read_until(b'\n', &mut line).await.ok()? == 0 || line.len() > 255 {
+                return None;
+            }
+            if line.starts_with(b"SSH-") {
+                break;
+            }
+        }
+
+        let name_lists = [
+            "curve25519-sha256,curve25519-sha256@libssh.org",
+            "ssh-ed25519,ecdsa-sha2-nistp256,ecdsa-sha2-nistp384,rsa-sha2-512,rsa-sha2-256,ssh-rsa",
+            "aes128-ctr,aes256-ctr,chacha20-poly1305@openssh.com",
+            "aes128-ctr,aes256-ctr,chacha20-poly1305@openssh.com",
+            "hmac-sha2-256,hmac-sha1",
+            "hmac-sha2-256,hmac-sha1",
+            "none",
+            "none",
+            "",
+            "",
+        ];
+        let mut kexinit = vec![MSG_KEXINIT];
+        kexinit.extend([0u8; 16]);
+        for list in name_lists {
+            kexinit.extend(ssh_string(list.as_bytes()));
+        }
+        kexinit.extend([0, 0, 0, 0, 0]);
+        stream.write_all(&ssh_packet(&kexinit)).await.ok()?;
+
+        while ssh_read_packet(&mut stream).await?.first() != Some(&MSG_KEXINIT) {}
+
+        // The Curve25519 base point is a valid public key and saves generating one
+        let mut ecdh_init = vec![MSG_KEX_ECDH_INIT];
+        let mut client_key = [0u8; 32];
+        client_key[0] = 9;
+        ecdh_init.extend(ssh_string(&client_key));
+        stream.write_all(&ssh_packet(&ecdh_init)).await.ok()?;
+
+        loop {
+            let payload = ssh_read_packet(&mut stream).await?;
+            if payload.first() == Some(&MSG_KEX_ECDH_REPLY) {
+                return ssh_take_string(&payload[1..]).map(|(blob, _)| blob.to_vec());
+            }
+        }
+    };
+    let blob = timeout(options.read_timeout_for(port), exchange).await.ok()??;
+
+    use base64::Engine;
+    use sha2::Digest;
+    let (key_type, _) = ssh_take_string(&blob)?;
+    Some(SshHostKey {
+        port,
+        key_type: String::from_utf8_lossy(key_type).into_owned(),
+        fingerprint: format!(
+            "SHA256:{}",
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(sha2::Sha256::digest(&blob))
+        ),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum RdpNegotiation {
+    Selected(u32),
+    // failureCode, e.g. 5 for HYBRID_REQUIRED_BY_SERVER
+    Failed(u32),
+}
+
+// Returns (NLA required, protocol picked when offered everything); (None, None) if
+// whatever listens is not RDP. NLA is required exactly when the server turns down a
+// client asking for standard RDP security and one asking for plain TLS
+async fn probe_rdp_security(ip: IpAddr, port: u16, options: &ScanOptions) -> (Option<bool>, Option<String>) {
+    let offer_all = RDP_PROTOCOL_SSL | RDP_PROTOCOL_HYBRID | RDP_PROTOCOL_HYBRID_EX;
+    let Some(best) = rdp_negotiate(ip, port, offer_all, options).await else {
+        return (None, None);
+    };
+    let security = match best {
+        RdpNegotiation::Selected(protocol) => rdp_protocol_name(protocol).to_string(),
+        RdpNegotiation::Failed(code) => format!("refused ({})", code),
+    };
+
+    let mut without_nla_allowed = false;
+    for requested in [RDP_PROTOCOL_RDP, RDP_PROTOCOL_SSL] {
+        match rdp_negotiate(ip, port, requested, options).await {
+            Some(RdpNegotiation::Selected(_)) => without_nla_allowed = true,
+            Some(RdpNegotiation::Failed(_)) => {}
+            // Could not tell; claim nothing rather than a false "required"
+            None => return (None, Some(security)),
+        }
+    }
+    (Some(!without_nla_allowed), Some(security))
+}
+
+fn rdp_protocol_name(protocol: u32) -> &'static str {
+    match protocol {
+        RDP_PROTOCOL_RDP => "rdp",
+        RDP_PROTOCOL_SSL => "ssl",
+        RDP_PROTOCOL_HYBRID => "hybrid",
+        RDP_PROTOCOL_RDSTLS => "rdstls",
+        RDP_PROTOCOL_HYBRID_EX => "hybrid_ex",
+        _ => "unknown",
+    }
+}
+
+// One X.224 Connection Request carrying an RDP Negotiation Request, on a fresh connection
+async fn rdp_negotiate(ip: IpAddr, port: u16, requested: u32, options: &ScanOptions) -> Option<RdpNegotiation> {
+    let _socket_permit = options.socket_permit().await;
+    let mut stream = match connect_port(ip, port, options).await {
+        ConnectOutcome::Open(stream, _) => stream,
+        _ => return None,
+    };
+
+    // TPKT header, X.224 CR (length indicator, code, dst-ref, src-ref, class), RDP_NEG_REQ
+    let mut request = vec![0x03, 0x00, 0x00, 0x13, 0x0e, 0xe0, 0, 0, 0, 0, 0];
+    request.extend([0x01, 0x00, 0x08, 0x00]);
+    request.extend(requested.to_le_bytes());
+
+    let exchange = async {
+        stream.write_all(&request).await.ok()?;
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await.ok()?;
+        if header[..2] != [0x03, 0x00] {
+            return None;
+        }
+        let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        let mut body = vec![0u8; len.checked_sub(4)?];
+        stream.read_exact(&mut body).await.ok()?;
+        parse_rdp_confirm(&body)
+    };
+    timeout(options.read_timeout_for(port), exchange).await.ok()?
+}
+
+// The X.224 Connection Confirm after its TPKT header
+fn parse_rdp_confirm(body: &[u8]) -> Option<RdpNegotiation> {
+    // Length indicator, then the CC code in the high nibble
+    if body.len() < 7 || body[1] & 0xf0 != 0xd0 {
+        return None;
+    }
+    let le_u32 = |at: usize| Some(u32::from_le_bytes(body.get(at..at + 4)?.try_into().ok()?));
+    match body.get(7) {
+        // Servers from before negotiation existed only speak standard RDP security
+        None => Some(RdpNegotiation::Selected(RDP_PROTOCOL_RDP)),
+        Some(0x02) => le_u32(11).map(RdpNegotiation::Selected),
+        Some(0x03) => le_u32(11).map(RdpNegotiation::Failed),
+        Some(_) => None,
+    }
+}
+
+fn ssh_string(data: &[u8]) -> Vec<u8> {
+    let mut out = (data.len() as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(data);
+    out
+}
+
+fn ssh_take_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let value = data.get(4..4 + len)?;
+    Some((value, &data[4 + len..]))
+}
+
+// Unencrypted binary packet: length, padding length, payload, padding to a multiple of 8
+fn ssh_packet(payload: &[u8]) -> Vec<u8> {
+    let mut padding = 8 - (5 + payload.len()) % 8;
+    if padding < 4 {
+        padding += 8;
+    }
+    let mut packet = ((1 + payload.len() + padding) as u32).to_be_bytes().to_vec();
+    packet.push(padding as u8);
+    packet.extend_from_slice(payload);
+    packet.resize(packet.len() + padding, 0);
+    packet
+}
+
+async fn ssh_read_packet(stream: &mut BufReader<TcpStream>) -> Option<Vec<u8>> {
+    // Larger than any key exchange message; anything bigger is not SSH
+    const MAX_PACKET: usize = 35_000;
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await.ok()?;
+    let len = u32::from_be_bytes(len) as usize;
+    if !(5..=MAX_PACKET).contains(&len) {
+        return None;
+    }
+    let mut packet = vec![0u8; len];
+    stream.read_exact(&mut packet).await.ok()?;
+    let padding = usize::from(packet[0]);
+    packet.get(1..len.checked_sub(padding)?).map(|payload| payload.to_vec())
+}
+
+// Queries sent from an ephemeral port are "legacy unicast" (RFC 6762 6.7): responders
+// answer straight back to us, so no multicast membership is needed
+async fn discover_mdns(window: Duration) -> std::io::Result<HashMap<Ipv4Addr, Vec<MdnsService>>> {
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let mut browsed = vec![MDNS_SERVICE_ENUMERATION.to_string()];
+    socket.send_to(&mdns_query(&browsed), (MDNS_GROUP, MDNS_PORT)).await?;
+
+    let deadline = Instant::now() + window;
+    let mut records = Vec::new();
+    let mut buf = vec![0u8; 9000];
+    while let Ok(Ok((len, _))) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let Some(answer) = parse_dns_records(&buf[..len]) else {
+            continue;
+        };
+        // Browse each newly enumerated service type once
+        let mut new_types = Vec::new();
+        for record in &answer {
+            if let DnsRecord::Ptr { name, target } = record {
+                let target = target.to_ascii_lowercase();
+                if name.eq_ignore_ascii_case(MDNS_SERVICE_ENUMERATION)
+                    && !browsed.contains(&target)
+                    && !new_types.contains(&target)
+                    && browsed.len() + new_types.len() <= MAX_MDNS_SERVICE_TYPES
+                {
+                    new_types.push(target);
+                }
+            }
+        }
+        if !new_types.is_empty() {
+            socket.send_to(&mdns_query(&new_types), (MDNS_GROUP, MDNS_PORT)).await?;
+            browsed.extend(new_types);
+        }
+        records.extend(answer);
+    }
+    Ok(mdns_services(&records))
+}
+
+// Joins SRV records to the A records of their targets
+fn mdns_services(records: &[DnsRecord]) -> HashMap<Ipv4Addr, Vec<MdnsService>> {
+    let mut addresses: HashMap<String, Vec<Ipv4Addr>> = HashMap::new();
+    for record in records {
+        if let DnsRecord::A { name, addr } = record {
+            addresses.entry(name.to_ascii_lowercase()).or_default().push(*addr);
+        }
+    }
+
+    let mut services: HashMap<Ipv4Addr, Vec<MdnsService>> = HashMap::new();
+    for record in records {
+        let DnsRecord::Srv { name, target, port } = record else {
+            continue;
+        };
+        let Some((instance, service)) = name.split_once('.') else {
+            continue;
+        };
+        let service = MdnsService {
+            name: instance.to_string(),
+            service: service.trim_end_matches(".local").to_string(),
+            port: *port,
+        };
+        for addr in addresses.get(&target.to_ascii_lowercase()).into_iter().flatten() {
+            let advertised = services.entry(*addr).or_default();
+            if !advertised.contains(&service) {
+                advertised.push(service.clone());
+            }
+        }
+    }
+    services
+}
+
+fn dns_encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+// PTR questions, one per name
+fn mdns_query(names: &[String]) -> Vec<u8> {
+    // ID 0, standard query
+    dns_ptr_query(0, 0, names)
+}
+
+fn dns_ptr_query(id: u16, flags: u16, names: &[String]) -> Vec<u8> {
+    let mut packet = id.to_be_bytes().to_vec();
+    packet.extend(flags.to_be_bytes());
+    packet.extend((names.len() as u16).to_be_bytes());
+    packet.extend([0u8; 6]);
+    for name in names {
+        dns_encode_name(name, &mut packet);
+        packet.extend(DNS_TYPE_PTR.to_be_bytes());
+        // IN
+        packet.extend(1u16.to_be_bytes());
+    }
+    packet
+}
+
+// The first nameserver in resolv.conf
+fn system_resolver() -> Option<std::net::SocketAddr> {
+    let contents = std::fs::read_to_string(RESOLV_CONF).ok()?;
+    contents.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            return None;
+        }
+        // Scoped IPv6 addresses such as fe80::1%eth0 are skipped
+        let ip: std::net::IpAddr = words.next()?.parse().ok()?;
+        Some((ip, 53).into())
+    })
+}
+
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        // One label per nibble, least significant first
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<String> = (0..32).map(|i| format!("{:x}", (u128::from(ip) >> (i * 4)) & 0xf)).collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+// Any local address of the same family, for a socket that will talk to `peer`
+fn unspecified_like(peer: std::net::SocketAddr) -> std::net::SocketAddr {
+    match peer {
+        std::net::SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        std::net::SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+// One recursive PTR query; None on NXDOMAIN, timeout or anything unexpected
+async fn reverse_dns(resolver: std::net::SocketAddr, ip: IpAddr, wait: Duration) -> Option<String> {
+    const RECURSION_DESIRED: u16 = 0x0100;
+
+    let socket = tokio::net::UdpSocket::bind(unspecified_like(resolver)).await.ok()?;
+    socket.connect(resolver).await.ok()?;
+    let name = reverse_name(ip);
+    let random = Uuid::new_v4();
+    let id = u16::from_be_bytes([random.as_bytes()[0], random.as_bytes()[1]]);
+    socket.send(&dns_ptr_query(id, RECURSION_DESIRED, std::slice::from_ref(&name))).await.ok()?;
+
+    let answer = async {
+        let mut buf = [0u8; 1500];
+        loop {
+            let len = socket.recv(&mut buf).await.ok()?;
+            // Stray or spoofed datagrams carry some other ID
+            if buf[..len].starts_with(&id.to_be_bytes()) {
+                return parse_dns_records(&buf[..len]);
+            }
+        }
+    };
+    let records = timeout(wait, answer).await.ok()??;
+    records.into_iter().find_map(|record| match record {
+        DnsRecord::Ptr { name: owner, target } if owner.eq_ignore_ascii_case(&name) && !target.is_empty() => {
+            Some(target.trim_end_matches('.').to_string())
+        }
+        _ => None,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum DnsRecord {
+    Ptr { name: String, target: String },
+    Srv { name: String, target: String, port: u16 },
+    A { name: String, addr: Ipv4Addr },
+}
+
+// Every record of a response, from the answer, authority and additional sections;
+// None if it is a query or is cut short
+fn parse_dns_records(packet: &[u8]) -> Option<Vec<DnsRecord>> {
+    let count = |at: usize| Some(usize::from(u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?)));
+    if packet.get(2)? & 0x80 == 0 {
+        return None;
+    }
+    let mut pos = 12;
+    for _ in 0..count(4)? {
+        pos = dns_read_name(packet, pos)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..count(6)? + count(8)? + count(10)? {
+        let (name, next) = dns_read_name(packet, pos)?;
+        let header = packet.get(next..next + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let start = next + 10;
+        let rdata = packet.get(start..start + usize::from(u16::from_be_bytes([header[8], header[9]])))?;
+        match rtype {
+            DNS_TYPE_PTR => records.push(DnsRecord::Ptr { name, target: dns_read_name(packet, start)?.0 }),
+            DNS_TYPE_SRV if rdata.len() > 6 => records.push(DnsRecord::Srv {
+                name,
+                port: u16::from_be_bytes([rdata[4], rdata[5]]),
+                target: dns_read_name(packet, start + 6)?.0,
+            }),
+            DNS_TYPE_A if rdata.len() == 4 => {
+                records.push(DnsRecord::A { name, addr: Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]) })
+            }
+            _ => {}
+        }
+        pos = start + rdata.len();
+    }
+    Some(records)
+}
+
+// A possibly compressed name at `pos`, and the offset just past it in place
+fn dns_read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    // More jumps than a 255-byte name could need means a pointer loop
+    const MAX_JUMPS: usize = 128;
+
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_JUMPS {
+        let len = usize::from(*packet.get(pos)?);
+        match len {
+            0 => return Some((labels.join("."), end.unwrap_or(pos + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                end.get_or_insert(pos + 2);
+                pos = (len & 0x3f) << 8 | usize::from(*packet.get(pos + 1)?);
+            }
+            len if len < 64 => {
+                labels.push(String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + len)?).into_owned());
+                pos += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+// A 3xx or 511 status line, with the Location header if any; None for any other
+// answer, including one that is not HTTP
+fn http_redirect(response: &[u8]) -> Option<HttpRedirect> {
+    let text = String::from_utf8_lossy(response);
+    let mut lines = text.lines();
+    let status: u16 = lines.next()?.strip_prefix("HTTP/")?.split_whitespace().nth(1)?.parse().ok()?;
+    if !(300..400).contains(&status) && status != 511 {
+        return None;
+    }
+    let location = lines.take_while(|header| !header.is_empty()).find_map(|header| {
+        let (name, value) = header.split_once(':')?;
+        name.eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+    });
+    Some(HttpRedirect { status, location })
+}
+
+// Banners end up in every report format, so keep them to one printable line
+fn banner_line(response: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(response);
+    let mut lines = text.lines();
+    let mut line = lines.next()?.to_string();
+    // An HTTP status line names no software; the Server header does
+    if line.starts_with("HTTP/") {
+        let server = lines.take_while(|header| !header.is_empty()).find_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            name.eq_ignore_ascii_case("server").then(|| value.trim())
+        });
+        if let Some(server) = server {
+            line = format!("{} ({})", line, server);
+        }
+    }
+    let line: String = line
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    let line = line.trim();
+    (!line.is_empty()).then(|| line.to_string())
+}
+
+async fn read_banner(stream: &mut TcpStream, read_timeout: Duration) -> Vec<u8> {
+    let mut buf = vec![0u8; 512];
+    match timeout(read_timeout, stream.read(&mut buf)).await {
+        Ok(Ok(n)) => {
+            buf.truncate(n);
+            buf
+        }
+        _ => Vec::new(),
+    }
+}
+
+type SinkWriter = Box<dyn tokio::io::AsyncWrite + Unpin + Send>;
+
+async fn run_sink(path: String, mut lines: mpsc::UnboundedReceiver<String>) {
+    let mut sink = match open_sink(&path).await {
+        Ok(sink) => sink,
+        Err(e) => {
+            warning!("sink {} unavailable, streamed results dropped: {}", path, e);
+            return;
+        }
+    };
+
+    while let Some(line) = lines.recv().await {
+        if let Err(e) = sink.write_all(format!("{}\n", line).as_bytes()).await {
+            warning!("sink {} closed by reader: {}", path, e);
+            return;
+        }
+    }
+
+    // Dropping a buffered async writer discards what it still holds
+    if let Err(e) = sink.shutdown().await {
+        warning!("sink {} not fully written: {}", path, e);
+    }
+}
+
+// A FIFO is written directly; anything else is treated as a regular NDJSON file
+#[cfg(unix)]
+async fn open_sink(path: &str) -> std::io::Result<SinkWriter> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_fifo() => Ok(Box::new(open_fifo(path).await?)),
+        _ => Ok(Box::new(tokio::io::BufWriter::new(tokio::fs::File::create(path).await?))),
+    }
+}
+
+#[cfg(not(unix))]
+async fn open_sink(path: &str) -> std::io::Result<SinkWriter> {
+    Ok(Box::new(tokio::io::BufWriter::new(tokio::fs::File::create(path).await?)))
+}
+
+// Opening a FIFO for writing fails with ENXIO until a reader is attached, so poll
+// instead of blocking in open(2) and let the scan continue if nobody shows up.
+#[cfg(unix)]
+async fn open_fifo(path: &str) -> std::io::Result<tokio::net::unix::pipe::Sender> {
+    const ENXIO: i32 = 6;
+    let deadline = Instant::now() + SINK_OPEN_TIMEOUT;
+    loop {
+        match tokio::net::unix::pipe::OpenOptions::new().open_sender(path) {
+            Err(e) if e.raw_os_error() == Some(ENXIO) && Instant::now() < deadline => {
+                sleep(Duration::from_millis(100)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(unix)]
+type ControlListener = tokio::net::UnixListener;
+#[cfg(not(unix))]
+type ControlListener = String;
+
+#[cfg(unix)]
+fn bind_control_socket(path: &str) -> Result<ControlListener, MapperError> {
+    // A stale socket file from a previous run would make bind fail
+    remove_stale_socket(path)?;
+    tokio::net::UnixListener::bind(path)
+        .map_err(|e| MapperError::Parse(format!("--control-socket {}: {}", path, e)))
+}
+
+#[cfg(not(unix))]
+fn bind_control_socket(path: &str) -> Result<ControlListener, MapperError> {
+    Ok(path.to_string())
+}
+
+// The path comes from the command line, so only ever unlink an actual socket
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> Result<(), MapperError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => Ok(std::fs::remove_file(path)?),
+        Ok(_) => Err(MapperError::Parse(format!(
+            "--control-socket {} exists and is not a socket, refusing to replace it",
+            path
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(MapperError::Parse(format!("--control-socket {}: {}", path, e))),
+    }
+}
+
+#[cfg(unix)]
+async fn run_control_socket(listener: ControlListener, progress: Arc<ScanProgress>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let progress = Arc::clone(&progress);
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = progress.handle_command(line.trim());
+                if writer.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+// Runs the command through the shell with the report paths as $1, $2, ... and the
+// host array on stdin. The caller logs the outcome; a failing hook never fails the scan
+async fn run_on_complete(command: &str, report_paths: &[String], hosts: String) -> std::io::Result<std::process::Output> {
+    use std::process::Stdio;
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = tokio::process::Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = tokio::process::Command::new("sh");
+        // The first argument after the script is $0
+        shell.arg("-c").arg(command).arg("network-mapper");
+        shell
+    };
+    let mut child = shell
+        .args(report_paths)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Fed alongside the wait so a hook that writes before reading cannot deadlock;
+    // one that never reads stdin just gets a broken pipe here
+    let mut stdin = child.stdin.take();
+    let feed = async move {
+        if let Some(stdin) = &mut stdin {
+            let _ = stdin.write_all(hosts.as_bytes()).await;
+        }
+    };
+    let (_, output) = tokio::join!(feed, child.wait_with_output());
+    output
+}
+
+type LiveResults = std::sync::RwLock<Vec<ScanResult>>;
+
+async fn start_server(
+    args: &Args,
+    live_results: &Arc<LiveResults>,
+) -> Result<Option<tokio::task::JoinHandle<()>>, MapperError> {
+    let Some(addr) = args.serve else {
+        return Ok(None);
+    };
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| MapperError::Parse(format!("--serve {}: {}", addr, e)))?;
+    eprintln!("serving on http://{}", addr);
+    Ok(Some(tokio::spawn(run_server(
+        listener,
+        Arc::clone(live_results),
+        args.collapse_subnets,
+        args.merge_dual_stack,
+        args.json_case,
+    ))))
+}
+
+// Requests never need more than a request line and a few headers
+const MAX_REQUEST_HEAD: usize = 8192;
+
+// One request per connection, answered and closed
+async fn run_server(
+    listener: tokio::net::TcpListener,
+    results: Arc<LiveResults>,
+    collapse_prefix: Option<u8>,
+    merge_dual_stack_nodes: bool,
+    case: JsonCase,
+) {
+    while let Ok((mut stream, _)) = listener.accept().await {
+        let results = Arc::clone(&results);
+        tokio::spawn(async move {
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+                match timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                    Ok(Ok(n)) if n > 0 => head.extend_from_slice(&buf[..n]),
+                    _ => return,
+                }
+            }
+            let head = String::from_utf8_lossy(&head);
+            let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+            let (method, target) = (request_line.next().unwrap_or(""), request_line.next().unwrap_or(""));
+
+            let (status, content_type, body) = if method != "GET" {
+                json_error(405, "only GET is supported")
+            } else if target == "/" {
+                let results = results.read().unwrap();
+                let rendered = if merge_dual_stack_nodes {
+                    render_visualization(&merge_dual_stack(&results), collapse_prefix)
+                } else {
+                    render_visualization(&results, collapse_prefix)
+                };
+                match rendered {
+                    Ok(html) => (200, "text/html; charset=utf-8", html),
+                    Err(e) => json_error(500, &e.to_string()),
+                }
+            } else {
+                api_response(target, &results.read().unwrap(), case)
+            };
+
+            let reason = match status {
+                200 => "OK",
+                404 => "Not Found",
+                405 => "Method Not Allowed",
+                _ => "Internal Server Error",
+            };
+            let response = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                reason,
+                content_type,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+fn json_error(status: u16, message: &str) -> (u16, &'static str, String) {
+    (status, "application/json", json!({ "error": message }).to_string())
+}
+
+// GET /api/hosts, /api/hosts/{ip} and /api/subnets
+fn api_response(target: &str, results: &[ScanResult], case: JsonCase) -> (u16, &'static str, String) {
+    let path = target.split('?').next().unwrap_or("").trim_end_matches('/');
+    let body = match path.split('/').collect::<Vec<_>>().as_slice() {
+        ["", "api", "hosts"] => to_json_string(&results, case),
+        ["", "api", "hosts", ip] => match results.iter().find(|result| result.ip == *ip) {
+            Some(result) => to_json_string(result, case),
+            None => return json_error(404, &format!("no live host {}", ip)),
+        },
+        ["", "api", "subnets"] => {
+            let mut subnets: std::collections::BTreeMap<&str, Vec<&str>> = std::collections::BTreeMap::new();
+            for result in results {
+                subnets.entry(&result.subnet).or_default().push(&result.ip);
+            }
+            let subnets: Vec<_> = subnets
+                .into_iter()
+                .map(|(subnet, hosts)| json!({ "subnet": subnet, "host_count": hosts.len(), "hosts": hosts }))
+                .collect();
+            to_json_string(&subnets, case)
+        }
+        _ => return json_error(404, &format!("no route {}", path)),
+    };
+    match body {
+        Ok(body) => (200, "application/json", body),
+        Err(e) => json_error(500, &e.to_string()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn run_control_socket(path: ControlListener, _progress: Arc<ScanProgress>) {
+    warning!("control socket {} ignored, Unix sockets are only supported on Unix", path);
+}
+
+// The first Ctrl-C stops the scan as the control socket's "stop" does: nothing new is
+// started, hosts in progress finish, and the reports are written from what is done.
+// A second one quits at once
+async fn stop_on_interrupt(progress: Arc<ScanProgress>) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    progress.handle_command("stop");
+    warning!("interrupted, writing the results so far; press Ctrl-C again to quit without them");
+    if tokio::signal::ctrl_c().await.is_ok() {
+        eprintln!("interrupted again, quitting");
+        std::process::exit(130);
+    }
+}
+
+impl ScanProgress {
+    fn handle_command(&self, command: &str) -> String {
+        match command {
+            "pause" => {
+                self.paused.send_replace(true);
+                "ok paused".to_string()
+            }
+            "resume" => {
+                self.paused.send_replace(false);
+                "ok running".to_string()
+            }
+            "stop" => {
+                self.stopped.store(true, Ordering::Relaxed);
+                self.paused.send_replace(false);
+                "ok stopping".to_string()
+            }
+            "status" => {
+                let state = if self.stopped.load(Ordering::Relaxed) {
+                    "stopping"
+                } else if *self.paused.borrow() {
+                    "paused"
+                } else {
+                    "running"
+                };
+                format!("{} {}", state, self.status_line())
+            }
+            other => format!("error unknown command {:?}, expected pause, resume, status or stop", other),
+        }
+    }
+
+    fn status_line(&self) -> String {
+        format!(
+            "{}/{} hosts probed, {} live",
+            self.probed.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+            self.live.load(Ordering::Relaxed)
+        )
+    }
+}
+
+// Prints progress to stderr every second until aborted; stdout stays clean for the JSON
+async fn report_progress(progress: Arc<ScanProgress>, overwrite: bool) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    // The first tick is immediate and there is nothing to report yet
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        if overwrite {
+            // Erase whatever is left of the previous, possibly longer, line
+            eprint!("\r{}\x1b[K", paint(progress.status_line(), Style::Dim));
+        } else {
+            eprintln!("{}", paint(progress.status_line(), Style::Dim));
+        }
+    }
+}
+
+/// What [`guess_os`] makes of a host's open ports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OsGuess {
+    /// e.g. "Windows", "Linux/Unix", or "Unknown"
+    pub os: &'static str,
+    /// From 0.0, nothing to go on, to 1.0; how strongly the ports point at `os`
+    pub confidence: f64,
+}
+
+/// OS family suggested by which ports are open, or "Unknown" with no confidence.
+pub fn guess_os(open_ports: &[u16]) -> OsGuess {
+    let has = |port: u16| open_ports.contains(&port);
+    let guess = |os, confidence| OsGuess { os, confidence };
+    // Windows RPC, NetBIOS, SMB and RDP; Samba offers the middle two on other systems too
+    let windows_signals = [135, 139, 445, 3389].iter().filter(|&&port| has(port)).count();
+    // SSH beside just file sharing is Samba far more often than Windows
+    let samba = has(22) && !has(135) && !has(3389);
+
+    if has(62078) {
+        // lockdownd, the iPhone sync service
+        guess("iOS", 0.9)
+    } else if has(548) {
+        // AFP; Macs often share over SMB as well, so it outranks 139/445
+        guess("macOS", 0.7)
+    } else if windows_signals >= 3 {
+        guess("Windows", 0.9)
+    } else if windows_signals == 2 && !samba {
+        guess("Windows", 0.8)
+    } else if has(135) || has(3389) {
+        guess("Windows", 0.7)
+    } else if has(22) && has(80) {
+        guess("Linux", 0.6)
+    } else if samba && (has(139) || has(445)) {
+        guess("Linux/Unix", 0.6)
+    } else if has(139) || has(445) {
+        guess("Windows", 0.5)
+    } else if has(22) {
+        guess("Linux/Unix", 0.4)
+    } else {
+        guess("Unknown", 0.0)
+    }
+}
+
+fn render_json(report: &impl Serialize, style: JsonStyle, case: JsonCase) -> Result<Option<String>, serde_json::Error> {
+    match (style, case) {
+        (JsonStyle::Off, _) => Ok(None),
+        (JsonStyle::Pretty, JsonCase::Snake) => serde_json::to_string_pretty(report).map(Some),
+        (JsonStyle::Compact, JsonCase::Snake) => serde_json::to_string(report).map(Some),
+        (JsonStyle::Pretty, JsonCase::Camel) => {
+            serde_json::to_string_pretty(&camel_case_keys(serde_json::to_value(report)?)).map(Some)
+        }
+        (JsonStyle::Compact, JsonCase::Camel) => to_json_string(report, case).map(Some),
+    }
+}
+
+fn to_json_string(value: &impl Serialize, case: JsonCase) -> Result<String, serde_json::Error> {
+    match case {
+        JsonCase::Snake => serde_json::to_string(value),
+        JsonCase::Camel => serde_json::to_string(&camel_case_keys(serde_json::to_value(value)?)),
+    }
+}
+
+// Renames object keys only; keys without underscores, such as port numbers, pass through
+fn camel_case_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| (snake_to_camel(&key), camel_case_keys(value)))
+            .collect(),
+        serde_json::Value::Array(items) => items.into_iter().map(camel_case_keys).collect(),
+        other => other,
+    }
+}
+
+fn snake_to_camel(key: &str) -> String {
+    let mut parts = key.split('_');
+    let mut camel = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+fn write_json(report: &impl Serialize, path: &str, style: JsonStyle, case: JsonCase) -> Result<(), std::io::Error> {
+    if let Some(json) = render_json(report, style, case)? {
+        let mut out = open_output(path)?;
+        writeln!(out, "{}", json)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn open_output(path: &str) -> Result<Box<dyn Write>, std::io::Error> {
+    if path == "-" {
+        Ok(Box::new(std::io::stdout().lock()))
+    } else {
+        Ok(Box::new(std::io::BufWriter::new(File::create(path)?)))
+    }
+}
+
+#[derive(Serialize)]
+struct PortMatrixRow<'a> {
+    ip: &'a str,
+    port: u16,
+    state: PortState,
+    rtt: Option<f64>,
+}
+
+// Hosts that did not answer at all are not in the results, so neither are their ports
+fn port_matrix(results: &[ScanResult]) -> Vec<PortMatrixRow<'_>> {
+    results
+        .iter()
+        .flat_map(|result| {
+            result.probed_ports.iter().map(|probed| PortMatrixRow {
+                ip: &result.ip,
+                port: probed.port,
+                state: probed.state,
+                rtt: probed.rtt,
+            })
+        })
+        .collect()
+}
+
+// One tab-separated line per host: ip, os, comma-joined open ports
+fn write_grep(results: &[ScanResult], path: &str) -> Result<(), std::io::Error> {
+    let mut out = open_output(path)?;
+    for result in results {
+        let ports: Vec<String> = result.open_ports.iter().map(|port| port.to_string()).collect();
+        writeln!(out, "{}\t{}\t{}", result.ip, result.os_guess, ports.join(","))?;
+    }
+    out.flush()
+}
+
+// Service names for --format grepable, as nmap-services spells them
+const SERVICE_NAMES: &[(u16, &str)] = &[
+    (21, "ftp"),
+    (22, "ssh"),
+    (23, "telnet"),
+    (25, "smtp"),
+    (53, "domain"),
+    (80, "http"),
+    (88, "kerberos-sec"),
+    (110, "pop3"),
+    (111, "rpcbind"),
+    (123, "ntp"),
+    (135, "msrpc"),
+    (137, "netbios-ns"),
+    (139, "netbios-ssn"),
+    (143, "imap"),
+    (161, "snmp"),
+    (389, "ldap"),
+    (443, "https"),
+    (445, "microsoft-ds"),
+    (465, "smtps"),
+    (515, "printer"),
+    (548, "afp"),
+    (554, "rtsp"),
+    (587, "submission"),
+    (631, "ipp"),
+    (636, "ldapssl"),
+    (993, "imaps"),
+    (995, "pop3s"),
+    (1433, "ms-sql-s"),
+    (1723, "pptp"),
+    (1883, "mqtt"),
+    (1900, "upnp"),
+    (3306, "mysql"),
+    (3389, "ms-wbt-server"),
+    (5353, "mdns"),
+    (5432, "postgresql"),
+    (5900, "vnc"),
+    (6379, "redis"),
+    (8000, "http-alt"),
+    (8008, "http"),
+    (8080, "http-proxy"),
+    (8443, "https-alt"),
+    (9100, "jetdirect"),
+    (27017, "mongod"),
+    (62078, "iphone-sync"),
+];
+
+fn service_name(port: u16) -> &'static str {
+    SERVICE_NAMES.iter().find(|(p, _)| *p == port).map_or("", |(_, name)| name)
+}
+
+// nmap's ctime-style timestamps, e.g. "Mon Jan  1 12:00:00 2024", in local time
+fn nmap_time(unix_seconds: u64) -> String {
+    chrono::DateTime::from_timestamp(unix_seconds as i64, 0)
+        .map(|time| time.with_timezone(&chrono::Local).format("%a %b %e %H:%M:%S %Y").to_string())
+        .unwrap_or_default()
+}
+
+// nmap -oG: per host a Status line and, when anything is open, a Ports line, with
+// tab-separated fields. Each port is port/state/protocol/owner/service/rpc/version/;
+// only the service is filled in. The comment lines around them follow nmap's so
+// parsers that read the "done" trailer keep working
+fn write_grepable(results: &[ScanResult], path: &str, metadata: &ReportMetadata) -> Result<(), std::io::Error> {
+    let mut out = open_output(path)?;
+    let command: Vec<String> = std::env::args().collect();
+    writeln!(
+        out,
+        "# network-mapper {} scan initiated {} as: {}",
+        env!("CARGO_PKG_VERSION"),
+        nmap_time(metadata.started_at),
+        command.join(" ")
+    )?;
+    for result in results {
+        let host = format!("Host: {} ({})", result.ip, result.hostname.as_deref().unwrap_or(""));
+        writeln!(out, "{}\tStatus: Up", host)?;
+
+        let tcp = result.open_ports.iter().map(|&port| format!("{}/open/tcp//{}///", port, service_name(port)));
+        let udp = result.udp_ports.iter().map(|udp| {
+            let state = match udp.state {
+                UdpState::Open => "open",
+                UdpState::OpenFiltered => "open|filtered",
+                UdpState::Closed => "closed",
+            };
+            format!("{}/{}/udp//{}///", udp.port, state, service_name(udp.port))
+        });
+        let ports: Vec<String> = tcp.chain(udp).collect();
+        let os = if result.os_guess.is_empty() || result.os_guess == "Unknown" {
+            String::new()
+        } else {
+            format!("\tOS: {}", result.os_guess)
+        };
+        if !ports.is_empty() {
+            writeln!(out, "{}\tPorts: {}{}", host, ports.join(", "), os)?;
+        }
+    }
+    let finished = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+    writeln!(
+        out,
+        "# network-mapper done at {} -- {} IP addresses ({} hosts up) scanned in {} seconds",
+        nmap_time(finished),
+        metadata.targets,
+        metadata.sampled_from.unwrap_or(results.len()),
+        finished.saturating_sub(metadata.started_at)
+    )?;
+    out.flush()
+}
+
+// RFC 4180: quote a field only when it holds a comma, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// A header row, then one row per host with the columns in the order given
+fn write_csv(results: &[ScanResult], path: &str, columns: &[CsvColumn]) -> Result<(), std::io::Error> {
+    let mut out = open_output(path)?;
+    let header: Vec<&str> = columns.iter().map(|column| column.header()).collect();
+    writeln!(out, "{}", header.join(","))?;
+    for result in results {
+        let row: Vec<String> = columns.iter().map(|column| csv_field(&column.value(result))).collect();
+        writeln!(out, "{}", row.join(","))?;
+    }
+    out.flush()
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Subnet and host nodes joined by subnet-host edges, for yEd, Cytoscape and friends
+fn write_graphml(results: &[ScanResult], path: &str) -> Result<(), std::io::Error> {
+    let mut out = open_output(path)?;
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://graphml.graphdrawing.org/xmlns http://graphml.graphdrawing.org/xmlns/1.0/graphml.xsd">"#
+    )?;
+    writeln!(out, r#"  <key id="type" for="node" attr.name="type" attr.type="string"/>"#)?;
+    writeln!(out, r#"  <key id="os" for="node" attr.name="os" attr.type="string"/>"#)?;
+    writeln!(out, r#"  <key id="role" for="node" attr.name="role" attr.type="string"/>"#)?;
+    writeln!(out, r#"  <key id="ports" for="node" attr.name="ports" attr.type="string"/>"#)?;
+    writeln!(out, r#"  <key id="rtt" for="node" attr.name="rtt_ms" attr.type="double"/>"#)?;
+    writeln!(out, r#"  <graph id="topology" edgedefault="undirected">"#)?;
+
+    let subnets: std::collections::BTreeSet<&str> = results.iter().map(|r| r.subnet.as_str()).collect();
+    for subnet in &subnets {
+        writeln!(
+            out,
+            r#"    <node id="{}"><data key="type">subnet</data></node>"#,
+            xml_escape(subnet)
+        )?;
+    }
+
+    for result in results {
+        let ports: Vec<String> = result.open_ports.iter().map(|port| port.to_string()).collect();
+        writeln!(out, r#"    <node id="{}">"#, xml_escape(&result.ip))?;
+        writeln!(out, r#"      <data key="type">host</data>"#)?;
+        writeln!(out, r#"      <data key="os">{}</data>"#, xml_escape(&result.os_guess))?;
+        writeln!(out, r#"      <data key="role">{}</data>"#, xml_escape(&result.role))?;
+        writeln!(out, r#"      <data key="ports">{}</data>"#, ports.join(","))?;
+        if let Some(rtt) = result.rtt() {
+            writeln!(out, r#"      <data key="rtt">{:.3}</data>"#, rtt)?;
+        }
+        writeln!(out, "    </node>")?;
+    }
+
+    for result in results {
+        writeln!(
+            out,
+            r#"    <edge source="{}" target="{}"/>"#,
+            xml_escape(&result.subnet),
+            xml_escape(&result.ip)
+        )?;
+    }
+
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</graphml>")?;
+    out.flush()
+}
+
+/// Coarse device role such as "printer" or "server" from well-known open ports.
+/// Each port is weighted by how strongly it points to a role, and the role with the
+/// highest total wins, the more specific one on a tie; "unknown" if none match.
+pub fn infer_role(open_ports: &[u16]) -> &'static str {
+    let ranked = rank_roles(open_ports, &builtin_role_rules());
+    let best = ranked.first().map(|best| best.role.as_str());
+    ROLE_RULES.iter().map(|(role, _)| *role).find(|role| Some(*role) == best).unwrap_or("unknown")
+}
+
+/// Writes the interactive HTML graph of `results` to `output_file`. With
+/// `collapse_prefix`, hosts are grouped into aggregates of that prefix length.
+pub fn generate_interactive_visualization(
+    results: &[ScanResult],
+    output_file: &str,
+    collapse_prefix: Option<u8>,
+) -> Result<(), std::io::Error> {
+    let html_content = render_visualization(results, collapse_prefix)?;
+    let mut file = File::create(output_file)?;
+    file.write_all(html_content.as_bytes())?;
+
+    Ok(())
+}
+
+// Warnings shown above the graph: about the run, not any one host
+fn report_notes(metadata: &ReportMetadata) -> Vec<String> {
+    let mut notes = Vec::new();
+    // --max-results dropped hosts; nobody should read the graph as the whole network
+    if let Some(live) = metadata.sampled_from {
+        notes.push(format!("Showing a random sample of the {} live hosts found (--max-results).", live));
+    }
+    if metadata.stopped {
+        notes.push("The scan was stopped early: targets never probed are missing, not down.".to_string());
+    }
+    if let Some(portal) = &metadata.captive_portal {
+        notes.push(format!(
+            "Likely captive portal: {} hosts answer HTTP with {}. Hosts and open ports may be the portal's.",
+            portal.hosts, portal.answer
+        ));
+    }
+    notes
+}
+
+// One "port: ms" line per open port for the tooltip, by port, then the ping if any.
+// A LAN host answers in well under a millisecond; each router hop adds to that
+fn connect_times(result: &ScanResult) -> String {
+    let mut ports: Vec<_> = result.port_rtt.iter().collect();
+    ports.sort_by_key(|(port, _)| **port);
+    let mut lines: Vec<String> = ports.iter().map(|(port, rtt)| format!("{}: {:.1} ms", port, rtt)).collect();
+    if let Some(ping) = result.ping_rtt {
+        lines.push(format!("ping: {:.1} ms", ping));
+    }
+    lines.join("\n")
+}
+
+// The HTML report as a string, for the report file and for --serve
+fn render_visualization(results: &[ScanResult], collapse_prefix: Option<u8>) -> Result<String, serde_json::Error> {
+    render_graph(results, collapse_prefix, None, &[])
+}
+
+// --format diff-html: today's hosts plus those gone since the baseline, on the same
+// layout, each colored by how it changed
+fn render_diff_visualization(
+    baseline: &[ScanResult],
+    results: &[ScanResult],
+    collapse_prefix: Option<u8>,
+    notes: &[String],
+) -> Result<String, serde_json::Error> {
+    let diff = ScanDiff::between(baseline, results);
+    let mut changes: HashMap<&str, (DeltaChange, &HostDiff)> = HashMap::new();
+    for (_, change, hosts) in diff.by_change() {
+        for host in hosts {
+            changes.insert(&host.ip, (change, host));
+        }
+    }
+    let gone = baseline
+        .iter()
+        .filter(|result| matches!(changes.get(result.ip.as_str()), Some((DeltaChange::Removed, _))));
+    let hosts: Vec<ScanResult> = results.iter().chain(gone).cloned().collect();
+    render_graph(&hosts, collapse_prefix, Some(&changes), notes)
+}
+
+// `changes` turns the graph into a diff: fills come from the change instead of the OS
+fn render_graph(
+    results: &[ScanResult],
+    collapse_prefix: Option<u8>,
+    changes: Option<&HashMap<&str, (DeltaChange, &HostDiff)>>,
+    notes: &[String],
+) -> Result<String, serde_json::Error> {
+    // Group devices by subnet, or by the coarser aggregate under --collapse-subnets.
+    // A subnet already wider than the aggregate, e.g. a /16 from --cidr, stays whole
+    let group_of = |r: &ScanResult| match (collapse_prefix, r.ip.parse::<Ipv4Addr>()) {
+        (Some(prefix), Ok(ip)) => {
+            let own = r.subnet.rsplit_once('/').and_then(|(_, len)| len.parse::<u8>().ok());
+            Ipv4Cidr::containing(ip, own.map_or(prefix, |own| own.min(prefix))).to_string()
+        }
+        _ => r.subnet.clone(),
+    };
+    let mut subnets: HashMap<String, Vec<&ScanResult>> = HashMap::new();
+    for result in results {
+        subnets.entry(group_of(result)).or_default().push(result);
+    }
+
+    // Prepare data for D3.js
+    let nodes: Vec<HashMap<String, String>> = results.iter()
+        .map(|r| {
+            let mut node = HashMap::new();
+            node.insert("id".to_string(), r.ip.clone());
+            node.insert("label".to_string(), r.hostname.clone().unwrap_or_else(|| r.ip.clone()));
+            if let Some(hostname) = &r.hostname {
+                node.insert("hostname".to_string(), hostname.clone());
+            }
+            node.insert("os".to_string(), r.os_guess.clone());
+            node.insert("os_confidence".to_string(), format!("{:.0}%", r.os_confidence * 100.0));
+            node.insert("subnet".to_string(), r.subnet.clone());
+            node.insert("group".to_string(), group_of(r));
+            node.insert("role".to_string(), r.role.clone());
+            if r.roles.len() > 1 {
+                let ranked: Vec<String> = r.roles.iter().map(|role| format!("{} {}", role.role, role.score)).collect();
+                node.insert("roles".to_string(), ranked.join(", "));
+            }
+            node.insert("complete".to_string(), r.complete.to_string());
+            if !r.banners.is_empty() {
+                let mut banners: Vec<_> = r.banners.iter().collect();
+                banners.sort();
+                let lines: Vec<String> = banners.iter().map(|(port, banner)| format!("{}: {}", port, banner)).collect();
+                node.insert("banners".to_string(), lines.join("\n"));
+            }
+            if !r.dual_stack_addresses.is_empty() {
+                node.insert("dual_stack".to_string(), r.dual_stack_addresses.join(", "));
+            }
+            if !r.response_outliers.is_empty() {
+                node.insert("outliers".to_string(), r.response_outliers.join("\n"));
+            }
+            if !r.http_pages.is_empty() {
+                let mut pages: Vec<_> = r.http_pages.iter().collect();
+                pages.sort_by_key(|(port, _)| **port);
+                let lines: Vec<String> =
+                    pages.iter().map(|(port, page)| format!("{}: {}", port, page.summary())).collect();
+                node.insert("http".to_string(), lines.join("\n"));
+            }
+            if !r.port_rtt.is_empty() {
+                node.insert("latencies".to_string(), connect_times(r));
+            }
+            if let Some(tier) = r.latency_tier {
+                node.insert("latency_tier".to_string(), tier.name().to_string());
+            }
+            if let Some(changes) = changes {
+                let (change, detail) = match changes.get(r.ip.as_str()) {
+                    Some((change, host)) => (change.name(), host.summary(*change)),
+                    None => ("unchanged", String::new()),
+                };
+                node.insert("change".to_string(), change.to_string());
+                node.insert("change_detail".to_string(), detail);
+            }
+            node.insert("type".to_string(), "device".to_string());
+            node
+        })
+        .chain(subnets.iter().map(|(subnet, devices)| {
+            let mut node = HashMap::new();
+            node.insert("id".to_string(), subnet.clone());
+            node.insert("count".to_string(), devices.len().to_string());
+            node.insert("type".to_string(), "subnet".to_string());
+            node
+        }))
+        .collect();
+
+    let links: Vec<HashMap<String, String>> = subnets.iter()
+        .flat_map(|(subnet, devices)| {
+            devices.iter().map(move |device| {
+                let mut link = HashMap::new();
+                link.insert("source".to_string(), subnet.clone());
+                link.insert("target".to_string(), device.ip.clone());
+                link
+            })
+        })
+        .collect();
+
+    let data = json!({
+        "nodes": nodes,
+        "links": links,
+        "collapsed": collapse_prefix.is_some(),
+        "diff": changes.is_some(),
+        "tiers": results.iter().any(|r| r.latency_tier.is_some())
+    });
+    let title = if changes.is_some() { "Network Changes" } else { "Network Topology Visualization" };
+    let notes: String = notes.iter().map(|note| format!(r#"<p class="note">{}</p>"#, xml_escape(note))).collect();
+    // Nothing to lay out: the message takes the graph's place
+    let empty = match (results.is_empty(), changes.is_some()) {
+        (false, _) => "",
+        (true, false) => r#"<p class="empty">No live hosts were found, so there is nothing to draw.</p>"#,
+        (true, true) => r#"<p class="empty">Neither scan found a live host, so there is nothing to compare.</p>"#,
+    };
+
+    // HTML template with embedded D3.js visualization
+    let html_content = format!(
+        r##"
+        <!DOCTYPE html>
+        <html>
+        <head>
+            <meta charset="utf-8">
+            <title>{title}</title>
+            <script src="https://d3js.org/d3.v7.min.js"></script>
+            <style>
+                body {{ font-family: Arial, sans-serif; }}
+                .node {{ stroke: #fff; stroke-width: 1.5px; }}
+                .label {{ stroke: none; fill: #333; }}
+                .link {{ stroke: #999; stroke-opacity: 0.6; }}
+                #physics {{ margin-bottom: 8px; font-size: 13px; }}
+                #physics label {{ margin-right: 16px; }}
+                #physics output {{ display: inline-block; min-width: 3em; }}
+                .note {{ color: #a40; font-weight: bold; }}
+                #legend span {{ display: inline-block; margin: 0 16px 8px 0; font-size: 13px; }}
+                #legend i, #os-filters i {{ display: inline-block; width: 10px; height: 10px; margin-right: 4px; border-radius: 50%; }}
+                #filters {{ margin-bottom: 8px; font-size: 13px; }}
+                #filters input[type=search] {{ width: 16em; margin-right: 16px; }}
+                #os-filters label {{ margin-right: 12px; white-space: nowrap; }}
+                .dimmed {{ opacity: 0.15; }}
+                .empty {{ color: #666; font-size: 16px; }}
+            </style>
+        </head>
+        <body>
+            <h1>{title}</h1>
+            {notes}
+            {empty}
+            <div id="legend"></div>
+            <div id="filters">
+                <input type="search" id="search" placeholder="Search IP, hostname or OS">
+                <span id="os-filters"></span>
+            </div>
+            <div id="physics">
+                <label>Charge <input type="range" id="charge" min="-500" max="0" step="5"> <output></output></label>
+                <label>Link distance <input type="range" id="distance" min="5" max="300" step="5"> <output></output></label>
+                <label>Collision radius <input type="range" id="collide" min="0" max="50" step="1"> <output></output></label>
+                <button id="physics-reset">Reset</button>
+                <button id="export-svg">Download SVG</button>
+                <button id="export-png">Download PNG</button>
+            </div>
+            <div id="network-graph"></div>
+            <script>
+                const data = {};
+
+                const width = 960;
+                const height = 600;
+
+                // An empty report keeps its message alone on the page
+                if (data.nodes.length === 0) {{
+                    for (const id of ["legend", "filters", "physics", "network-graph"]) document.getElementById(id).hidden = true;
+                }}
+
+                // Every OS gets its color up front, so the checkboxes below match the nodes
+                const color = d3.scaleOrdinal(d3.schemeCategory10)
+                    .domain([...new Set(data.nodes.filter(d => d.type === "device").map(d => d.os))].sort());
+
+                // A diff report colors hosts by what changed since the baseline instead of by OS,
+                // and --latency-tiers by how far away they are; hosts without a tier stay grey
+                const changeColors = {{ added: "#2ca02c", removed: "#d62728", changed: "#ff7f0e", unchanged: "#c7c7c7" }};
+                const tierColors = {{ local: "#2ca02c", lan: "#1f77b4", wan: "#ff7f0e", slow: "#d62728" }};
+                const fill = d => d.type === "subnet" ? "#ccc"
+                    : data.diff ? changeColors[d.change]
+                    : data.tiers ? tierColors[d.latency_tier] || "#c7c7c7"
+                    : color(d.os);
+                const legend = data.diff ? changeColors : data.tiers ? tierColors : null;
+                if (legend) {{
+                    d3.select("#legend")
+                        .selectAll("span")
+                        .data(Object.entries(legend))
+                        .join("span")
+                        .html(([change, c]) => `<i style="background:${{c}}"></i>${{change}}`);
+                }}
+
+                // 24x24 icons per inferred role; anything else is drawn as a circle
+                const icons = {{
+                    server: "M4 2h16v9H4z M4 13h16v9H4z",
+                    workstation: "M2 3h20v13H2z M10 16h4v3h-4z M6 19h12v2H6z",
+                    router: "M2 13h20v8H2z M5 4h2v9H5z M17 4h2v9h-2z",
+                    printer: "M6 2h12v6H6z M2 8h20v9H2z M6 14h12v8H6z",
+                    iot: "M7 7h10v10H7z M9 3h2v4H9z M13 3h2v4h-2z M9 17h2v4H9z M13 17h2v4h-2z M3 9h4v2H3z M3 13h4v2H3z M17 9h4v2h-4z M17 13h4v2h-4z"
+                }};
+
+                const simulation = d3.forceSimulation()
+                    .force("link", d3.forceLink().id(d => d.id))
+                    .force("charge", d3.forceManyBody())
+                    .force("collide", d3.forceCollide())
+                    .force("center", d3.forceCenter(width / 2, height / 2));
+
+                // Good values differ between a 10-node and a 500-node graph, so the
+                // viewer tunes them and the browser remembers the choice
+                const physicsKey = "network-mapper-physics";
+                const physicsDefaults = {{ charge: -30, distance: 30, collide: 0 }};
+                let physics = {{ ...physicsDefaults }};
+                try {{
+                    physics = {{ ...physicsDefaults, ...JSON.parse(localStorage.getItem(physicsKey) || "{{}}") }};
+                }} catch (e) {{}}
+
+                function applyPhysics() {{
+                    simulation.force("charge").strength(physics.charge);
+                    simulation.force("link").distance(physics.distance);
+                    simulation.force("collide").radius(physics.collide);
+                    for (const key of Object.keys(physicsDefaults)) {{
+                        const input = document.getElementById(key);
+                        input.value = physics[key];
+                        input.nextElementSibling.value = physics[key];
+                    }}
+                    try {{ localStorage.setItem(physicsKey, JSON.stringify(physics)); }} catch (e) {{}}
+                }}
+
+                for (const key of Object.keys(physicsDefaults)) {{
+                    document.getElementById(key).addEventListener("input", event => {{
+                        physics[key] = Number(event.target.value);
+                        applyPhysics();
+                        simulation.alpha(0.5).restart();
+                    }});
+                }}
+                document.getElementById("physics-reset").addEventListener("click", () => {{
+                    physics = {{ ...physicsDefaults }};
+                    applyPhysics();
+                    simulation.alpha(1).restart();
+                }});
+                applyPhysics();
+
+                const svg = d3.select("#network-graph")
+                    .append("svg")
+                    .attr("width", width)
+                    .attr("height", height);
+
+                const linkLayer = svg.append("g");
+                const nodeLayer = svg.append("g");
+                let link = linkLayer.selectAll("line");
+                let node = nodeLayer.selectAll("g");
+
+                // One checkbox per OS in the color domain; unchecking one hides its hosts.
+                // Built with text() rather than html(): the OS names come from banners
+                const hiddenOs = new Set();
+                d3.select("#os-filters")
+                    .selectAll("label")
+                    .data(color.domain())
+                    .join("label")
+                    .each(function(os) {{
+                        const label = d3.select(this);
+                        label.append("input")
+                            .attr("type", "checkbox")
+                            .property("checked", true)
+                            .on("input", event => {{
+                                if (event.target.checked) hiddenOs.delete(os); else hiddenOs.add(os);
+                                render();
+                            }});
+                        // Colored by change or tier, the OS swatch would mislead
+                        if (!legend) label.append("i").style("background", color(os));
+                        label.append("span").text(os);
+                    }});
+
+                // Collapsed aggregates start closed; clicking one shows or hides its hosts
+                const expanded = new Set();
+                const visible = d => (!data.collapsed || d.type === "subnet" || expanded.has(d.group))
+                    && !(d.type === "device" && hiddenOs.has(d.os));
+
+                // Nodes whose IP, hostname or OS contains the query stay bright, everything
+                // else is dimmed; an empty query dims nothing
+                const search = document.getElementById("search");
+                const matches = (d, query) => [d.id, d.hostname, d.os].some(v => v && v.toLowerCase().includes(query));
+                function highlight() {{
+                    const query = search.value.trim().toLowerCase();
+                    node.classed("dimmed", d => query !== "" && !matches(d, query));
+                    link.classed("dimmed", l => query !== "" && !matches(l.target, query));
+                }}
+                search.addEventListener("input", highlight);
+
+                function render() {{
+                    const nodes = data.nodes.filter(visible);
+                    const ids = new Set(nodes.map(d => d.id));
+                    // forceLink replaces ids with node objects, so hand it fresh copies
+                    const links = data.links.filter(l => ids.has(l.target)).map(l => ({{ ...l }}));
+
+                    link = link
+                        .data(links, l => l.target)
+                        .join("line")
+                        .attr("class", "link");
+
+                    node = node
+                        .data(nodes, d => d.id)
+                        .join(enter => {{
+                            const g = enter.append("g")
+                                .attr("class", "node")
+                                .call(drag(simulation));
+                            decorate(g);
+                            return g;
+                        }});
+
+                    simulation.nodes(nodes);
+                    simulation.force("link").links(links);
+                    simulation.alpha(1).restart();
+                    highlight();
+                }}
+
+                function decorate(g) {{
+                    g.filter(d => icons[d.role])
+                        .append("path")
+                        .attr("d", d => icons[d.role])
+                        .attr("transform", "translate(-12,-12)")
+                        .attr("fill", fill);
+
+                    g.filter(d => !icons[d.role])
+                        .append("circle")
+                        .attr("r", d => d.type === "subnet" ? (data.collapsed ? 8 + Math.sqrt(d.count) : 8) : 5)
+                        .attr("fill", fill);
+
+                    // Incomplete scans get a dashed red outline
+                    g.filter(d => d.complete === "false")
+                        .append("circle")
+                        .attr("r", 14)
+                        .attr("fill", "none")
+                        .attr("stroke", "#d62728")
+                        .attr("stroke-dasharray", "3,2");
+
+                    g.filter(d => d.type === "subnet" && data.collapsed)
+                        .style("cursor", "pointer")
+                        .on("click", (event, d) => {{
+                            if (expanded.has(d.id)) expanded.delete(d.id); else expanded.add(d.id);
+                            render();
+                        }})
+                        .append("text")
+                        .attr("text-anchor", "middle")
+                        .attr("dy", "0.35em")
+                        .attr("font-size", "10px")
+                        .text(d => d.count);
+
+                    g.filter(d => d.type === "device")
+                        .append("text")
+                        .attr("class", "label")
+                        .attr("text-anchor", "middle")
+                        .attr("dy", "2.2em")
+                        .attr("font-size", "9px")
+                        .text(d => d.label);
+
+                    g.append("title")
+                        .text(d => d.type === "subnet"
+                            ? `Subnet: ${{d.id}}\nHosts: ${{d.count}}`
+                            : (d.hostname ? `Host: ${{d.hostname}}\n` : "") + `IP: ${{d.id}}\nOS: ${{d.os}} (${{d.os_confidence}})\nRole: ${{d.roles || d.role}}\nSubnet: ${{d.subnet}}`
+                                + (d.dual_stack ? `\nAlso: ${{d.dual_stack}}` : "")
+                                + (d.change ? `\nChange: ${{d.change}}` + (d.change_detail ? `, ${{d.change_detail}}` : "") : "")
+                                + (d.latency_tier ? `\nLatency tier: ${{d.latency_tier}}` : "")
+                                + (d.latencies ? `\nConnect times:\n${{d.latencies}}` : "")
+                                + (d.banners ? `\nBanners:\n${{d.banners}}` : "")
+                                + (d.http ? `\nWeb pages:\n${{d.http}}` : "")
+                                + (d.outliers ? `\nUnusual responses:\n${{d.outliers}}` : "")
+                                + (d.complete === "false" ? "\nINCOMPLETE scan: some ports unanswered" : ""));
+                }}
+
+                render();
+
+                // The page's stylesheet does not travel with the file, so every element of
+                // the copy gets its computed presentation inlined
+                const exported = ["fill", "stroke", "stroke-width", "stroke-opacity", "stroke-dasharray", "opacity",
+                    "font-family", "font-size"];
+                function svgSnapshot() {{
+                    const live = svg.node();
+                    const copy = live.cloneNode(true);
+                    copy.setAttribute("xmlns", "http://www.w3.org/2000/svg");
+                    const originals = [live, ...live.querySelectorAll("*")];
+                    [copy, ...copy.querySelectorAll("*")].forEach((element, i) => {{
+                        const style = getComputedStyle(originals[i]);
+                        element.setAttribute("style", exported.map(p => `${{p}}:${{style.getPropertyValue(p)}}`).join(";"));
+                    }});
+                    // Tooltips mean nothing in a picture
+                    copy.querySelectorAll("title").forEach(title => title.remove());
+                    const background = document.createElementNS("http://www.w3.org/2000/svg", "rect");
+                    background.setAttribute("width", width);
+                    background.setAttribute("height", height);
+                    background.setAttribute("fill", "#fff");
+                    copy.insertBefore(background, copy.firstChild);
+                    return new XMLSerializer().serializeToString(copy);
+                }}
+
+                function download(blob, name) {{
+                    const a = document.createElement("a");
+                    a.href = URL.createObjectURL(blob);
+                    a.download = name;
+                    a.click();
+                    setTimeout(() => URL.revokeObjectURL(a.href), 0);
+                }}
+
+                document.getElementById("export-svg").addEventListener("click", () => {{
+                    download(new Blob([svgSnapshot()], {{ type: "image/svg+xml" }}), "network-topology.svg");
+                }});
+                // Drawn at twice the size so the labels stay legible
+                document.getElementById("export-png").addEventListener("click", () => {{
+                    const image = new Image();
+                    const url = URL.createObjectURL(new Blob([svgSnapshot()], {{ type: "image/svg+xml" }}));
+                    image.onload = () => {{
+                        const canvas = document.createElement("canvas");
+                        canvas.width = width * 2;
+                        canvas.height = height * 2;
+                        const context = canvas.getContext("2d");
+                        context.scale(2, 2);
+                        context.drawImage(image, 0, 0);
+                        URL.revokeObjectURL(url);
+                        canvas.toBlob(blob => download(blob, "network-topology.png"), "image/png");
+                    }};
+                    image.src = url;
+                }});
+
+                simulation.on("tick", () => {{
+                    link
+                        .attr("x1", d => d.source.x)
+                        .attr("y1", d => d.source.y)
+                        .attr("x2", d => d.target.x)
+                        .attr("y2", d => d.target.y);
+
+                    node
+                        .attr("transform", d => `translate(${{d.x}},${{d.y}})`);
+                }});
+
+                function drag(simulation) {{
+                    function dragstarted(event) {{
+                        if (!event.active) simulation.alphaTarget(0.3).restart();
+                        event.subject.fx = event.subject.x;
+                        event.subject.fy = event.subject.y;
+                    }}
+
+                    function dragged(event) {{
+                        event.subject.fx = event.x;
+                        event.subject.fy = event.y;
+                    }}
+
+                    function dragended(event) {{
+                        if (!event.active) simulation.alphaTarget(0);
+                        event.subject.fx = null;
+                        event.subject.fy = null;
+                    }}
+
+                    return d3.drag()
+                        .on("start", dragstarted)
+                        .on("drag", dragged)
+                        .on("end", dragended);
+                }}
+            </script>
+        </body>
+        </html>
+        "##,
+        // A banner or hostname holding "</script>" would otherwise end the script early
+        serde_json::to_string(&data)?.replace('<', "\\u003c")
+    );
+
+    Ok(html_content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn definitive_answers_are_not_retried() {
+        assert!(!ConnectOutcome::Refused(Duration::from_millis(1)).is_retryable());
+        assert!(!ConnectOutcome::Error(std::io::ErrorKind::PermissionDenied.into()).is_retryable());
+    }
+
+    // Outer IPv4 header from `from`, ICMP 3/code, quoting a TCP SYN to 10.0.0.5:445
+    fn icmp_unreachable(from: [u8; 4], code: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 56, 0, 0, 0, 0, 64, 1, 0, 0];
+        packet.extend(from);
+        packet.extend([10, 0, 0, 1]);
+        packet.extend([3, code, 0, 0, 0, 0, 0, 0]);
+        packet.extend([0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 5]);
+        packet.extend([0xc0, 0x00, 0x01, 0xbd, 0, 0, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn only_errors_from_the_target_prove_it_is_up() {
+        let target = Ipv4Addr::new(10, 0, 0, 5);
+
+        let (ip, port, error) = parse_icmp_error(&icmp_unreachable([10, 0, 0, 5], 3)).unwrap();
+        assert_eq!((ip, port), (target, 445));
+        assert!(error.proves_host_up(target));
+
+        let (_, _, error) = parse_icmp_error(&icmp_unreachable([10, 0, 0, 1], 1)).unwrap();
+        assert_eq!(error.meaning, "host unreachable");
+        assert!(!error.proves_host_up(target));
+
+        let (_, _, error) = parse_icmp_error(&icmp_unreachable([10, 0, 0, 1], 13)).unwrap();
+        assert!(!error.proves_host_up(target));
+    }
+
+    fn host(ip: &str, open_ports: &[u16]) -> ScanResult {
+        serde_json::from_value(json!({
+            "ip": ip,
+            "open_ports": open_ports,
+            "os_guess": "Linux",
+            "subnet": "10.0.0.0/24",
+            "role": "server",
+            "port_rtt": { "22": 1.5 },
+        }))
+        .unwrap()
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("network-mapper-test-{}-{}", Uuid::new_v4(), name))
+    }
+
+    #[test]
+    fn csv_has_the_chosen_columns_in_order() {
+        let path = temp_path("topology.csv");
+        let mut quoted = host("10.0.0.9", &[443]);
+        quoted.os_guess = "Windows, probably".to_string();
+        let columns = [CsvColumn::Ports, CsvColumn::Ip, CsvColumn::Os, CsvColumn::Rtt];
+        write_csv(&[host("10.0.0.5", &[22, 80]), quoted], path.to_str().unwrap(), &columns).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, ["ports,ip,os,rtt_ms", "22 80,10.0.0.5,Linux,1.50", "443,10.0.0.9,\"Windows, probably\",1.50"]);
+    }
+
+    #[test]
+    fn graphml_is_well_formed_and_links_hosts_to_subnets() {
+        use quick_xml::events::Event;
+
+        let path = temp_path("topology.graphml");
+        write_graphml(&[host("10.0.0.5", &[22, 80]), host("10.0.0.9", &[443])], path.to_str().unwrap()).unwrap();
+        let xml = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let attr = |e: &quick_xml::events::BytesStart, name: &str| {
+            e.try_get_attribute(name).unwrap().map(|a| String::from_utf8(a.value.into_owned()).unwrap())
+        };
+        let (mut keys, mut nodes, mut edges, mut data_keys) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        let mut reader = quick_xml::Reader::from_str(&xml);
+        reader.check_end_names(true);
+        loop {
+            match reader.read_event().expect("GraphML must be well-formed XML") {
+                Event::Start(e) | Event::Empty(e) => match e.name().as_ref() {
+                    b"key" => {
+                        assert!(nodes.is_empty(), "keys must precede the graph");
+                        keys.push(attr(&e, "id").unwrap());
+                    }
+                    b"node" => nodes.push(attr(&e, "id").unwrap()),
+                    b"edge" => edges.push((attr(&e, "source").unwrap(), attr(&e, "target").unwrap())),
+                    b"data" => data_keys.push(attr(&e, "key").unwrap()),
+                    _ => {}
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        assert_eq!(keys, ["type", "os", "role", "ports", "rtt"]);
+        assert!(data_keys.iter().all(|key| keys.contains(key)), "undeclared data key");
+        assert_eq!(nodes, ["10.0.0.0/24", "10.0.0.5", "10.0.0.9"]);
+        assert_eq!(
+            edges,
+            [
+                ("10.0.0.0/24".to_string(), "10.0.0.5".to_string()),
+                ("10.0.0.0/24".to_string(), "10.0.0.9".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn file_sink_has_every_line_once_it_returns() {
+        let path = temp_path("sink.ndjson");
+        let (tx, rx) = mpsc::unbounded_channel();
+        let lines: Vec<String> = (0..500)
+            .map(|i| json!({ "ip": format!("10.0.{}.{}", i / 256, i % 256) }).to_string())
+            .collect();
+        for line in &lines {
+            tx.send(line.clone()).unwrap();
+        }
+        drop(tx);
+
+        run_sink(path.to_str().unwrap().to_string(), rx).await;
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(written.ends_with('\n'));
+        assert_eq!(written.lines().collect::<Vec<_>>(), lines);
+    }
+
+    #[test]
+    fn rotation_never_collides_and_prunes_only_rotated_copies() {
+        let dir = temp_path("rotate");
+        std::fs::create_dir(&dir).unwrap();
+        let base = dir.join("report.html");
+        let base = base.to_str().unwrap();
+        std::fs::write(dir.join("report-2024.html"), "mine").unwrap();
+
+        let first = rotated_path(base, 1000).unwrap();
+        let second = rotated_path(base, 1000).unwrap();
+        assert_ne!(first, second);
+        let newest = rotated_path(base, 2000).unwrap();
+
+        prune_rotated(base, 2).unwrap();
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let name = |p: &str| std::path::Path::new(p).file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(left, vec!["report-2024.html".to_string(), name(&second), name(&newest)]);
+    }
+
+    #[test]
+    fn business_hours_windows_may_span_midnight() {
+        let day: TimeWindow = "09:00-17:30".parse().unwrap();
+        assert!(day.contains(9 * 60));
+        assert!(day.contains(17 * 60 + 29));
+        assert!(!day.contains(17 * 60 + 30));
+        assert!(!day.contains(3 * 60));
+
+        let night: TimeWindow = "22:00-06:00".parse().unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(0));
+        assert!(night.contains(5 * 60 + 59));
+        assert!(!night.contains(6 * 60));
+        assert!(!night.contains(12 * 60));
+
+        assert!("09:00-09:00".parse::<TimeWindow>().is_err());
+        assert!("24:00-06:00".parse::<TimeWindow>().is_err());
+
+        // 23:30 UTC is 01:30 the next day at +02:00
+        let late = 23 * 3600 + 30 * 60;
+        assert_eq!("+02:00".parse::<WindowZone>().unwrap().minute_of_day(late), 90);
+        assert_eq!("-05:30".parse::<WindowZone>().unwrap().minute_of_day(late), 18 * 60);
+        assert_eq!("utc".parse::<WindowZone>().unwrap().minute_of_day(late), 23 * 60 + 30);
+    }
+
+    #[test]
+    fn sink_deltas_mark_added_changed_and_removed_hosts() {
+        let delta = |previous: Option<&[u16]>, current: Option<&[u16]>| {
+            SinkDelta::between("scan", "10.0.0.5", previous, current).map(|d| (d.change, d.opened, d.closed))
+        };
+        assert_eq!(delta(None, Some(&[80, 22])), Some((DeltaChange::Added, vec![22, 80], vec![])));
+        assert_eq!(delta(Some(&[22, 80]), Some(&[443, 22])), Some((DeltaChange::Changed, vec![443], vec![80])));
+        assert_eq!(delta(Some(&[22]), None), Some((DeltaChange::Removed, vec![], vec![22])));
+        assert_eq!(delta(Some(&[80, 22]), Some(&[22, 80])), None);
+        assert_eq!(delta(None, None), None);
+    }
+
+    #[test]
+    fn mdns_answers_join_services_to_addresses() {
+        let record = |packet: &mut Vec<u8>, name: &[u8], rtype: u16, rdata: &[u8]| {
+            packet.extend_from_slice(name);
+            packet.extend(rtype.to_be_bytes());
+            // Cache-flush IN, TTL 4500
+            packet.extend([0x80, 0x01, 0, 0, 0x11, 0x94]);
+            packet.extend((rdata.len() as u16).to_be_bytes());
+            packet.extend_from_slice(rdata);
+        };
+        // Response, no questions, one answer and two additional records
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        let (mut service_type, mut instance, mut host_name) = (Vec::new(), Vec::new(), Vec::new());
+        dns_encode_name("_ipp._tcp.local", &mut service_type);
+        dns_encode_name("Office Printer._ipp._tcp.local", &mut instance);
+        dns_encode_name("printer.local", &mut host_name);
+
+        // The SRV names its instance by pointing into the PTR's rdata
+        let instance_at = packet.len() + service_type.len() + 10;
+        record(&mut packet, &service_type, DNS_TYPE_PTR, &instance);
+        let pointer = [0xc0 | (instance_at >> 8) as u8, instance_at as u8];
+        let mut srv = vec![0, 0, 0, 0, 0x02, 0x77];
+        srv.extend_from_slice(&host_name);
+        record(&mut packet, &pointer, DNS_TYPE_SRV, &srv);
+        record(&mut packet, &host_name, DNS_TYPE_A, &[192, 168, 1, 40]);
+
+        let records = parse_dns_records(&packet).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            mdns_services(&records)[&Ipv4Addr::new(192, 168, 1, 40)],
+            [MdnsService { name: "Office Printer".to_string(), service: "_ipp._tcp".to_string(), port: 631 }]
+        );
+
+        // A query, or a response cut mid-record, yields nothing
+        assert!(parse_dns_records(&mdns_query(&["_ipp._tcp.local".to_string()])).is_none());
+        assert!(parse_dns_records(&packet[..packet.len() - 2]).is_none());
+    }
+
+    #[test]
+    fn rate_limit_spaces_connects_after_a_short_burst() {
+        let ms = Duration::from_millis;
+        // 200/s: a 20-connect burst, then one every 5 ms
+        let limit = RateLimit::new(200.0);
+        let start = Instant::now();
+        for _ in 0..20 {
+            assert_eq!(limit.reserve(start), start);
+        }
+        assert_eq!(limit.reserve(start), start + ms(5));
+        assert_eq!(limit.reserve(start), start + ms(10));
+        // Callers that come later still queue behind the debt
+        assert_eq!(limit.reserve(start + ms(1)), start + ms(15));
+
+        // Idle time refills the bucket, but never past the burst
+        let rested = start + Duration::from_secs(10);
+        for _ in 0..20 {
+            assert_eq!(limit.reserve(rested), rested);
+        }
+        assert_eq!(limit.reserve(rested), rested + ms(5));
+
+        // Below 10/s the burst is a single connect
+        let slow = RateLimit::new(0.5);
+        assert_eq!(slow.reserve(start), start);
+        assert_eq!(slow.reserve(start), start + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn error_bursts_pause_connects_then_resume_spaced_out() {
+        let throttle = Throttle::new(3, Duration::from_secs(5));
+        let start = Instant::now();
+        assert_eq!(throttle.reserve(start), start);
+
+        // Errors spread wider than the window never add up to a burst
+        let spread = THROTTLE_ERROR_WINDOW + Duration::from_millis(1);
+        for i in 0..6 {
+            assert!(!throttle.record(true, start + spread * i));
+        }
+
+        let burst = start + spread * 10;
+        assert!(!throttle.record(true, burst));
+        assert!(!throttle.record(true, burst));
+        assert!(throttle.record(true, burst));
+        let resume = burst + Duration::from_secs(5);
+        assert_eq!(throttle.reserve(burst), resume);
+        assert_eq!(throttle.reserve(burst), resume + THROTTLE_MIN_SPACING);
+
+        // Successes shrink the gap back to nothing
+        for _ in 0..50 {
+            throttle.record(false, resume);
+        }
+        let later = resume + Duration::from_secs(1);
+        assert_eq!(throttle.reserve(later), later);
+        assert_eq!(throttle.reserve(later), later);
+    }
+
+    #[test]
+    fn report_graph_links_subnets_hosts_and_shared_identities() {
+        let mut results = vec![host("10.0.0.5", &[22]), host("10.0.0.9", &[22])];
+        results[1].subnet = "10.0.1.0/24".to_string();
+        results[0].same_device_as = vec!["10.0.0.9".to_string()];
+        results[1].same_device_as = vec!["10.0.0.5".to_string()];
+
+        let graph = TopologyGraph::build(&results);
+        let nodes: Vec<(&str, &str)> = graph.nodes.iter().map(|n| (n.id.as_str(), n.kind)).collect();
+        assert_eq!(
+            nodes,
+            [("10.0.0.0/24", "subnet"), ("10.0.1.0/24", "subnet"), ("10.0.0.5", "host"), ("10.0.0.9", "host")]
+        );
+        let edges: Vec<(&str, &str, &str)> =
+            graph.edges.iter().map(|e| (e.source.as_str(), e.target.as_str(), e.kind)).collect();
+        assert_eq!(
+            edges,
+            [
+                ("10.0.0.0/24", "10.0.0.5", "subnet"),
+                ("10.0.1.0/24", "10.0.0.9", "subnet"),
+                ("10.0.0.5", "10.0.0.9", "same_device"),
+            ]
+        );
+    }
+
+    #[test]
+    fn api_routes_answer_from_the_current_results() {
+        let results = vec![host("10.0.0.5", &[22]), host("10.0.0.9", &[80])];
+        let get = |target: &str| {
+            let (status, _, body) = api_response(target, &results, JsonCase::Snake);
+            (status, serde_json::from_str::<serde_json::Value>(&body).unwrap())
+        };
+
+        let (status, hosts) = get("/api/hosts");
+        assert_eq!(status, 200);
+        assert_eq!(hosts.as_array().unwrap().len(), 2);
+
+        let (status, one) = get("/api/hosts/10.0.0.9/");
+        assert_eq!((status, &one["open_ports"]), (200, &json!([80])));
+
+        let (status, subnets) = get("/api/subnets?pretty");
+        assert_eq!(status, 200);
+        assert_eq!(subnets, json!([{ "subnet": "10.0.0.0/24", "host_count": 2, "hosts": ["10.0.0.5", "10.0.0.9"] }]));
+
+        assert_eq!(get("/api/hosts/10.0.0.7").0, 404);
+        assert_eq!(get("/api/nothing").0, 404);
+    }
+
+    #[test]
+    fn targets_split_into_internal_and_globally_routable() {
+        for ip in ["10.1.2.3", "172.31.0.1", "192.168.1.1", "100.64.0.9", "127.0.0.1", "169.254.10.1", "::1", "fd12::5", "fe80::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(is_internal(ip) && !is_globally_routable(ip), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "172.32.0.1", "1.1.1.1", "2606:4700::1111"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(!is_internal(ip) && is_globally_routable(ip), "{}", ip);
+        }
+        // Neither: nobody reaches these over the internet, and nor are they a LAN
+        for ip in ["0.1.2.3", "192.0.2.7", "198.19.0.1", "224.0.0.251", "255.255.255.255", "2001:db8::1", "ff02::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(!is_internal(ip) && !is_globally_routable(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn cidr_expands_to_its_usable_hosts() {
+        let range = |cidr: &str| {
+            let (first, last) = cidr.parse::<Ipv4Cidr>().unwrap().host_range();
+            (first.to_string(), last.to_string())
+        };
+        assert_eq!(range("192.168.1.0/24"), ("192.168.1.1".into(), "192.168.1.254".into()));
+        // Host bits in the address are ignored
+        assert_eq!(range("10.20.30.40/22"), ("10.20.28.1".into(), "10.20.31.254".into()));
+        assert_eq!(range("10.0.0.4/31"), ("10.0.0.4".into(), "10.0.0.5".into()));
+        assert_eq!(range("10.0.0.9/32"), ("10.0.0.9".into(), "10.0.0.9".into()));
+        assert_eq!(range("0.0.0.0/0"), ("0.0.0.1".into(), "255.255.255.254".into()));
+
+        for bad in ["10.0.0.0/33", "10.0.0/24", "10.0.0.0/x"] {
+            assert!(bad.parse::<Ipv4Cidr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn port_lists_mix_values_and_ranges() {
+        let ports: PortList = "22, 80,8000-8002,80".parse().unwrap();
+        assert_eq!(ports.0, [22, 80, 8000, 8001, 8002]);
+
+        let error = |list: &str| list.parse::<PortList>().unwrap_err();
+        assert!(error("70000").contains("70000"));
+        assert!(error("22,abc").contains("abc"));
+        assert!(error("0").contains("1-65535"));
+        assert!(error("90-80").contains("reversed"));
+        assert!(error(",").contains("empty"));
+    }
+
+    #[test]
+    fn rdp_connection_confirms_are_decoded() {
+        let confirm = |negotiation: &[u8]| {
+            let mut body = vec![6 + negotiation.len() as u8, 0xd0, 0, 0, 0x12, 0x34, 0];
+            body.extend_from_slice(negotiation);
+            parse_rdp_confirm(&body)
+        };
+        assert_eq!(confirm(&[0x02, 0x1f, 0x08, 0x00, 0x02, 0, 0, 0]), Some(RdpNegotiation::Selected(RDP_PROTOCOL_HYBRID)));
+        // HYBRID_REQUIRED_BY_SERVER
+        assert_eq!(confirm(&[0x03, 0x00, 0x08, 0x00, 0x05, 0, 0, 0]), Some(RdpNegotiation::Failed(5)));
+        assert_eq!(confirm(&[]), Some(RdpNegotiation::Selected(RDP_PROTOCOL_RDP)));
+        assert_eq!(confirm(&[0x02, 0x00, 0x08]), None);
+
+        // Not a Connection Confirm at all, e.g. some other service on 3389
+        assert_eq!(parse_rdp_confirm(b"HTTP/1.1 400"), None);
+        assert_eq!(rdp_protocol_name(RDP_PROTOCOL_HYBRID_EX), "hybrid_ex");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn on_complete_gets_report_paths_and_hosts_on_stdin() {
+        let paths = ["topology.html".to_string(), "report.json".to_string()];
+        let output = run_on_complete(r#"echo "$1 $2"; cat; exit 3"#, &paths, "[{\"ip\":\"10.0.0.5\"}]".to_string())
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "topology.html report.json\n[{\"ip\":\"10.0.0.5\"}]");
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn udp_ports_are_open_closed_or_silent() {
+        let wait = Duration::from_millis(200);
+
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((len, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..len], from).await;
+            }
+        });
+        assert_eq!(udp_probe(echo_addr, b"ping", wait, 0).await.unwrap(), UdpState::Open);
+
+        // Bound but never answering looks the same as a firewall dropping the probe
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        assert_eq!(udp_probe(silent_addr, b"ping", wait, 1).await.unwrap(), UdpState::OpenFiltered);
+
+        // Nothing bound: the loopback stack answers with port unreachable
+        drop(silent);
+        assert_eq!(udp_probe(silent_addr, b"ping", wait, 0).await.unwrap(), UdpState::Closed);
+
+        let state = serde_json::to_value(UdpState::OpenFiltered).unwrap();
+        assert_eq!(state, json!("open|filtered"));
+    }
+
+    #[test]
+    fn huge_scans_walk_each_target_once() {
+        let mut filter = BloomFilter::with_capacity(1000);
+        let inserted: Vec<u128> = (0..1000).map(|i| i * 7919).collect();
+        for &item in &inserted {
+            assert!(filter.insert(item));
+        }
+        assert!(!filter.insert(inserted[3]));
+        assert!(inserted.iter().all(|&item| filter.contains(item)));
+        let false_positives = (1_000_000..1_100_000).filter(|&item| filter.contains(item)).count();
+        assert!(false_positives < 500, "{} false positives in 100000", false_positives);
+
+        let args = Args::parse_from([
+            "network-mapper",
+            "--huge-scan",
+            "--cidr",
+            "10.0.0.0/29",
+            "--ips",
+            "10.0.0.3,10.9.9.9,10.0.0.3",
+        ]);
+        let targets: Vec<String> = huge_scan_targets(&args).unwrap().map(|ip| ip.to_string()).collect();
+        assert_eq!(targets, ["10.0.0.3", "10.9.9.9", "10.0.0.1", "10.0.0.2", "10.0.0.4", "10.0.0.5", "10.0.0.6"]);
+        let mut expected: Vec<Ipv4Addr> = targets.iter().map(|ip| ip.parse().unwrap()).collect();
+        expected.sort();
+        assert_eq!(expand_targets(&args).unwrap(), expected);
+    }
+
+    #[test]
+    fn cve_hints_match_whole_versions_in_banners() {
+        let path = temp_path("cve-map.txt");
+        std::fs::write(&path, "# product version, then CVEs\nvsFTPd 2.3.4 CVE-2011-2523\nOpenSSH_7.2 CVE-2016-6210 CVE-2016-6515\n").unwrap();
+        let hints = load_cve_map(path.to_str().unwrap()).unwrap();
+        assert_eq!(hints[0], CveHint { pattern: "vsFTPd 2.3.4".to_string(), cves: vec!["CVE-2011-2523".to_string()] });
+
+        let banners = |banner: &str| HashMap::from([(22, banner.to_string())]);
+        assert_eq!(
+            cve_hints_for(&banners("SSH-2.0-OpenSSH_7.2p2 Ubuntu-4ubuntu2.10"), &hints),
+            ["CVE-2016-6210", "CVE-2016-6515"]
+        );
+        assert!(cve_hints_for(&banners("SSH-2.0-OpenSSH_7.23"), &hints).is_empty());
+        assert_eq!(cve_hints_for(&banners("220 (VSFTPD 2.3.4)"), &hints), ["CVE-2011-2523"]);
+        assert!(!cve_hints_for(&banners("SSH-2.0-OpenSSH_9.6p1"), &builtin_cve_hints()).is_empty());
+
+        // HTTP banners carry the Server header, which is where the version is
+        let http = banner_line(b"HTTP/1.1 200 OK\r\nDate: now\r\nServer: Apache/2.4.49 (Unix)\r\n\r\n").unwrap();
+        assert_eq!(http, "HTTP/1.1 200 OK (Apache/2.4.49 (Unix))");
+        assert_eq!(cve_hints_for(&banners(&http), &builtin_cve_hints()), ["CVE-2021-41773"]);
+
+        std::fs::write(&path, "CVE-2011-2523\n").unwrap();
+        assert!(load_cve_map(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reverse_dns_reads_the_ptr_answer() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            // A stray reply with the wrong ID comes first and must be ignored
+            let stray = [0xff, 0xff, 0x81, 0x80, 0, 0, 0, 0, 0, 0, 0, 0];
+            server.send_to(&stray, from).await.unwrap();
+
+            let mut reply = buf[..len].to_vec();
+            reply[2..4].copy_from_slice(&[0x81, 0x80]);
+            reply[7] = 1;
+            // Owner name points back at the question
+            reply.extend([0xc0, 0x0c]);
+            reply.extend(DNS_TYPE_PTR.to_be_bytes());
+            reply.extend([0, 1, 0, 0, 0x0e, 0x10]);
+            let mut target = Vec::new();
+            dns_encode_name("nas.home.arpa", &mut target);
+            reply.extend((target.len() as u16).to_be_bytes());
+            reply.extend(target);
+            server.send_to(&reply, from).await.unwrap();
+        });
+
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        assert_eq!(reverse_name(ip), "20.1.168.192.in-addr.arpa");
+        let v6: IpAddr = "2001:db8::567:89ab".parse().unwrap();
+        assert_eq!(reverse_name(v6), "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa");
+        let hostname = reverse_dns(resolver, ip, Duration::from_secs(2)).await;
+        assert_eq!(hostname.as_deref(), Some("nas.home.arpa"));
+
+        // Nobody answering costs the timeout and nothing more
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let started = Instant::now();
+        assert_eq!(reverse_dns(silent.local_addr().unwrap(), ip, Duration::from_millis(200)).await, None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn schedules_cover_every_target_once_and_resume_from_a_checkpoint() {
+        let targets: Vec<Ipv4Addr> = (1..=10).map(|last| Ipv4Addr::new(10, 0, 0, last)).collect();
+        let buckets: Vec<Vec<Ipv4Addr>> = (0..3).map(|bucket| schedule_bucket(&targets, bucket, 3)).collect();
+        assert_eq!(buckets[1], [Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(10, 0, 0, 8)]);
+        let mut covered: Vec<Ipv4Addr> = buckets.concat();
+        covered.sort();
+        assert_eq!(covered, targets);
+
+        let path = temp_path("checkpoint.json");
+        let path = path.to_str().unwrap();
+        assert!(ScheduleCheckpoint::load(path, 10, 3).unwrap().is_none());
+        let mut checkpoint = ScheduleCheckpoint::new(1_700_000_000, 10, 3);
+        checkpoint.next_bucket = 2;
+        checkpoint.save(path, &[host("10.0.0.2", &[22])]).unwrap();
+
+        let resumed = ScheduleCheckpoint::load(path, 10, 3).unwrap().unwrap();
+        assert_eq!((resumed.started_at, resumed.next_bucket), (1_700_000_000, 2));
+        assert_eq!(resumed.hosts[0].ip, "10.0.0.2");
+        // A different target set or bucket count is refused rather than mixed in
+        assert!(ScheduleCheckpoint::load(path, 11, 3).is_err());
+
+        checkpoint.next_bucket = 3;
+        checkpoint.save(path, &[]).unwrap();
+        assert!(ScheduleCheckpoint::load(path, 10, 3).unwrap().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scan_range_returns_the_live_hosts() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = ScanOptions {
+            ports: vec![listener.local_addr().unwrap().port()],
+            timeout: Duration::from_millis(200),
+            ..ScanOptions::default()
+        };
+
+        let loopback = Ipv4Addr::new(127, 0, 0, 1);
+        let results = scan_range(loopback, loopback, options).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!((results[0].ip.as_str(), results[0].subnet.as_str()), ("127.0.0.1", "127.0.0.0/24"));
+        assert_eq!(results[0].open_ports, [listener.local_addr().unwrap().port()]);
+    }
+
+    #[test]
+    fn dual_stack_hosts_are_matched_across_families() {
+        let mut by_key = host("10.0.0.5", &[22]);
+        by_key.identity_fingerprint = Some("ssh:SHA256:abc".to_string());
+        let mut v6_by_key = host("fd00::5", &[22]);
+        v6_by_key.identity_fingerprint = Some("ssh:SHA256:abc".to_string());
+        let mut by_name = host("10.0.0.9", &[80]);
+        by_name.hostname = Some("NAS.home.arpa".to_string());
+        let mut v6_by_name = host("fd00::9", &[80]);
+        v6_by_name.hostname = Some("nas.home.arpa".to_string());
+        // Same name, same family: a pool, not one device
+        let mut pool = host("10.0.0.10", &[80]);
+        pool.hostname = Some("nas.home.arpa".to_string());
+        let mut results = vec![by_key, v6_by_key, by_name, v6_by_name, pool, host("fd00::7", &[443])];
+
+        correlate_dual_stack(&mut results);
+        let peers: Vec<&[String]> = results.iter().map(|result| result.dual_stack_addresses.as_slice()).collect();
+        assert_eq!(peers[0], ["fd00::5"]);
+        assert_eq!(peers[1], ["10.0.0.5"]);
+        assert_eq!(peers[2], ["fd00::9"]);
+        assert_eq!(peers[3], ["10.0.0.10", "10.0.0.9"]);
+        assert_eq!(peers[4], ["fd00::9"]);
+        assert!(peers[5].is_empty());
+
+        let merged: Vec<String> = merge_dual_stack(&results).into_iter().map(|result| result.ip).collect();
+        assert_eq!(merged, ["10.0.0.5", "10.0.0.9", "10.0.0.10", "fd00::7"]);
+        assert_eq!(subnet_of("fd00::7".parse().unwrap(), 24, IPV6_SUBNET_PREFIX), "fd00::/64");
+    }
+
+    #[test]
+    fn progress_counts_probed_and_live_hosts() {
+        let progress = ScanProgress {
+            total: AtomicUsize::new(256),
+            probed: AtomicUsize::new(40),
+            live: AtomicUsize::new(3),
+            stopped: AtomicBool::new(false),
+            paused: watch::channel(false).0,
+        };
+        assert_eq!(progress.status_line(), "40/256 hosts probed, 3 live");
+        progress.probed.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(progress.handle_command("status"), "running 41/256 hosts probed, 3 live");
+
+        let args = Args::parse_from(["network-mapper", "--progress", "always"]);
+        assert_eq!(args.progress, ProgressMode::Always);
+        assert_eq!(Args::parse_from(["network-mapper"]).progress, ProgressMode::Auto);
+    }
+
+    #[tokio::test]
+    async fn port_matrix_has_a_row_per_probed_port() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let options = ScanOptions {
+            ports: vec![open, closed],
+            record_probed_ports: true,
+            ..ScanOptions::default()
+        };
+        let result = scan_host("127.0.0.1".parse().unwrap(), &options).await.unwrap();
+
+        let rows = serde_json::to_value(port_matrix(std::slice::from_ref(&result))).unwrap();
+        let rows = rows.as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        let row = |port: u16| rows.iter().find(|row| row["port"] == port).unwrap();
+        assert_eq!((&row(open)["ip"], &row(open)["state"]), (&json!("127.0.0.1"), &json!("open")));
+        assert!(row(open)["rtt"].is_number());
+        assert_eq!(row(closed)["state"], json!("closed"));
+
+        // Not recorded unless asked for, and never in the host JSON
+        assert!(serde_json::to_value(&result).unwrap().get("probed_ports").is_none());
+        let plain = scan_host("127.0.0.1".parse().unwrap(), &ScanOptions { ports: vec![open], ..ScanOptions::default() }).await;
+        assert!(plain.unwrap().probed_ports.is_empty());
+    }
+
+    #[test]
+    fn concurrency_warns_only_when_pointless() {
+        assert_eq!(Args::parse_from(["network-mapper"]).concurrency, MAX_CONCURRENT_SCANS);
+        assert_eq!(warn_if_concurrency_pointless(100, 254, Some(1024)), None);
+        assert_eq!(warn_if_concurrency_pointless(300, 254, Some(1024)), None);
+        // The default never warns, however few the targets
+        assert_eq!(warn_if_concurrency_pointless(MAX_CONCURRENT_SCANS, 1, Some(1024)), None);
+        assert!(warn_if_concurrency_pointless(5000, 254, None).unwrap().contains("254 targets"));
+        let warning = warn_if_concurrency_pointless(5000, 65536, Some(1024)).unwrap();
+        assert!(warning.contains("open-file limit of 1024"), "{}", warning);
+    }
+
+    #[test]
+    fn sni_is_the_first_client_hello_extension() {
+        let plain = ClientHelloProfile::Modern.client_hello(None);
+        let named = ClientHelloProfile::Modern.client_hello(Some("intranet.example"));
+        // The server_name extension: type, length, list length, host_name entry
+        let mut extension = vec![0x00, 0x00, 0x00, 0x15, 0x00, 0x13, 0x00, 0x00, 0x10];
+        extension.extend(b"intranet.example");
+        assert_eq!(named.len(), plain.len() + extension.len());
+        assert!(named.windows(extension.len()).any(|window| window == extension));
+        assert!(ClientHelloProfile::Modern.ja3(Some("intranet.example")).contains(",0-65281-10-11-13-23,"));
+        assert!(ClientHelloProfile::Modern.ja3(None).contains(",65281-10-11-13-23,"));
+
+        assert!(validate_server_name("intranet.example").is_ok());
+        assert!(validate_server_name("10.0.0.5").is_err());
+        assert!(validate_server_name("bad..name").is_err());
+
+        let args = Args::parse_from(["network-mapper"]);
+        assert!(args.user_agent.starts_with("network-mapper/"));
+    }
+
+    #[test]
+    fn timeouts_are_whole_positive_milliseconds() {
+        assert_eq!(Args::parse_from(["network-mapper"]).timeout_ms.get(), 1000);
+        assert_eq!(Args::parse_from(["network-mapper", "--timeout-ms", "250"]).timeout_ms.get(), 250);
+        for bad in ["0", "-5", "1.5"] {
+            assert!(Args::try_parse_from(["network-mapper", "--timeout-ms", bad]).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn targets_are_resolved_before_the_scan() {
+        let names = ["localhost", "10.0.0.5", "10.0.0.5", "no-such-host.invalid"].map(String::from);
+        let resolution = resolve_targets(&names, Duration::from_secs(2)).await;
+        assert!(resolution.resolved["localhost"].contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(resolution.resolved["10.0.0.5"], [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5))]);
+        assert!(resolution.failed.contains_key("no-such-host.invalid"));
+        assert_eq!(resolution.resolved.len() + resolution.failed.len(), 3);
+
+        // Naming targets replaces the default range, as --ips does
+        let args = Args::parse_from(["network-mapper", "--target", "localhost"]);
+        assert!(target_range(&args).unwrap().is_none());
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        let base = Duration::from_millis(100);
+        let delays: Vec<u128> = (0..4).map(|retry| retry_backoff(base, retry).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800]);
+        assert_eq!(retry_backoff(base, 40), MAX_RETRY_DELAY);
+        assert_eq!(retry_backoff(Duration::ZERO, 3), Duration::ZERO);
+        assert_eq!(Args::parse_from(["network-mapper", "--retries", "2"]).retry_delay_ms, 100);
+    }
+
+    #[test]
+    fn small_ipv6_cidrs_are_scanned_and_big_ones_refused() {
+        let args = Args::parse_from(["network-mapper", "--cidr", "fd00::1:0/126"]);
+        let targets: Vec<String> = expand_targets(&args).unwrap().iter().map(IpAddr::to_string).collect();
+        assert_eq!(targets, ["fd00::1:1", "fd00::1:2", "fd00::1:3"]);
+        assert!(target_range(&args).unwrap().is_none());
+
+        let args = Args::parse_from(["network-mapper", "--cidr", "fd00::/120"]);
+        assert_eq!(expand_targets(&args).unwrap().len(), 255);
+        let args = Args::parse_from(["network-mapper", "--cidr", "fd00::/120", "--max-hosts", "100"]);
+        assert!(expand_targets(&args).is_err());
+
+        let args = Args::parse_from(["network-mapper", "--cidr", "2001:db8::/64"]);
+        let error = expand_targets(&args).unwrap_err().to_string();
+        assert!(error.contains("2^64 addresses, more than --max-hosts 65536"), "{}", error);
+        let args = Args::parse_from(["network-mapper", "--cidr", "::/0"]);
+        assert!(expand_targets(&args).is_err());
+
+        // IPv4 networks are unchanged
+        let args = Args::parse_from(["network-mapper", "--cidr", "10.0.0.0/30"]);
+        assert_eq!(expand_targets(&args).unwrap().len(), 2);
+        assert!("fd00::/129".parse::<CidrTarget>().is_err());
+    }
+
+    #[test]
+    fn echo_requests_and_replies_round_trip() {
+        let request = echo_request(0x1234, 7);
+        assert_eq!(&request[..2], [8, 0]);
+        // A correct checksum makes the whole packet sum to zero
+        assert_eq!(internet_checksum(&request), 0);
+
+        let mut reply = request.clone();
+        reply[0] = 0;
+        assert_eq!(echo_reply_seq(&reply, None), Some(7));
+        let mut raw = vec![0x45; 1];
+        raw.extend([0u8; 19]);
+        raw.extend(&reply);
+        assert_eq!(echo_reply_seq(&raw, Some(0x1234)), Some(7));
+        assert_eq!(echo_reply_seq(&raw, Some(0x9999)), None);
+        assert_eq!(echo_reply_seq(&request, None), None);
+
+        // Where this environment allows ICMP sockets at all, loopback always answers
+        if icmp_echo_socket().is_ok() {
+            let rtt = ping_host(Ipv4Addr::LOCALHOST, Duration::from_secs(1), 0).unwrap();
+            assert!(rtt.is_some());
+        }
+    }
+
+    #[test]
+    fn open_ports_are_counted_across_hosts() {
+        let results = vec![host("10.0.0.1", &[22, 443]), host("10.0.0.2", &[443]), host("10.0.0.3", &[80, 443, 22])];
+        let histogram: Vec<(u16, usize)> =
+            open_port_histogram(&results).iter().map(|count| (count.port, count.hosts)).collect();
+        assert_eq!(histogram, [(443, 3), (22, 2), (80, 1)]);
+        assert!(open_port_histogram(&[]).is_empty());
+    }
+
+    #[test]
+    fn saved_results_load_bare_or_wrapped() {
+        let hosts = vec![host("10.0.0.5", &[22]), host("10.0.0.9", &[80, 443])];
+        let path = temp_path("saved-hosts.json");
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, serde_json::to_string(&hosts).unwrap()).unwrap();
+        let (metadata, loaded) = load_saved_results("--from-file", path, Some("replay".to_string())).unwrap();
+        assert_eq!((metadata.scan_id.as_str(), metadata.targets), ("replay", 2));
+        assert_eq!(loaded[1].open_ports, [80, 443]);
+
+        let (original, _) = load_saved_results("--from-file", path, None).unwrap();
+        std::fs::write(path, serde_json::to_string(&Report::new(&original, &hosts)).unwrap()).unwrap();
+        let (metadata, loaded) = load_saved_results("--from-file", path, Some("ignored".to_string())).unwrap();
+        assert_eq!(metadata.scan_id, original.scan_id);
+        assert_eq!(loaded.len(), 2);
+
+        std::fs::write(path, "{\"hosts\": 5}").unwrap();
+        assert!(load_saved_results("--from-file", path, None).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn color_follows_the_flag_then_the_terminal() {
+        let auto = ColorMode::Auto;
+        assert!(auto.enabled(true, None, Some("xterm-256color")));
+        assert!(!auto.enabled(false, None, Some("xterm-256color")));
+        assert!(!auto.enabled(true, Some("1"), None));
+        assert!(auto.enabled(true, Some(""), None));
+        assert!(!auto.enabled(true, None, Some("dumb")));
+
+        // An explicit choice beats both the terminal check and NO_COLOR
+        assert!(ColorMode::Always.enabled(false, Some("1"), Some("dumb")));
+        assert!(!ColorMode::Never.enabled(true, None, Some("xterm")));
+        let args = Args::try_parse_from(["network-mapper", "--color", "always"]).unwrap();
+        assert_eq!(args.color, ColorMode::Always);
+    }
+
+    #[test]
+    fn scans_are_compared_by_ip_and_open_ports() {
+        let old = vec![host("10.0.0.5", &[22, 80]), host("10.0.0.9", &[443]), host("10.0.0.10", &[22])];
+        let new = vec![host("10.0.0.10", &[22]), host("10.0.0.5", &[443, 22]), host("10.0.0.20", &[80, 8080])];
+        let diff = ScanDiff::between(&old, &new);
+
+        fn hosts(hosts: &[HostDiff]) -> Vec<(&str, Vec<u16>, Vec<u16>)> {
+            hosts.iter().map(|h| (h.ip.as_str(), h.opened.clone(), h.closed.clone())).collect()
+        }
+        assert_eq!(hosts(&diff.appeared), [("10.0.0.20", vec![80, 8080], vec![])]);
+        assert_eq!(hosts(&diff.disappeared), [("10.0.0.9", vec![], vec![443])]);
+        assert_eq!(hosts(&diff.changed), [("10.0.0.5", vec![443], vec![80])]);
+        assert_eq!(ScanDiff::between(&new, &new), ScanDiff::default());
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["changed"], json!([{ "ip": "10.0.0.5", "opened": [443], "closed": [80] }]));
+        assert_eq!(diff.changed[0].summary(DeltaChange::Changed), "opened 443, closed 80");
+    }
+
+    #[test]
+    fn diff_html_colors_hosts_by_change() {
+        let baseline = vec![host("10.0.0.5", &[22, 80]), host("10.0.0.9", &[443]), host("10.0.0.10", &[22])];
+        let current = vec![host("10.0.0.5", &[22, 443]), host("10.0.0.10", &[22]), host("10.0.0.20", &[80])];
+        let html = render_diff_visualization(&baseline, &current, None, &[]).unwrap();
+
+        let data = html.split("const data = ").nth(1).unwrap().split(";\n").next().unwrap();
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(data["diff"], json!(true));
+        let change = |ip: &str| {
+            let node = data["nodes"].as_array().unwrap().iter().find(|node| node["id"] == ip).unwrap();
+            (node["change"].as_str().unwrap().to_string(), node["change_detail"].as_str().unwrap().to_string())
+        };
+        assert_eq!(change("10.0.0.5"), ("changed".to_string(), "opened 443, closed 80".to_string()));
+        // Gone hosts are drawn from the baseline so they can be shown in red
+        assert_eq!(change("10.0.0.9"), ("removed".to_string(), "was open 443".to_string()));
+        assert_eq!(change("10.0.0.20").0, "added");
+        assert_eq!(change("10.0.0.10"), ("unchanged".to_string(), String::new()));
+        assert!(html.contains("<title>Network Changes</title>"));
+
+        let plain = render_visualization(&current, None).unwrap();
+        assert!(plain.contains("<title>Network Topology Visualization</title>") && !plain.contains("\"change\""));
+    }
+
+    #[tokio::test]
+    async fn only_hosts_that_changed_since_the_baseline_are_probed_deeply() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = std::io::Write::write_all(&mut stream, b"SSH-2.0-live\r\n");
+            }
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let scan = |previous: ScanResult| {
+            let options = ScanOptions {
+                ports: vec![port],
+                banner_all: true,
+                baseline: Some(Arc::new(HashMap::from([(ip, previous)]))),
+                ..ScanOptions::default()
+            };
+            async move { scan_host(ip, &options).await.unwrap() }
+        };
+
+        // Same ports as before: the banner is the baseline's, not re-read
+        let mut unchanged = host("127.0.0.1", &[port]);
+        unchanged.banners.insert(port, "SSH-2.0-old".to_string());
+        let result = scan(unchanged).await;
+        assert!(result.carried_from_baseline);
+        assert_eq!(result.banners[&port], "SSH-2.0-old");
+
+        // A port opened since: probed again in full
+        let mut changed = host("127.0.0.1", &[]);
+        changed.banners.insert(port, "SSH-2.0-old".to_string());
+        let result = scan(changed).await;
+        assert!(!result.carried_from_baseline);
+        assert_eq!(result.banners[&port], "SSH-2.0-live");
+    }
+
+    #[tokio::test]
+    async fn responses_are_measured_to_the_end() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Two writes apart, so the response spans more than one read
+            std::io::Write::write_all(&mut stream, &[b'x'; 3000]).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            std::io::Write::write_all(&mut stream, &[b'y'; 2000]).unwrap();
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (first, stats) = probe_service_measured(&mut stream, None, Duration::from_secs(1)).await;
+        assert_eq!(first[0], b'x');
+        assert_eq!(stats.bytes, 5000);
+        assert!(stats.first_byte_ms.is_some());
+    }
+
+    #[test]
+    fn response_outliers_are_judged_per_port() {
+        let with_response = |ip: &str, bytes: usize, first_byte_ms: f64| {
+            let mut result = host(ip, &[80]);
+            result.responses.insert(80, ResponseStats { bytes, first_byte_ms: Some(first_byte_ms) });
+            result
+        };
+        let mut results = vec![
+            with_response("10.0.0.1", 2000, 20.0),
+            with_response("10.0.0.2", 2100, 25.0),
+            with_response("10.0.0.3", 1900, 30.0),
+            with_response("10.0.0.4", 48000, 22.0),
+            with_response("10.0.0.5", 2050, 900.0),
+        ];
+        flag_response_outliers(&mut results);
+        let outliers: Vec<&[String]> = results.iter().map(|result| result.response_outliers.as_slice()).collect();
+        assert_eq!(outliers[3], ["port 80: 48000 bytes, median 2050"]);
+        assert_eq!(outliers[4], ["port 80: first byte after 900 ms, median 25 ms"]);
+        assert!(outliers[..3].iter().all(|outliers| outliers.is_empty()));
+
+        // Too few peers on the port to call anything unusual
+        let mut few = results.split_off(3);
+        flag_response_outliers(&mut few);
+        assert!(few.iter().all(|result| result.response_outliers.is_empty()));
+    }
+
+    #[test]
+    fn os_guesses_weigh_every_signal() {
+        let guess = |ports: &[u16]| {
+            let guess = guess_os(ports);
+            (guess.os, guess.confidence)
+        };
+        assert_eq!(guess(&[62078, 445]), ("iOS", 0.9));
+        assert_eq!(guess(&[548, 445, 22]), ("macOS", 0.7));
+        assert_eq!(guess(&[135, 139, 445]), ("Windows", 0.9));
+        assert_eq!(guess(&[135, 445, 22]), ("Windows", 0.8));
+        assert_eq!(guess(&[139, 445]), ("Windows", 0.8));
+        assert_eq!(guess(&[3389]), ("Windows", 0.7));
+        assert_eq!(guess(&[135, 80]), ("Windows", 0.7));
+        assert_eq!(guess(&[22, 80]), ("Linux", 0.6));
+        assert_eq!(guess(&[22, 139, 445]), ("Linux/Unix", 0.6));
+        assert_eq!(guess(&[445]), ("Windows", 0.5));
+        assert_eq!(guess(&[22, 443]), ("Linux/Unix", 0.4));
+        assert_eq!(guess(&[9100]), ("Unknown", 0.0));
+        assert_eq!(guess(&[]), ("Unknown", 0.0));
+    }
+
+    #[test]
+    fn target_specs_name_addresses_networks_ranges_or_hosts() {
+        let addresses = |line: &str| match parse_target_spec(line, None).unwrap() {
+            TargetSpec::Addresses(ips) => ips.iter().map(IpAddr::to_string).collect::<Vec<_>>(),
+            TargetSpec::Name(name) => panic!("{} parsed as a name", name),
+        };
+        assert_eq!(addresses("10.0.0.5"), ["10.0.0.5"]);
+        assert_eq!(addresses("  fd00::5  open 22/tcp"), ["fd00::5"]);
+        assert_eq!(addresses("10.0.0.0/30"), ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(addresses("fd00::/127"), ["fd00::", "fd00::1"]);
+        assert!(addresses("").is_empty());
+        assert!(addresses("# from the discovery sweep").is_empty());
+
+        assert_eq!(parse_target_spec("nas.lan", None), Ok(TargetSpec::Name("nas.lan".to_string())));
+        assert!(parse_target_spec("fd00::/64", None).is_err());
+        assert!(parse_target_spec("fd00::/120", Some(100)).is_err());
+        assert!(parse_target_spec("10.0.0.0/33", None).is_err());
+
+        assert_eq!(addresses("10.0.0.1-10.0.0.3"), ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        assert_eq!(addresses("10.0.1.254-10.0.2.1").len(), 4);
+        assert_eq!(addresses("10.0.0.9-10"), ["10.0.0.9", "10.0.0.10"]);
+        assert!(parse_target_spec("10.0.0.9-3", None).is_err());
+        assert!(parse_target_spec("10.0.0.9-300", None).is_err());
+        // Refused before a single address is collected
+        assert!(parse_target_spec("0.0.0.0-255.255.255.255", None).is_err());
+        assert!(parse_target_spec("10.0.0.0/0", None).is_err());
+        assert!(parse_target_spec("10.0.0.0/24", Some(100)).is_err());
+        assert_eq!(addresses("10.0.0.0/16").len(), 65534);
+        assert_eq!(parse_target_spec("web-01.lan", None), Ok(TargetSpec::Name("web-01.lan".to_string())));
+
+        let mut resolution = TargetResolution::default();
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+        let v6: IpAddr = "fd00::5".parse().unwrap();
+        resolution.resolved.insert("dual.lan".to_string(), vec![v4, v6]);
+        resolution.resolved.insert("v4only.lan".to_string(), vec![v4]);
+        resolution.retain_family(AddressFamily::Ipv6);
+        assert_eq!(resolution.resolved["dual.lan"], [v6]);
+        assert_eq!(resolution.failed["v4only.lan"], "no IPv6 address");
+
+        let args = Args::try_parse_from(["network-mapper", "--target", "10.0.0.0/30,nas.lan", "--target-family", "ipv4"]);
+        assert_eq!(args.unwrap().target_family, AddressFamily::Ipv4);
+    }
+
+    #[test]
+    fn subnets_follow_the_scanned_prefix() {
+        let prefixes = |extra: &[&str]| {
+            let args = Args::try_parse_from([&["network-mapper"], extra].concat()).unwrap();
+            subnet_prefixes(&args).unwrap()
+        };
+        assert_eq!(prefixes(&[]), (24, 64));
+        assert_eq!(prefixes(&["--cidr", "10.20.0.0/16"]), (16, 64));
+        assert_eq!(prefixes(&["--cidr", "10.20.0.0/16", "--subnet-prefix", "20"]), (20, 64));
+        assert_eq!(prefixes(&["--cidr", "fd00::100/120"]), (24, 120));
+        // A range spanning four /24s is one /22; one inside a /24 keeps the /24
+        assert_eq!(prefixes(&["--start-ip", "10.0.0.1", "--end-ip", "10.0.3.254"]), (22, 64));
+        assert_eq!(prefixes(&["--start-ip", "10.0.0.10", "--end-ip", "10.0.0.20"]), (24, 64));
+
+        assert_eq!(subnet_of("10.20.30.40".parse().unwrap(), 16, 64), "10.20.0.0/16");
+        assert_eq!(subnet_of("fd00::1a5".parse().unwrap(), 24, 120), "fd00::100/120");
+        assert_eq!(subnet_of("fd00::5".parse().unwrap(), 24, 128), "fd00::5/128");
+
+        // A /16 subnet is not split into /24 aggregates by --collapse-subnets 24
+        let mut results = vec![host("10.20.1.5", &[22]), host("10.20.2.9", &[22])];
+        for result in &mut results {
+            result.subnet = "10.20.0.0/16".to_string();
+        }
+        let html = render_visualization(&results, Some(24)).unwrap();
+        assert!(html.contains("10.20.0.0/16"));
+        assert!(!html.contains("10.20.1.0/24"));
+    }
+
+    #[test]
+    fn roles_are_ranked_by_weighted_ports() {
+        let rules = builtin_role_rules();
+        let ranked = |ports: &[u16]| -> Vec<(String, f64)> {
+            rank_roles(ports, &rules).into_iter().map(|role| (role.role, role.score)).collect()
+        };
+        // A database outweighs the generic web port it sits beside, and both are kept
+        assert_eq!(ranked(&[80, 5432]), [("server".to_string(), 3.5)]);
+        assert_eq!(ranked(&[22, 80, 9100]), [("printer".to_string(), 3.0), ("server".to_string(), 0.5)]);
+        // SMB alone is a file server; with the rest of Windows' ports, a desktop
+        assert_eq!(infer_role(&[445]), "server");
+        assert_eq!(infer_role(&[135, 139, 445, 3389]), "workstation");
+        assert_eq!(infer_role(&[53, 80, 443, 1900]), "router");
+        assert_eq!(infer_role(&[22]), "unknown");
+        assert!(ranked(&[22]).is_empty());
+
+        let file = temp_path("role-rules.txt");
+        let path = file.to_str().unwrap();
+        std::fs::write(&file, "# tuned\nserver 445:0.5,80:0.5\nnas  445:2, 548:3, 5000:3\n").unwrap();
+        let tuned = load_role_rules(path).unwrap();
+        let top = |ports: &[u16]| rank_roles(ports, &tuned).first().map(|role| role.role.clone());
+        assert_eq!(top(&[445, 5000]), Some("nas".to_string()));
+        assert_eq!(top(&[135, 139, 445]), Some("workstation".to_string()));
+        assert_eq!(tuned.len(), rules.len() + 1);
+
+        std::fs::write(&file, "server 445=2\n").unwrap();
+        assert!(load_role_rules(path).unwrap_err().to_string().contains(":1: 445=2"));
+        std::fs::write(&file, "server 445:-1\n").unwrap();
+        assert!(load_role_rules(path).is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn tooltips_list_connect_times_by_port() {
+        let mut result = host("10.0.0.5", &[22, 443, 80]);
+        result.port_rtt = HashMap::from([(443, 12.34), (22, 0.25), (80, 3.0)]);
+        result.ping_rtt = Some(0.4);
+        assert_eq!(connect_times(&result), "22: 0.2 ms\n80: 3.0 ms\n443: 12.3 ms\nping: 0.4 ms");
+
+        // Kept for an ICMP answer alone: no connect times to list
+        let mut quiet = host("10.0.0.9", &[]);
+        quiet.port_rtt.clear();
+        let html = render_visualization(&[result, quiet], None).unwrap();
+        let data = html.split("const data = ").nth(1).unwrap().split(";\n").next().unwrap();
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        let latencies: Vec<&serde_json::Value> = data["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|node| node["type"] == "device")
+            .map(|node| &node["latencies"])
+            .collect();
+        assert_eq!(latencies[0], "22: 0.2 ms\n80: 3.0 ms\n443: 12.3 ms\nping: 0.4 ms");
+        assert!(latencies[1].is_null());
+    }
+
+    #[test]
+    fn max_results_keeps_a_uniform_sample() {
+        let offered = |i: u32| host(&Ipv4Addr::from(0x0a00_0000 + i).to_string(), &[22]);
+
+        let mut all = Reservoir::seeded(None, 7);
+        all.extend((1..=50).map(offered));
+        let (hosts, sampled_from) = all.into_hosts();
+        assert_eq!((hosts.len(), sampled_from), (50, None));
+
+        let mut under = Reservoir::seeded(Some(10), 7);
+        under.extend((1..=10).map(offered));
+        assert_eq!(under.into_hosts().1, None);
+
+        // Every host is equally likely to be kept: 10 of 100, over 2000 runs, is
+        // about 200 times each
+        let mut kept = vec![0u32; 100];
+        for seed in 0..2000 {
+            let mut sample = Reservoir::seeded(Some(10), seed);
+            sample.extend((0..100).map(offered));
+            let (hosts, sampled_from) = sample.into_hosts();
+            assert_eq!((hosts.len(), sampled_from), (10, Some(100)));
+            let indices: Vec<usize> = hosts
+                .iter()
+                .map(|result| (u32::from(result.ip.parse::<Ipv4Addr>().unwrap()) - 0x0a00_0000) as usize)
+                .collect();
+            assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+            for index in indices {
+                kept[index] += 1;
+            }
+        }
+        assert!(kept.iter().all(|&count| (130..=270).contains(&count)), "{:?}", kept);
+        // The first and the last offered have the same chance
+        assert!(kept[..10].iter().sum::<u32>().abs_diff(kept[90..].iter().sum::<u32>()) < 300);
+
+        let hosts: Vec<ScanResult> = (1..=3).map(offered).collect();
+        let notes = ["Showing a random sample of the 40 live hosts found (--max-results).".to_string()];
+        let html = render_graph(&hosts, None, None, &notes).unwrap();
+        assert!(html.contains(r#"<p class="note">Showing a random sample of the 40 live hosts found"#));
+        assert!(!render_visualization(&hosts, None).unwrap().contains(r#"<p class="note">"#));
+    }
+
+    #[test]
+    fn top_ports_take_the_most_common_first() {
+        assert_eq!(TOP_PORTS.len(), 1000);
+        assert_eq!(TOP_PORTS.iter().collect::<std::collections::HashSet<_>>().len(), 1000);
+        assert!(TOP_PORTS[TOP_PORTS_RANKED..].windows(2).all(|pair| pair[0] < pair[1]));
+
+        let ports = |extra: &[&str]| {
+            let args = Args::try_parse_from([&["network-mapper"], extra].concat()).unwrap();
+            scan_ports(&args).to_vec()
+        };
+        assert_eq!(ports(&[]), DEFAULT_SCAN_PORTS);
+        assert_eq!(ports(&["--top-ports", "5"]), [80, 23, 443, 21, 22]);
+        assert_eq!(ports(&["--top-ports", "1000"]).len(), 1000);
+        assert!(Args::try_parse_from(["network-mapper", "--top-ports", "10", "--ports", "22"]).is_err());
+    }
+
+    #[test]
+    fn uniform_http_redirects_reveal_a_captive_portal() {
+        let moved = b"HTTP/1.1 302 Found\r\nServer: portal\r\nlocation: http://login.guest.example/?r=1\r\n\r\n";
+        let redirect = http_redirect(moved).unwrap();
+        assert_eq!((redirect.status, redirect.location.as_deref()), (302, Some("http://login.guest.example/?r=1")));
+        assert_eq!(http_redirect(b"HTTP/1.0 511 Network Authentication Required\r\n\r\n").unwrap().status, 511);
+        assert_eq!(http_redirect(b"HTTP/1.1 200 OK\r\nLocation: /x\r\n\r\n"), None);
+        assert_eq!(http_redirect(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+
+        let redirected = |ip: &str, location: &str| {
+            let mut result = host(ip, &[80]);
+            result.http_redirects.insert(80, HttpRedirect { status: 302, location: Some(location.to_string()) });
+            result
+        };
+        let mut results: Vec<ScanResult> =
+            (1..=4).map(|i| redirected(&format!("10.0.0.{}", i), "http://login.guest.example/")).collect();
+        // Each server sending clients to its own HTTPS site is no portal
+        results.extend((5..=9).map(|i| redirected(&format!("10.0.0.{}", i), &format!("https://10.0.0.{}/", i))));
+        assert_eq!(detect_captive_portal(&results), None);
+
+        results.push(redirected("10.0.0.10", "http://login.guest.example/"));
+        let portal = detect_captive_portal(&results).unwrap();
+        assert_eq!((portal.answer.as_str(), portal.hosts), ("http://login.guest.example/", 5));
+
+        // One explicit 511 is enough
+        let mut portal_host = host("10.0.0.20", &[80]);
+        portal_host.http_redirects.insert(80, HttpRedirect { status: 511, location: None });
+        let portal = detect_captive_portal(&[portal_host]).unwrap();
+        assert_eq!((portal.answer.as_str(), portal.hosts), ("511 Network Authentication Required", 1));
+    }
+
+    #[tokio::test]
+    async fn liveness_only_connects_once_and_learns_nothing_else() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let closed = {
+            let spare = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            spare.local_addr().unwrap().port()
+        };
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let options = |port: u16| ScanOptions {
+            liveness_port: Some(port),
+            banner_all: true,
+            ports: vec![open],
+            ..ScanOptions::default()
+        };
+
+        let up = scan_host(localhost, &options(open)).await.unwrap();
+        assert_eq!(up.open_ports, [open]);
+        assert!(up.banners.is_empty());
+        // Refused is up too, just with nothing open
+        let refused = scan_host(localhost, &options(closed)).await.unwrap();
+        assert!(refused.open_ports.is_empty());
+        // The listener saw exactly one connection
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_ok());
+        assert!(listener.accept().is_err());
+
+        let args = Args::try_parse_from(["network-mapper", "--liveness-only"]).unwrap();
+        assert_eq!(args.liveness_only, Some(80));
+        let args = Args::try_parse_from(["network-mapper", "--liveness-only", "443"]).unwrap();
+        assert_eq!(args.liveness_only, Some(443));
+        assert!(Args::try_parse_from(["network-mapper", "--liveness-only", "--ports", "22"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn masscan_imports_probe_only_the_listed_ports() {
+        // An old masscan's -oJ: a trailing comma, a closed port, a UDP port, a banner
+        // record for a port already listed, and the finished trailer
+        let file = r#"[
+{   "ip": "10.0.0.5",   "timestamp": "1700000000", "ports": [ {"port": 22, "proto": "tcp", "status": "open", "reason": "syn-ack", "ttl": 64} ] },
+{   "ip": "10.0.0.5",   "timestamp": "1700000001", "ports": [ {"port": 80, "proto": "tcp", "status": "open", "reason": "syn-ack", "ttl": 64} ] },
+{   "ip": "10.0.0.5",   "timestamp": "1700000002", "ports": [ {"port": 80, "proto": "tcp", "service": {"name": "http", "banner": "nginx"} } ] },
+{   "ip": "10.0.0.7",   "timestamp": "1700000003", "ports": [ {"port": 443, "proto": "tcp", "status": "closed", "reason": "rst", "ttl": 64} ] },
+{   "ip": "10.0.0.9",   "timestamp": "1700000004", "ports": [ {"port": 53, "proto": "udp", "status": "open", "reason": "none", "ttl": 64} ] },
+{finished: 1}
+]
+"#;
+        let (imported, skipped) = parse_masscan("scan.json", file).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[&"10.0.0.5".parse::<IpAddr>().unwrap()], [22, 80]);
+        assert_eq!(skipped, 1);
+
+        // Valid JSON, e.g. passed through jq, parses as a whole
+        let pretty = r#"[{"ip": "10.0.0.8", "ports": [{"port": 8080, "proto": "tcp", "status": "open"}]}]"#;
+        let (imported, _) = parse_masscan("scan.json", pretty).unwrap();
+        assert_eq!(imported[&"10.0.0.8".parse::<IpAddr>().unwrap()], [8080]);
+
+        let error = parse_masscan("scan.json", "[\n{\"ip\": \"10.0.0.300\", \"ports\": []},\n]").unwrap_err();
+        assert!(error.to_string().contains("scan.json:2:"), "{}", error);
+
+        // The imported ports replace --ports for that host, so a listener masscan did not
+        // see is never touched
+        let listed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unlisted = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listed_port = listed.local_addr().unwrap().port();
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let options = ScanOptions {
+            ports: vec![unlisted.local_addr().unwrap().port()],
+            imported_ports: Some(Arc::new(HashMap::from([(localhost, vec![listed_port])]))),
+            ..ScanOptions::default()
+        };
+        let result = scan_host(localhost, &options).await.unwrap();
+        assert_eq!(result.open_ports, [listed_port]);
+        unlisted.set_nonblocking(true).unwrap();
+        assert!(unlisted.accept().is_err());
+    }
+
+    #[test]
+    fn html_reports_have_a_search_box_os_filters_and_image_export() {
+        let mut hosts = vec![host("10.0.0.5", &[22]), host("10.0.0.9", &[445])];
+        hosts[1].os_guess = "Windows <script>".to_string();
+        let html = render_visualization(&hosts, None).unwrap();
+        assert!(html.contains(r#"<input type="search" id="search""#));
+        assert!(html.contains(r#"<span id="os-filters"></span>"#));
+        assert!(html.contains(r#"<button id="export-svg">"#) && html.contains(r#"<button id="export-png">"#));
+        // OS names reach the page only as JSON data, never as markup
+        assert!(!html.contains("Windows <script>"));
+        let data = html.split("const data = ").nth(1).unwrap().split(";\n").next().unwrap();
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert!(data["nodes"].as_array().unwrap().iter().any(|node| node["os"] == "Windows <script>"));
+    }
+
+    #[test]
+    fn hosts_fall_into_latency_tiers_by_their_fastest_round_trip() {
+        let tiers = LatencyTiers::default();
+        assert_eq!(tiers.tier(0.3), LatencyTier::Local);
+        assert_eq!(tiers.tier(1.0), LatencyTier::Lan);
+        assert_eq!(tiers.tier(42.0), LatencyTier::Wan);
+        assert_eq!(tiers.tier(100.0), LatencyTier::Slow);
+
+        let mut pinged = host("10.0.0.5", &[]);
+        pinged.port_rtt.clear();
+        assert_eq!(pinged.latency_tier_under(&tiers), None);
+        pinged.ping_rtt = Some(0.4);
+        assert_eq!(pinged.latency_tier_under(&tiers), Some(LatencyTier::Local));
+        // The fastest of the connects and the ping counts; host() connects in 1.5 ms
+        let mut far = host("10.0.0.9", &[22]);
+        far.ping_rtt = Some(30.0);
+        assert_eq!(far.latency_tier_under(&tiers), Some(LatencyTier::Lan));
+        let custom: LatencyTiers = "2,20,200".parse().unwrap();
+        assert_eq!(far.latency_tier_under(&custom), Some(LatencyTier::Local));
+
+        for invalid in ["1,10", "10,1,100", "1,10,abc", "0,10,100"] {
+            assert!(invalid.parse::<LatencyTiers>().is_err(), "{}", invalid);
+        }
+        let args = Args::try_parse_from(["network-mapper", "--latency-tiers"]).unwrap();
+        assert_eq!(args.latency_tiers, Some(LatencyTiers::default()));
+
+        far.latency_tier = Some(LatencyTier::Wan);
+        assert_eq!(serde_json::to_value(&far).unwrap()["latency_tier"], "wan");
+        let html = render_visualization(&[far], None).unwrap();
+        let data = html.split("const data = ").nth(1).unwrap().split(";\n").next().unwrap();
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(data["tiers"], true);
+        assert_eq!(data["nodes"][0]["latency_tier"], "wan");
+    }
+
+    #[test]
+    fn empty_single_host_and_single_subnet_reports_still_render() {
+        let graph = |hosts: &[ScanResult]| {
+            let html = render_visualization(hosts, None).unwrap();
+            let data = html.split("const data = ").nth(1).unwrap().split(";\n").next().unwrap();
+            let data: serde_json::Value = serde_json::from_str(data).unwrap();
+            let count = |key: &str| data[key].as_array().unwrap().len();
+            (html.contains(r#"<p class="empty">"#), count("nodes"), count("links"))
+        };
+        assert_eq!(graph(&[]), (true, 0, 0));
+        // A host and its subnet
+        assert_eq!(graph(&[host("10.0.0.5", &[22])]), (false, 2, 1));
+        // A live host with nothing open is still drawn
+        assert_eq!(graph(&[host("10.0.0.5", &[])]), (false, 2, 1));
+        let one_subnet = [host("10.0.0.5", &[22]), host("10.0.0.9", &[80]), host("10.0.0.7", &[443])];
+        assert_eq!(graph(&one_subnet), (false, 4, 3));
+
+        let diff = render_diff_visualization(&[], &[], None, &[]).unwrap();
+        assert!(diff.contains("Neither scan found a live host"));
+    }
+
+    #[test]
+    fn grepable_output_follows_nmaps_layout() {
+        let path = temp_path("topology.gnmap");
+        let mut named = host("10.0.0.10", &[22, 80, 40000]);
+        named.hostname = Some("nas.lan".to_string());
+        named.udp_ports = vec![UdpPort { port: 53, protocol: "udp".to_string(), state: UdpState::OpenFiltered }];
+        let mut quiet = host("10.0.0.11", &[]);
+        quiet.os_guess = "Unknown".to_string();
+        let metadata: ReportMetadata =
+            serde_json::from_value(json!({ "scan_id": "s", "started_at": 1_700_000_000, "targets": 254 })).unwrap();
+        write_grepable(&[named, quiet], path.to_str().unwrap(), &metadata).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("# network-mapper ") && lines[0].contains(" scan initiated "));
+        assert_eq!(lines[1], "Host: 10.0.0.10 (nas.lan)\tStatus: Up");
+        assert_eq!(
+            lines[2],
+            "Host: 10.0.0.10 (nas.lan)\tPorts: 22/open/tcp//ssh///, 80/open/tcp//http///, 40000/open/tcp/////, \
+             53/open|filtered/udp//domain///\tOS: Linux"
+        );
+        // Up with nothing open: no Ports line, as with nmap
+        assert_eq!(lines[3], "Host: 10.0.0.11 ()\tStatus: Up");
+        assert!(lines[4].contains(" -- 254 IP addresses (2 hosts up) scanned in "), "{}", lines[4]);
+        assert!(OutputFormat::Grepable.writes_stdout());
+    }
+
+    #[test]
+    fn deduplicated_banners_are_written_once_and_expanded_on_load() {
+        let mut hosts: Vec<ScanResult> = (1..=3).map(|n| host(&format!("10.0.0.{}", n), &[22, 80])).collect();
+        for host in &mut hosts {
+            host.banners.insert(22, "SSH-2.0-OpenSSH_8.9p1".to_string());
+            host.banners.insert(80, "HTTP/1.1 200 OK".to_string());
+        }
+        hosts[2].banners.insert(80, "HTTP/1.1 401 Unauthorized".to_string());
+        let metadata: ReportMetadata =
+            serde_json::from_value(json!({ "scan_id": "s", "started_at": 0, "targets": 3 })).unwrap();
+
+        let report = serde_json::to_value(Report::new(&metadata, &hosts).deduplicate_banners()).unwrap();
+        let table = report["banners"].as_array().unwrap();
+        assert_eq!(table.len(), 3);
+        let host = &report["hosts"][0];
+        assert!(host.get("banners").is_none());
+        assert_eq!(table[host["banner_ids"]["22"].as_u64().unwrap() as usize], "SSH-2.0-OpenSSH_8.9p1");
+        // Without the option nothing changes
+        assert!(serde_json::to_value(Report::new(&metadata, &hosts)).unwrap().get("banners").is_none());
+
+        let path = temp_path("deduplicated.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, report.to_string()).unwrap();
+        let (_, loaded) = load_saved_results("--from-file", path, None).unwrap();
+        let banners = |hosts: &[ScanResult]| hosts.iter().map(|host| host.banners.clone()).collect::<Vec<_>>();
+        assert_eq!(banners(&loaded), banners(&hosts));
+        assert!(loaded.iter().all(|host| host.banner_ids.is_empty()));
+
+        // An id past the end of the table is an error, not a silently lost banner
+        let mut broken = report.clone();
+        broken["banners"] = json!(["only one"]);
+        std::fs::write(path, broken.to_string()).unwrap();
+        let Err(error) = load_saved_results("--baseline", path, None) else { panic!("loaded a broken table") };
+        assert!(error.to_string().contains("is not in the banners table"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn baseline_round_trips_set_per_host_connect_timeouts() {
+        let ceiling = Duration::from_millis(1000);
+        // host() connected to 22 in 1.5 ms: the floor wins
+        let near = host("10.0.0.5", &[22]);
+        assert_eq!(rtt_timeout(&near, ceiling), Some(RTT_TIMEOUT_FLOOR));
+        let mut far = host("10.0.0.9", &[22, 443]);
+        far.port_rtt.insert(443, 80.0);
+        assert_eq!(rtt_timeout(&far, ceiling), Some(Duration::from_millis(320)));
+        // Slow hosts never get more than --timeout-ms
+        far.ping_rtt = Some(400.0);
+        assert_eq!(rtt_timeout(&far, ceiling), Some(ceiling));
+        let mut untimed = host("10.0.0.7", &[]);
+        untimed.port_rtt.clear();
+        assert_eq!(rtt_timeout(&untimed, ceiling), None);
+
+        let known = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+        let options = ScanOptions {
+            timeout: ceiling,
+            host_timeouts: Some(Arc::new(HashMap::from([(known, RTT_TIMEOUT_FLOOR)]))),
+            ..ScanOptions::default()
+        };
+        assert_eq!(options.connect_timeout_for(known), RTT_TIMEOUT_FLOOR);
+        assert_eq!(options.connect_timeout_for(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 6))), ceiling);
+        assert!(Args::try_parse_from(["network-mapper", "--rtt-timeouts"]).is_err());
+    }
+
+    #[test]
+    fn each_v_raises_the_log_level() {
+        assert_eq!(Args::parse_from(["network-mapper"]).verbose, 0);
+        assert_eq!(Args::parse_from(["network-mapper", "-v"]).verbose, 1);
+        assert_eq!(Args::parse_from(["network-mapper", "-vvv"]).verbose, 3);
+        assert_eq!(Args::parse_from(["network-mapper", "--verbose", "-v"]).verbose, 2);
+    }
+
+    #[tokio::test]
+    async fn http_probes_follow_same_port_redirects_to_the_page_title() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let response: &[u8] = if buf[..n].starts_with(b"GET / ") {
+                    b"HTTP/1.1 302 Found\r\nLocation: /login\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 200 OK\r\nServer: nginx/1.24\r\n\r\n<html><head><TITLE>\n  Router &amp; Co\n</title>"
+                };
+                let _ = stream.write_all(response).await;
+            }
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let page = fetch_http_page(ip, port, false, &ScanOptions::default()).await.unwrap();
+        let expected = HttpPage {
+            status: 200,
+            title: Some("Router & Co".to_string()),
+            server: Some("nginx/1.24".to_string()),
+            location: None,
+        };
+        assert_eq!(page, expected);
+        assert_eq!(page.summary(), "200 \"Router & Co\" (nginx/1.24)");
+
+        // Leaving the host or the scheme is recorded, not followed
+        let next = |location: &str, current: &str| same_port_path(location, current, "10.0.0.1".parse().unwrap(), 80, false);
+        assert_eq!(next("https://10.0.0.1/", "/"), None);
+        assert_eq!(next("http://example.com/", "/"), None);
+        assert_eq!(next("http://10.0.0.1:80/a", "/"), Some("/a".to_string()));
+        assert_eq!(next("b.html", "/a/index"), Some("/a/b.html".to_string()));
+        let moved = parse_http_page(b"HTTP/1.0 301 Moved\r\nLocation: https://10.0.0.1/\r\n\r\n").unwrap();
+        assert_eq!(moved.summary(), "301 -> https://10.0.0.1/");
+
+        // Malformed answers are not pages
+        assert_eq!(parse_http_page(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+        assert_eq!(parse_http_page(b"HTTP/1.1 abc\r\n\r\n"), None);
+        assert_eq!(parse_http_page(b"HTTP/1.1 200 OK").unwrap().title, None);
+        assert_eq!(html_title("<title></title>"), None);
+        assert_eq!(html_title("<title>cut off"), Some("cut off".to_string()));
+    }
+
+    #[test]
+    fn hosts_sharing_an_identity_are_linked() {
+        let mut results = vec![host("10.0.0.5", &[22]), host("10.0.0.9", &[22]), host("10.0.0.7", &[22])];
+        results[0].identity_fingerprint = Some("ssh:SHA256:abc".to_string());
+        results[1].identity_fingerprint = Some("ssh:SHA256:abc".to_string());
+        results[2].identity_fingerprint = Some("ssh:SHA256:other".to_string());
+
+        correlate_identities(&mut results);
+        assert_eq!(results[0].same_device_as, ["10.0.0.9"]);
+        assert_eq!(results[1].same_device_as, ["10.0.0.5"]);
+        assert!(results[2].same_device_as.is_empty());
+    }
+
+    #[test]
+    fn only_local_failures_leave_a_port_inconclusive() {
+        use std::io::ErrorKind;
+
+        assert!(ConnectOutcome::TimedOut.is_conclusive());
+        assert!(ConnectOutcome::Refused(Duration::from_millis(1)).is_conclusive());
+        assert!(ConnectOutcome::Error(ErrorKind::HostUnreachable.into()).is_conclusive());
+        for kind in [ErrorKind::AddrNotAvailable, ErrorKind::PermissionDenied, ErrorKind::Other] {
+            assert!(!ConnectOutcome::Error(kind.into()).is_conclusive(), "{:?}", kind);
+        }
+
+        // Results saved before the field existed were complete scans
+        assert!(host("10.0.0.5", &[22]).complete);
+    }
+
+    #[test]
+    fn timeouts_and_transient_errors_are_retried() {
+        use std::io::ErrorKind;
+
+        assert!(ConnectOutcome::TimedOut.is_retryable());
+        for kind in [
+            ErrorKind::TimedOut,
+            ErrorKind::Interrupted,
+            ErrorKind::WouldBlock,
+            ErrorKind::AddrNotAvailable,
+            ErrorKind::ConnectionAborted,
+        ] {
+            assert!(ConnectOutcome::Error(kind.into()).is_retryable(), "{:?}", kind);
+        }
+    }
+}